@@ -0,0 +1,302 @@
+use colored::Colorize;
+use pea_2_pea::*;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket},
+    os::fd::AsRawFd,
+};
+
+const GSO_MAX_SEGMENTS: usize = 64; // UDP_MAX_SEGMENTS of kernel
+const GSO_MAX_SIZE: usize = 65000; // segments together have to fit into one udp datagram
+const CONTROL_WORDS: usize = 8; // room for one cmsg with segment size, u64 keeps cmsghdr aligned
+
+/// let kernel coalesce datagrams of same size from one peer, recv gives us segment size
+pub fn enable_gro(socket: &UdpSocket) -> std::io::Result<()> {
+    let value: libc::c_int = 1;
+    match unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// reads on tun return WouldBlock when there is nothing more to batch
+pub fn set_nonblocking(fd: std::os::fd::RawFd) -> std::io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(a) => {
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = a.port().to_be();
+            sin.sin_addr.s_addr = u32::from(*a.ip()).to_be();
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(a) => {
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = a.port().to_be();
+            sin6.sin6_addr.s6_addr = a.ip().octets();
+            sin6.sin6_flowinfo = a.flowinfo();
+            sin6.sin6_scope_id = a.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+fn from_sockaddr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            Some(SocketAddr::V4(SocketAddrV4::new(
+                Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr)),
+                u16::from_be(sin.sin_port),
+            )))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                Ipv6Addr::from(sin6.sin6_addr.s6_addr),
+                u16::from_be(sin6.sin6_port),
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
+
+/// buffers for datagrams received by one recvmmsg
+pub struct RecvBatch {
+    bufs: Vec<Box<[u8]>>,
+    addrs: Vec<libc::sockaddr_storage>,
+    controls: Vec<[u64; CONTROL_WORDS]>,
+    received: Vec<(usize, Option<SocketAddr>, usize)>, // lenght, source and segment size of coalesced datagram
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        RecvBatch {
+            bufs: (0..UDP_BATCH_SIZE)
+                .map(|_| vec![0u8; IP_BUFFER_SIZE].into_boxed_slice())
+                .collect(),
+            addrs: vec![unsafe { std::mem::zeroed() }; UDP_BATCH_SIZE],
+            controls: vec![[0u64; CONTROL_WORDS]; UDP_BATCH_SIZE],
+            received: Vec::with_capacity(UDP_BATCH_SIZE),
+        }
+    }
+
+    /// blocks until at least one datagram arrives or read timeout of socket passes, then takes what is queued
+    pub fn recv(&mut self, socket: &UdpSocket) -> std::io::Result<usize> {
+        let mut iovs: Vec<libc::iovec> = self
+            .bufs
+            .iter_mut()
+            .map(|b| libc::iovec {
+                iov_base: b.as_mut_ptr() as *mut libc::c_void,
+                iov_len: b.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = (0..UDP_BATCH_SIZE)
+            .map(|i| {
+                let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+                hdr.msg_name = &mut self.addrs[i] as *mut _ as *mut libc::c_void;
+                hdr.msg_namelen = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+                hdr.msg_iov = &mut iovs[i];
+                hdr.msg_iovlen = 1;
+                hdr.msg_control = self.controls[i].as_mut_ptr() as *mut libc::c_void;
+                hdr.msg_controllen = std::mem::size_of::<[u64; CONTROL_WORDS]>() as _;
+                libc::mmsghdr {
+                    msg_hdr: hdr,
+                    msg_len: 0,
+                }
+            })
+            .collect();
+
+        let count = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                UDP_BATCH_SIZE as libc::c_uint,
+                libc::MSG_WAITFORONE,
+                std::ptr::null_mut(),
+            )
+        };
+        if count < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        self.received.clear();
+        for (i, msg) in msgs[..count as usize].iter().enumerate() {
+            let len = msg.msg_len as usize;
+            let mut segment = len;
+            unsafe {
+                let mut cmsg = libc::CMSG_FIRSTHDR(&msg.msg_hdr);
+                while !cmsg.is_null() {
+                    if (*cmsg).cmsg_level == libc::SOL_UDP && (*cmsg).cmsg_type == libc::UDP_GRO {
+                        segment =
+                            std::ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const libc::c_int)
+                                as usize;
+                    }
+                    cmsg = libc::CMSG_NXTHDR(&msg.msg_hdr, cmsg);
+                }
+            }
+            self.received
+                .push((len, from_sockaddr(&self.addrs[i]), segment.max(1)));
+        }
+        Ok(count as usize)
+    }
+
+    /// datagrams of last recv, coalesced ones are split back
+    pub fn datagrams(&mut self) -> impl Iterator<Item = (&mut [u8], SocketAddr)> {
+        self.bufs
+            .iter_mut()
+            .zip(self.received.iter())
+            .filter_map(|(buf, &(len, src, segment))| src.map(|src| (buf, len, src, segment)))
+            .flat_map(|(buf, len, src, segment)| {
+                buf[..len].chunks_mut(segment).map(move |d| (d, src))
+            })
+    }
+}
+
+/// send datagrams with one sendmmsg, when gso is on consecutive datagrams to same peer
+/// are handed to kernel as one and it splits them, gso is turned off if kernel can't do it
+pub fn send_batch(
+    socket: &UdpSocket,
+    datagrams: &[(SocketAddr, Vec<u8>)],
+    gso: &mut bool,
+) -> std::io::Result<()> {
+    // ranges of datagrams that go in one message, all but last have to be same size
+    let mut groups: Vec<(usize, usize)> = Vec::with_capacity(datagrams.len());
+    let mut i = 0;
+    while i < datagrams.len() {
+        let (dst, first) = &datagrams[i];
+        let mut end = i + 1;
+        let mut total = first.len();
+        while *gso
+            && end < datagrams.len()
+            && end - i < GSO_MAX_SEGMENTS
+            && datagrams[end].0 == *dst
+            && datagrams[end].1.len() <= first.len()
+            && total + datagrams[end].1.len() <= GSO_MAX_SIZE
+        {
+            total += datagrams[end].1.len();
+            end += 1;
+            if datagrams[end - 1].1.len() < first.len() {
+                break; // shorter one has to be last
+            }
+        }
+        groups.push((i, end));
+        i = end;
+    }
+
+    let payloads: Vec<Option<Vec<u8>>> = groups
+        .iter()
+        .map(|&(start, end)| {
+            (end - start > 1).then(|| {
+                datagrams[start..end]
+                    .iter()
+                    .flat_map(|(_, d)| d.iter().copied())
+                    .collect()
+            })
+        })
+        .collect();
+    let mut addrs: Vec<(libc::sockaddr_storage, libc::socklen_t)> = groups
+        .iter()
+        .map(|&(start, _)| to_sockaddr(&datagrams[start].0))
+        .collect();
+    let mut iovs: Vec<libc::iovec> = groups
+        .iter()
+        .zip(payloads.iter())
+        .map(|(&(start, _), payload)| {
+            let data: &[u8] = match payload {
+                Some(p) => p,
+                None => &datagrams[start].1,
+            };
+            libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            }
+        })
+        .collect();
+    let mut controls: Vec<[u64; CONTROL_WORDS]> = vec![[0u64; CONTROL_WORDS]; groups.len()];
+    let mut msgs: Vec<libc::mmsghdr> = (0..groups.len())
+        .map(|g| {
+            let mut hdr: libc::msghdr = unsafe { std::mem::zeroed() };
+            hdr.msg_name = &mut addrs[g].0 as *mut _ as *mut libc::c_void;
+            hdr.msg_namelen = addrs[g].1;
+            hdr.msg_iov = &mut iovs[g];
+            hdr.msg_iovlen = 1;
+            if payloads[g].is_some() {
+                hdr.msg_control = controls[g].as_mut_ptr() as *mut libc::c_void;
+                unsafe {
+                    hdr.msg_controllen =
+                        libc::CMSG_SPACE(std::mem::size_of::<u16>() as libc::c_uint) as _;
+                    let cmsg = libc::CMSG_FIRSTHDR(&hdr);
+                    (*cmsg).cmsg_level = libc::SOL_UDP;
+                    (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+                    (*cmsg).cmsg_len =
+                        libc::CMSG_LEN(std::mem::size_of::<u16>() as libc::c_uint) as _;
+                    std::ptr::write_unaligned(
+                        libc::CMSG_DATA(cmsg) as *mut u16,
+                        datagrams[groups[g].0].1.len() as u16,
+                    );
+                }
+            }
+            libc::mmsghdr {
+                msg_hdr: hdr,
+                msg_len: 0,
+            }
+        })
+        .collect();
+
+    let mut result: std::io::Result<()> = Ok(());
+    let mut sent = 0;
+    while sent < msgs.len() {
+        let count = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs[sent..].as_mut_ptr(),
+                (msgs.len() - sent) as libc::c_uint,
+                0,
+            )
+        };
+        if count > 0 {
+            sent += count as usize;
+            continue;
+        }
+        // first message of rest failed, others still can get through
+        let e = std::io::Error::last_os_error();
+        let (start, end) = groups[sent];
+        if end - start > 1 {
+            if e.raw_os_error() == Some(libc::EIO) {
+                eprintln!(
+                    "{} kernel can't segment datagrams, sending them one by one, Error: {}",
+                    "[WARNING]".yellow(),
+                    e
+                );
+                *gso = false;
+            }
+            if let Err(e) = send_batch(socket, &datagrams[start..end], &mut false) {
+                result = Err(e);
+            }
+        } else {
+            result = Err(e);
+        }
+        sent += 1;
+    }
+    result
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use pea_2_pea::shared::{crypto::IdentityKey, subnet::Subnet};
+use serde::Deserialize;
+use zeroize::Zeroizing;
+
+use crate::{NetworkArgs, PasswordArgs};
+
+const CONFIG_DIR_NAME: &str = "pea_2_pea";
+const CONFIG_FILE_NAME: &str = "client.toml";
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    #[serde(default)]
+    pub registrars: Vec<String>, // tried in order until one of them responds
+    pub registrar_port: Option<u16>,
+    pub network_id: Option<String>,
+    pub password: Option<Zeroizing<String>>,
+    pub password_file: Option<PathBuf>, // first line of file is used as password
+    pub password_env: Option<String>,   // name of environment variable holding password
+    pub hide_id: Option<bool>,          // registrar gets keyed hash of network id
+    pub interface_name: Option<String>,
+    pub subnet: Option<Subnet>,
+    pub tap: Option<bool>,      // only used when creating network
+    pub compress: Option<bool>, // only used when creating network
+    pub cipher: Option<pea_2_pea::CipherSuite>, // only used when creating network
+    #[serde(default)]
+    pub allow_members: Vec<IdentityKey>, // only used when creating network
+    #[serde(default)]
+    pub advertise_routes: Vec<Subnet>,
+    pub exit_node: Option<bool>,
+    pub use_exit_node: Option<std::net::Ipv4Addr>,
+    #[serde(default)]
+    pub exit_routes: Vec<Subnet>,
+    pub address: Option<std::net::Ipv4Addr>,
+    pub address_fallback: Option<bool>,
+    pub verbose: Option<bool>,
+}
+
+pub fn default_config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_FILE_NAME))
+}
+
+/// identity key is kept next to config file
+pub fn default_identity_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(pea_2_pea::IDENTITY_FILE_NAME))
+}
+
+fn config_dir() -> Option<PathBuf> {
+    #[cfg(not(target_os = "windows"))]
+    let config_dir: Option<PathBuf> = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    };
+    #[cfg(target_os = "windows")]
+    let config_dir: Option<PathBuf> = std::env::var_os("APPDATA").map(PathBuf::from);
+
+    config_dir.map(|dir| dir.join(CONFIG_DIR_NAME))
+}
+
+pub fn load(path: &std::path::Path) -> std::io::Result<Config> {
+    let content = std::fs::read_to_string(path)?;
+    toml::from_str(&content).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    })
+}
+
+impl Profile {
+    /// fill values that were not given on command line
+    pub fn apply(self, cli: &mut NetworkArgs) -> std::io::Result<()> {
+        if cli.registrar.is_empty() {
+            cli.registrar = self.registrars;
+        }
+        cli.registrar_port = cli.registrar_port.or(self.registrar_port);
+        cli.network_id = cli.network_id.take().or(self.network_id);
+        cli.if_name = cli.if_name.take().or(self.interface_name);
+        cli.subnet = cli.subnet.or(self.subnet);
+        cli.tap = flag(cli.tap, cli.no_tap, self.tap);
+        cli.compress = flag(cli.compress, cli.no_compress, self.compress);
+        cli.hide_id = flag(cli.hide_id, cli.no_hide_id, self.hide_id);
+        cli.cipher = cli.cipher.or(self.cipher);
+        if cli.allow_members.is_empty() {
+            cli.allow_members = self.allow_members;
+        }
+        if cli.advertise_routes.is_empty() {
+            cli.advertise_routes = self.advertise_routes;
+        }
+        cli.exit_node = flag(cli.exit_node, cli.no_exit_node, self.exit_node);
+        cli.use_exit_node = cli.use_exit_node.or(self.use_exit_node);
+        if cli.exit_routes.is_empty() {
+            cli.exit_routes = self.exit_routes;
+        }
+        cli.address = cli.address.or(self.address);
+        cli.address_fallback = flag(
+            cli.address_fallback,
+            cli.no_address_fallback,
+            self.address_fallback,
+        );
+        cli.verbose = flag(cli.verbose, cli.no_verbose, self.verbose);
+
+        // password given on command line was already read, profile can't override it
+        if cli.password.value.is_none() {
+            cli.password.value = self.password;
+            cli.password.file = self.password_file;
+            cli.password.env = self.password_env;
+        }
+        read_password(&mut cli.password, cli.network_id.as_deref())
+    }
+}
+
+/// flag given on command line wins over profile, --no-* turns it off
+fn flag(on: bool, off: bool, profile: Option<bool>) -> bool {
+    match (on, off) {
+        (true, _) => true,
+        (_, true) => false,
+        _ => profile.unwrap_or(false),
+    }
+}
+
+/// fill password from file, environment variable or prompt, they keep it out of process list
+/// and shell history unlike -P
+pub fn read_password(password: &mut PasswordArgs, network_id: Option<&str>) -> std::io::Result<()> {
+    if password.value.is_some() {
+        return Ok(());
+    }
+    password.value = match (password.file.take(), password.env.take(), password.ask) {
+        (Some(file), _, _) => Some(read_password_file(&file).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("password file {}: {}", file.display(), e))
+        })?),
+        (None, Some(var), _) => match std::env::var(&var) {
+            Ok(p) => Some(Zeroizing::new(p)),
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("password environment variable {}: {}", var, e),
+                ));
+            }
+        },
+        (None, None, true) => Some(Zeroizing::new(rpassword::prompt_password(
+            match network_id {
+                Some(id) => format!("password of network {}: ", id),
+                None => "password: ".to_owned(),
+            },
+        )?)),
+        (None, None, false) => None,
+    };
+    Ok(())
+}
+
+/// first line of file is password, - reads it from standard input
+fn read_password_file(path: &Path) -> std::io::Result<Zeroizing<String>> {
+    let mut content = Zeroizing::new(String::new());
+    match path == Path::new("-") {
+        true => std::io::stdin().read_to_string(&mut content)?,
+        false => std::fs::File::open(path)?.read_to_string(&mut content)?,
+    };
+    Ok(Zeroizing::new(
+        content.lines().next().unwrap_or_default().to_owned(),
+    ))
+}
@@ -0,0 +1,547 @@
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    process::exit,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
+
+use colored::Colorize;
+use zeroize::Zeroizing;
+
+use pea_2_pea::{
+    Compression, UNDERLAY_MTU,
+    shared::crypto::{Identity, IdentityKey},
+};
+
+use crate::{
+    Command, NetworkArgs, config, join,
+    types::{JoinedNetwork, Networks},
+};
+
+// control requests, one per connection
+// request is command and its arguments separated by \0 terminated by \n
+const STATUS: &str = "status";
+const PEERS: &str = "peers";
+const LEAVE: &str = "leave";
+const JOIN: &str = "join";
+const ALLOW: &str = "allow";
+const REVOKE: &str = "revoke";
+const PASSWORD: &str = "password";
+const ARG_SEPARATOR: char = '\0';
+
+pub struct DaemonState {
+    pub networks: Networks,
+    pub identity: Arc<Identity>, // networks joined on request are joined with it too
+    pub ready: AtomicBool,       // false until networks given on command line are joined
+}
+
+/// detach from terminal, output is kept so join errors are still visible
+pub fn daemonize() -> std::io::Result<()> {
+    match unsafe { libc::daemon(1, 1) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// redirect standard streams to /dev/null once we don't need them
+pub fn detach_output() -> std::io::Result<()> {
+    let dev_null = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    let fd = std::os::unix::io::AsRawFd::as_raw_fd(&dev_null);
+    for std_fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(fd, std_fd) } < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+pub fn listen(
+    path: &Path,
+    networks: Networks,
+    identity: Arc<Identity>,
+) -> std::io::Result<Arc<DaemonState>> {
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!("daemon is already running on {}", path.display()),
+            ));
+        }
+        std::fs::remove_file(path)?; // stale socket of dead daemon
+    }
+    // socket is created only accessible by us, chmod after bind would leave it open for a while
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(path);
+    unsafe { libc::umask(umask) };
+    let listener = listener?;
+
+    let state: Arc<DaemonState> = Arc::new(DaemonState {
+        networks,
+        identity,
+        ready: AtomicBool::new(false),
+    });
+    {
+        let state_clone = state.clone();
+        let path_clone = path.to_path_buf();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(s) => {
+                        // joining takes a while, don't block other requests
+                        let state_clone = state_clone.clone();
+                        let path_clone = path_clone.clone();
+                        std::thread::spawn(move || {
+                            if let Err(e) = handle_control_connection(s, &state_clone, &path_clone)
+                            {
+                                eprintln!(
+                                    "{} control connection failed: {}",
+                                    "[WARNING]".yellow(),
+                                    e
+                                );
+                            }
+                        });
+                    }
+                    Err(e) => eprintln!("{} control socket accept failed: {}", "[ERROR]".red(), e),
+                }
+            }
+        });
+    }
+    println!(
+        "{} control socket listening on {}",
+        "[LOG]".blue(),
+        path.display()
+    );
+    Ok(state)
+}
+
+fn handle_control_connection(
+    mut stream: UnixStream,
+    state: &DaemonState,
+    path: &Path,
+) -> std::io::Result<()> {
+    let mut request = Zeroizing::new(String::new()); // join request can carry password
+    BufReader::new(&stream).read_line(&mut request)?;
+    let mut args = request.trim_end_matches('\n').split(ARG_SEPARATOR);
+    let command = args.next().unwrap_or_default();
+    let args: Vec<&str> = args.collect();
+
+    if !state.ready.load(Ordering::Relaxed) {
+        stream.write_all(b"joining network\n")?;
+        return Ok(());
+    }
+
+    // optional -n selecting one network
+    let network_id: Option<&str> = match args.as_slice() {
+        ["-n" | "--network-id", id] => Some(id),
+        _ => None,
+    };
+    match command {
+        STATUS => stream.write_all(for_each_network(state, network_id, status).as_bytes()),
+        PEERS => stream.write_all(for_each_network(state, network_id, peers).as_bytes()),
+        LEAVE => {
+            let response = for_each_network(state, network_id, |j| {
+                join::leave_network(j, &state.networks);
+                format!("left network {}\n", j.network.read().unwrap().net_id)
+            });
+            stream.write_all(response.as_bytes())?;
+            if state.networks.read().unwrap().is_empty() {
+                println!("{} no networks left, stopping daemon", "[LOG]".blue());
+                let _ = std::fs::remove_file(path);
+                exit(0);
+            }
+            Ok(())
+        }
+        ALLOW | REVOKE => {
+            // key goes first, optional -n follows it
+            let response = match args.split_first() {
+                Some((key, rest)) => match key.parse::<IdentityKey>() {
+                    Ok(key) => {
+                        let network_id: Option<&str> = match rest {
+                            ["-n" | "--network-id", id] => Some(id),
+                            _ => None,
+                        };
+                        let response = for_each_network(state, network_id, |j| {
+                            match join::change_allowlist(j, key, command == ALLOW) {
+                                Ok(version) => format!(
+                                    "allowlist of network {} changed, version {}\n",
+                                    j.network.read().unwrap().net_id,
+                                    version
+                                ),
+                                // without -n networks we don't own are skipped
+                                Err(e)
+                                    if network_id.is_none()
+                                        && e.kind() == std::io::ErrorKind::PermissionDenied =>
+                                {
+                                    String::new()
+                                }
+                                Err(e) => format!("error: {}\n", e),
+                            }
+                        });
+                        match response.is_empty() {
+                            true => "error: we don't own any joined network\n".to_owned(),
+                            false => response,
+                        }
+                    }
+                    Err(e) => format!("error: {}\n", e),
+                },
+                None => "error: identity key is required\n".to_owned(),
+            };
+            stream.write_all(response.as_bytes())
+        }
+        PASSWORD => {
+            // new password goes first, optional -n follows it
+            let response = match args.split_first() {
+                Some((&"", _)) => "error: password can't be empty\n".to_owned(),
+                Some((password, rest)) => {
+                    let network_id: Option<&str> = match rest {
+                        ["-n" | "--network-id", id] => Some(id),
+                        _ => None,
+                    };
+                    match network_id.is_none() && state.networks.read().unwrap().len() > 1 {
+                        true => "error: more networks are joined, choose one with -n\n".to_owned(),
+                        false => {
+                            for_each_network(state, network_id, |j| {
+                                match join::change_password(j, password) {
+                                    Ok(()) => format!(
+                                        "password of network {} changed\n",
+                                        j.network.read().unwrap().net_id
+                                    ),
+                                    Err(e) => format!("error: {}\n", e),
+                                }
+                            })
+                        }
+                    }
+                }
+                None => "error: new password is required\n".to_owned(),
+            };
+            stream.write_all(response.as_bytes())
+        }
+        JOIN => {
+            let response = match join_request(&args, &state.networks, &state.identity) {
+                Ok(joined) => joined.iter().map(|j| status(j)).collect::<String>(),
+                Err(e) => format!("error: {}\n", e),
+            };
+            stream.write_all(response.as_bytes())
+        }
+        other => stream.write_all(format!("unknown command: {}\n", other).as_bytes()),
+    }
+}
+
+fn for_each_network(
+    state: &DaemonState,
+    network_id: Option<&str>,
+    f: impl Fn(&JoinedNetwork) -> String,
+) -> String {
+    let joined: Vec<Arc<JoinedNetwork>> = match network_id {
+        Some(id) => match state
+            .networks
+            .read()
+            .unwrap()
+            .values()
+            .find(|j| j.network.read().unwrap().net_id == id)
+        {
+            Some(j) => vec![j.clone()],
+            None => return format!("not member of network {}\n", id),
+        },
+        None => state.networks.read().unwrap().values().cloned().collect(),
+    };
+    joined.iter().map(|j| f(j)).collect()
+}
+
+fn join_request(
+    args: &[&str],
+    networks: &Networks,
+    identity: &Arc<Identity>,
+) -> std::io::Result<Vec<Arc<JoinedNetwork>>> {
+    #[derive(clap::Parser)]
+    struct JoinArgs {
+        #[command(flatten)]
+        network: NetworkArgs,
+    }
+    let join_args = <JoinArgs as clap::Parser>::try_parse_from(
+        std::iter::once(JOIN).chain(args.iter().copied()),
+    )
+    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+    crate::resolve_profiles(join_args.network)?
+        .into_iter()
+        .map(|a| join::join_network(a, networks, identity))
+        .collect()
+}
+
+fn status(joined: &JoinedNetwork) -> String {
+    let network = joined.network.read().unwrap();
+    let fmt_opt = |o: Option<std::net::SocketAddr>| match o {
+        Some(sa) => sa.to_string(),
+        None => "unknown".to_owned(),
+    };
+    format!(
+        "network: {}{}\ninterface: {}\nencrypted: {}\ncipher: {}\nkey derivation: {}\nmode: {}\ncompression: {}\nmtu: {}\nsubnet: {}\nvirtual ip: {}\nvirtual ipv6: {}\nexit node: {}\nidentity: {}\nallowlist: {}\npublic sockaddr: {}\nregistrar: {}\nlast heartbeat confirmed: {}\npeers: {}\n",
+        network.net_id,
+        match network.registrar_id == network.net_id {
+            true => "",
+            false => " (hidden from registrar)",
+        },
+        joined.if_name,
+        network.encrypted,
+        match network.encrypted {
+            true => network.cipher.to_string(),
+            false => "none".to_owned(),
+        },
+        match network.encrypted {
+            true => network.kdf.to_string(),
+            false => "none".to_owned(),
+        },
+        network.mode,
+        match network.compression {
+            Compression::NONE => "none".to_owned(),
+            c => format!("{}, {}", c, network.compression_stats),
+        },
+        network.mtu_for(UNDERLAY_MTU),
+        network.subnet,
+        network.private_ip,
+        network.private_ip6,
+        match (network.exit_node, network.use_exit_node) {
+            (true, _) => "serving".to_owned(),
+            (false, Some(ip)) => format!("using {}", ip),
+            (false, None) => "none".to_owned(),
+        },
+        match &network.identity {
+            Some(identity) => identity.public().fingerprint(),
+            None => "none".to_owned(),
+        },
+        match (network.owner, &network.allowlist) {
+            (None, _) => "none".to_owned(),
+            (Some(owner), list) => format!(
+                "{}, owner {}{}",
+                match list {
+                    Some(l) => format!("version {}, {} members", l.version, l.members.len()),
+                    None => "not received yet".to_owned(),
+                },
+                owner.fingerprint(),
+                if network.is_owner() { " (us)" } else { "" }
+            ),
+        },
+        fmt_opt(network.public_sock_addr),
+        fmt_opt(network.registrar),
+        match network.last_heartbeat_ack {
+            Some(t) => format!("{}s ago", chrono::Utc::now().timestamp() - t),
+            None => "never".to_owned(),
+        },
+        network.peers.len()
+    )
+}
+
+fn peers(joined: &JoinedNetwork) -> String {
+    let network = joined.network.read().unwrap();
+    network
+        .peers
+        .iter()
+        .map(|p| {
+            format!(
+                "{} {} {} {} pmtu={} replayed={}{}{}{}\n",
+                network.net_id,
+                p.private_ip,
+                match p.private_ip6 {
+                    Some(ip6) => ip6.to_string(),
+                    None => "-".to_owned(),
+                },
+                p.sock_addr,
+                p.path_mtu,
+                p.replay.lock().unwrap().replayed,
+                match &p.session {
+                    Some(session) => {
+                        let (send, recv) = session.lock().unwrap().epochs();
+                        format!(" epoch={}/{}", send, recv)
+                    }
+                    None => String::new(),
+                },
+                match p.identity {
+                    Some(i) => format!(" id={}", i.fingerprint()),
+                    None => String::new(),
+                },
+                p.routes
+                    .iter()
+                    .map(|r| format!(" route={}", r))
+                    .collect::<String>()
+                    + if p.exit_node { " exit" } else { "" }
+            )
+        })
+        .collect()
+}
+
+fn send_command<S: AsRef<str>>(path: &Path, command: &str, args: &[S]) -> std::io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut request = Zeroizing::new(command.to_owned());
+    for arg in args {
+        request.push(ARG_SEPARATOR);
+        request.push_str(arg.as_ref());
+    }
+    request.push('\n');
+    stream.write_all(request.as_bytes())?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+/// arguments given after join subcommand, control socket and identity are given by spawn,
+/// password is already read and is passed separately
+fn join_args() -> Vec<String> {
+    const SKIPPED: [&str; 5] = [
+        "--control-socket",
+        "--identity",
+        "--password",
+        "--password-file",
+        "--password-env",
+    ];
+    let mut args: Vec<String> = Vec::new();
+    let mut skip_next = false;
+    for arg in std::env::args().skip(1).skip_while(|a| a != JOIN).skip(1) {
+        if skip_next {
+            skip_next = false;
+        } else if arg == "-P" || SKIPPED.contains(&arg.as_str()) {
+            skip_next = true;
+        } else if !arg.starts_with("-P")
+            && arg != "--ask-password"
+            && !SKIPPED.iter().any(|s| arg.starts_with(&format!("{}=", s)))
+        {
+            args.push(arg);
+        }
+    }
+    args
+}
+
+/// start daemon with same options and wait until it joins network
+fn spawn(
+    path: &Path,
+    identity: Option<PathBuf>,
+    args: Vec<String>,
+    password: Option<Zeroizing<String>>,
+) -> std::io::Result<String> {
+    // daemon keeps our pipe as its output until it joined network or gave up, so end of it
+    // tells us joining is over
+    let mut daemon = std::process::Command::new(std::env::current_exe()?);
+    daemon.arg("--daemon").arg("--control-socket").arg(path);
+    if let Some(identity) = identity {
+        daemon.arg("--identity").arg(identity);
+    }
+    daemon.args(args).stdout(std::process::Stdio::piped());
+    // password goes through pipe so it doesn't show in process list
+    let mut child = match password {
+        Some(password) => {
+            let mut child = daemon
+                .arg("--password-file")
+                .arg("-")
+                .stdin(std::process::Stdio::piped())
+                .spawn()?;
+            if let Some(mut stdin) = child.stdin.take() {
+                stdin.write_all(Zeroizing::new(format!("{}\n", *password)).as_bytes())?;
+            }
+            child
+        }
+        None => daemon.stdin(std::process::Stdio::null()).spawn()?,
+    };
+    if let Some(mut output) = child.stdout.take() {
+        std::io::copy(&mut output, &mut std::io::stdout())?;
+    }
+    let exit_status = child.wait()?;
+    if !exit_status.success() {
+        return Err(std::io::Error::other(format!(
+            "daemon failed to start: {}",
+            exit_status
+        )));
+    }
+
+    match send_command::<&str>(path, STATUS, &[]) {
+        Ok(s) if !s.starts_with("joining") => Ok(s),
+        Ok(_) => Err(std::io::Error::other(
+            "daemon closed its output before joining network",
+        )),
+        // daemon printed why it gave up
+        Err(e) => Err(std::io::Error::other(format!(
+            "daemon failed to join network: {}",
+            e
+        ))),
+    }
+}
+
+pub fn run_command(
+    command: Command,
+    path: PathBuf,
+    identity: Option<PathBuf>,
+) -> std::io::Result<()> {
+    let network_arg = |id: Option<String>| match id {
+        Some(id) => vec!["-n".to_owned(), id],
+        None => Vec::new(),
+    };
+    let response = match command {
+        Command::Status { network_id } => send_command(&path, STATUS, &network_arg(network_id)),
+        Command::Peers { network_id } => send_command(&path, PEERS, &network_arg(network_id)),
+        Command::Leave { network_id } => send_command(&path, LEAVE, &network_arg(network_id)),
+        Command::Join(mut args) => {
+            // password is read here, daemon has no terminal to ask on
+            config::read_password(&mut args.password, args.network_id.as_deref())?;
+            match UnixStream::connect(&path) {
+                // add network to running daemon
+                Ok(_) => {
+                    let join_args = join_args();
+                    let mut request: Vec<&str> = join_args.iter().map(String::as_str).collect();
+                    if let Some(password) = &args.password.value {
+                        request.extend(["-P", password.as_str()]);
+                    }
+                    send_command(&path, JOIN, &request)
+                }
+                Err(_) => spawn(&path, identity, join_args(), args.password.value),
+            }
+        }
+        Command::Allow { key, network_id } => send_command(
+            &path,
+            ALLOW,
+            &[vec![key.to_string()], network_arg(network_id)].concat(),
+        ),
+        Command::Revoke { key, network_id } => send_command(
+            &path,
+            REVOKE,
+            &[vec![key.to_string()], network_arg(network_id)].concat(),
+        ),
+        Command::Password {
+            network_id,
+            mut new,
+        } => {
+            // new password is asked for when it isn't given other way
+            if new.value.is_none() && new.file.is_none() && new.env.is_none() {
+                new.ask = true;
+            }
+            config::read_password(&mut new, network_id.as_deref())?;
+            let password = new.value.unwrap_or_default();
+            let mut request: Vec<&str> = vec![password.as_str()];
+            if let Some(id) = &network_id {
+                request.extend(["-n", id.as_str()]);
+            }
+            send_command(&path, PASSWORD, &request)
+        }
+        Command::Identity => unreachable!("identity is shown without daemon"),
+    };
+    match response {
+        Ok(r) if r.starts_with("error: ") => {
+            eprint!("{}", r);
+            exit(5); // EIO
+        }
+        Ok(r) => {
+            print!("{}", r);
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            eprintln!("daemon is not running on {}", path.display());
+            exit(2); // ENOENT
+        }
+        Err(e) => Err(e),
+    }
+}
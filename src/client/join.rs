@@ -0,0 +1,1173 @@
+use colored::Colorize;
+use pea_2_pea::{
+    shared::crypto::{Identity, IdentityKey, KdfParams},
+    *,
+};
+use rand::RngCore;
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::{ToSocketAddrs, UdpSocket},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use crate::batch;
+use crate::{
+    NetworkArgs, mtu, net, tun,
+    types::{self, JoinedNetwork, Network, Networks, VirtualIface},
+};
+
+/// check options before doing anything on network
+pub fn validate(args: &NetworkArgs) -> std::io::Result<()> {
+    let network_id = match args.network_id {
+        Some(ref id) => id,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "network id is required, use -n or profile",
+            ));
+        }
+    };
+    if args.registrar.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "at least one registrar is required, use -r or profile",
+        ));
+    }
+    if network_id.len() > 0xff {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ArgumentListTooLong,
+            "network id cannot have more then 255 charactes",
+        ));
+    }
+    if let Some(route) = args
+        .advertise_routes
+        .iter()
+        .find(|r| r.overlaps(&args.subnet.unwrap_or_default()))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("advertised route {} overlaps virtual subnet", route),
+        ));
+    }
+    if let Some(subnet) = args.subnet
+        && (subnet.prefix_len() < MIN_NETWORK_PREFIX_LEN || subnet.host_count() < 2)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("subnet {} is too small for virtual network", subnet),
+        ));
+    }
+    // hash is keyed by password, without it anybody could compute it from name
+    if args.hide_id && args.password.value.is_none() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "hiding network id needs password",
+        ));
+    }
+    // identities are proven only by handshake of aead networks
+    if !args.allow_members.is_empty()
+        && (args.password.value.is_none() || args.cipher == Some(CipherSuite::AES_256_CBC))
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "allowlist needs encrypted network with aes-256-gcm or chacha20-poly1305 cipher",
+        ));
+    }
+    Ok(())
+}
+
+/// posix error code to exit with when joining fails
+pub fn exit_code(e: &std::io::Error) -> i32 {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => 2,            // ENOENT
+        std::io::ErrorKind::ArgumentListTooLong => 7, // E2BIG
+        std::io::ErrorKind::InvalidInput => 22,       // EINVAL
+        std::io::ErrorKind::AlreadyExists => 17,      // EEXIST
+        std::io::ErrorKind::AddrInUse => 98,          // EADDRINUSE
+        std::io::ErrorKind::PermissionDenied => 13,   // EACCES
+        _ => 5,                                       // EIO
+    }
+}
+
+fn free_interface_name(networks: &Networks) -> String {
+    let networks = networks.read().unwrap();
+    (0..)
+        .map(|n| format!("{}{}", DEFAULT_INTERFACE_PREFIX, n))
+        .find(|name| !networks.values().any(|j| &j.if_name == name))
+        .unwrap()
+}
+
+pub fn join_network(
+    args: NetworkArgs,
+    networks: &Networks,
+    identity: &Arc<Identity>,
+) -> std::io::Result<Arc<JoinedNetwork>> {
+    validate(&args)?;
+    let network_id = args.network_id.clone().unwrap();
+    if networks
+        .read()
+        .unwrap()
+        .values()
+        .any(|j| j.network.read().unwrap().net_id == network_id)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("already member of network {}", network_id),
+        ));
+    }
+    if args.use_exit_node.is_some()
+        && args.exit_routes.is_empty()
+        && let Some(j) = networks.read().unwrap().values().find(|j| {
+            let n = j.network.read().unwrap();
+            n.use_exit_node.is_some() && n.exit_routes.is_empty()
+        })
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!(
+                "default route already goes through exit node of network {}",
+                j.network.read().unwrap().net_id
+            ),
+        ));
+    }
+    // registrar never sees name of network in privacy mode
+    let registrar_id: String = match (args.hide_id, &args.password.value) {
+        (true, Some(p)) => shared::crypto::hidden_network_id(&network_id, p.as_bytes())
+            .map_err(|e| std::io::Error::other(format!("failed to hide network id: {}", e)))?,
+        _ => network_id.clone(),
+    };
+    let left: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+
+    let mut buf: [u8; UDP_BUFFER_SIZE] = [0; UDP_BUFFER_SIZE];
+    let (socket, virtual_network) = {
+        let socket: Arc<UdpSocket> = Arc::new(UdpSocket::bind("0.0.0.0:0")?); // bind to OS assigned random port
+        tun::mark_underlay_socket(&socket)?;
+
+        #[cfg(not(feature = "no-timeout"))]
+        socket.set_read_timeout(Some(Duration::new(10, 0)))?; // set timeout to 10 seconds
+
+        let server_port: u16 = args.registrar_port.unwrap_or(SERVER_PORT);
+
+        // use first registrar that responds
+        #[allow(non_snake_case)] // i think this is valid snake case but rustc doesnt think so
+        let (server_SocketAddr, public_sock_addr_raw): (core::net::SocketAddr, String) = 'found: {
+            for registrar in args.registrar.iter() {
+                let addrs = match (registrar.as_str(), server_port).to_socket_addrs() {
+                    Ok(a) => a,
+                    Err(e) => {
+                        eprintln!(
+                            "{} {}:{} is invalid registrar address, Error: {}",
+                            "[WARNING]".yellow(),
+                            registrar,
+                            server_port,
+                            e
+                        );
+                        continue;
+                    }
+                };
+                for addr in addrs.filter(|a| a.is_ipv4()) {
+                    // query here
+                    match net::query_request(&mut buf, &addr, &socket) {
+                        Ok(s) => break 'found (addr, s),
+                        Err(e) => eprintln!(
+                            "{} registrar {} is not responding, Error: {}",
+                            "[WARNING]".yellow(),
+                            addr,
+                            e
+                        ),
+                    }
+                }
+            }
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotConnected,
+                "no registrar responded",
+            ));
+        };
+        println!(
+            "{} my bublic sockaddr: {}",
+            "[LOG]".blue(),
+            public_sock_addr_raw
+        );
+
+        let mut salt: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
+        let mut iv: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
+        if args.password.value.is_some() {
+            let mut rng = rand::rng();
+            rng.fill_bytes(&mut salt);
+            rng.fill_bytes(&mut iv);
+        }
+        // encrypted once we know key, it is derived only after network params are known
+        let mut public_sock_addr: Box<[u8]> =
+            public_sock_addr_raw.as_bytes().to_vec().into_boxed_slice();
+
+        let virtual_network: Arc<RwLock<Network>> = RwLock::new({
+            match net::get_request(
+                &mut buf,
+                &server_SocketAddr,
+                &socket,
+                &network_id,
+                &registrar_id,
+                &args.password.value,
+            ) {
+                Ok(mut n) => {
+                    eprintln!("Network exists joining it");
+                    if let Some(subnet) = args.subnet
+                        && subnet != n.subnet
+                    {
+                        eprintln!(
+                            "{} network uses subnet {}, ignoring requested subnet {}",
+                            "[WARNING]".yellow(),
+                            n.subnet,
+                            subnet
+                        );
+                    }
+                    if args.tap && n.mode != NetworkMode::TAP {
+                        eprintln!(
+                            "{} network uses {} mode, ignoring requested tap mode",
+                            "[WARNING]".yellow(),
+                            n.mode
+                        );
+                    }
+                    if let Some(cipher) = args.cipher
+                        && n.encrypted
+                        && cipher != n.cipher
+                    {
+                        eprintln!(
+                            "{} network uses {} cipher, ignoring requested {}",
+                            "[WARNING]".yellow(),
+                            n.cipher,
+                            cipher
+                        );
+                    }
+                    if args.compress && n.compression == Compression::NONE {
+                        eprintln!(
+                            "{} network was created without compression, ignoring requested compression",
+                            "[WARNING]".yellow()
+                        );
+                    }
+                    if !args.allow_members.is_empty() {
+                        eprintln!(
+                            "{} network already exists, ignoring allowed members, owner can change allowlist with allow command",
+                            "[WARNING]".yellow()
+                        );
+                    }
+                    check_subnet_free(&n, networks)?;
+                    if n.encrypts_sock_addrs() {
+                        public_sock_addr =
+                            shared::crypto::encrypt(&n.key, &iv, public_sock_addr_raw.as_bytes())
+                                .unwrap()
+                                .into_boxed_slice();
+                    }
+                    if net::send_heartbeat(
+                        &mut buf,
+                        &server_SocketAddr,
+                        socket.clone(),
+                        &n,
+                        &public_sock_addr,
+                        &iv,
+                        left.clone(),
+                    )
+                    .is_ok()
+                    {
+                        n.last_heartbeat_ack = Some(chrono::Utc::now().timestamp());
+                    }
+                    n
+                }
+                Err(e) if e.kind() == ServerResponse::ID_DOESNT_EXIST => {
+                    eprintln!("Network does not exist creating it!");
+                    let cipher = args.cipher.unwrap_or_default();
+                    let kdf = KdfParams::default();
+                    let encryption_key = match args.password.value {
+                        Some(ref p) => {
+                            let key = shared::crypto::derive_key(p.as_bytes(), &salt, &kdf)
+                                .map_err(|e| {
+                                    std::io::Error::other(format!(
+                                        "failed to derive key from password: {}",
+                                        e
+                                    ))
+                                })?;
+                            #[cfg(debug_assertions)]
+                            eprintln!(
+                                "key: {}",
+                                key.iter()
+                                    .map(|x| format!("{:02X} ", x))
+                                    .collect::<String>()
+                            );
+                            if cipher == CipherSuite::AES_256_CBC {
+                                public_sock_addr = shared::crypto::encrypt(
+                                    &key,
+                                    &iv,
+                                    public_sock_addr_raw.as_bytes(),
+                                )
+                                .unwrap()
+                                .into_boxed_slice();
+                            }
+                            key
+                        }
+                        None => shared::crypto::SecretKey::default(),
+                    };
+                    let mut tmp_v_net: Network = Network::new(
+                        args.password.value.is_some(),
+                        encryption_key,
+                        network_id,
+                        registrar_id,
+                        salt,
+                        args.subnet.unwrap_or_default(),
+                        match args.tap {
+                            true => NetworkMode::TAP,
+                            false => NetworkMode::TUN,
+                        },
+                        Vec::with_capacity(1),
+                    );
+                    tmp_v_net.cipher = cipher;
+                    tmp_v_net.kdf = kdf;
+                    if tmp_v_net.encrypted {
+                        // registrar gets only its hash, members get it from hello answers
+                        let mut update_secret = shared::crypto::SecretKey::default();
+                        rand::rng().fill_bytes(&mut *update_secret);
+                        tmp_v_net.update_secret = Some(update_secret);
+                    }
+                    if args.compress {
+                        tmp_v_net.compression = Compression::LZ4;
+                    }
+                    if !args.allow_members.is_empty() {
+                        // we own network, owner is allways member
+                        let mut members: Vec<IdentityKey> = vec![identity.public()];
+                        for member in args.allow_members.iter() {
+                            if !members.contains(member) {
+                                members.push(*member);
+                            }
+                        }
+                        tmp_v_net.owner = Some(identity.public());
+                        tmp_v_net.allowlist = Some(types::Allowlist::signed(
+                            identity,
+                            &tmp_v_net.net_tag,
+                            1,
+                            members,
+                        ));
+                    }
+                    check_subnet_free(&tmp_v_net, networks)?;
+                    net::register_request(
+                        &mut buf,
+                        &server_SocketAddr,
+                        &socket,
+                        &tmp_v_net,
+                        &public_sock_addr,
+                        &iv,
+                    )
+                    .map_err(ServerErrorResponses::into_io_error)?;
+
+                    if net::send_heartbeat(
+                        // send heart beat to start periodic heart beat
+                        &mut buf,
+                        &server_SocketAddr,
+                        socket.clone(),
+                        &tmp_v_net,
+                        &public_sock_addr,
+                        &iv,
+                        left.clone(),
+                    )
+                    .is_ok()
+                    {
+                        tmp_v_net.last_heartbeat_ack = Some(chrono::Utc::now().timestamp());
+                    }
+
+                    tmp_v_net
+                }
+                Err(e) => {
+                    return Err(std::io::Error::other(format!(
+                        "Failed to get data from server. Reason: {}",
+                        e
+                    )));
+                }
+            }
+        })
+        .into();
+        {
+            let mut network_write_lock = virtual_network.write().unwrap();
+            network_write_lock.registrar = Some(server_SocketAddr);
+            network_write_lock.public_sock_addr = public_sock_addr_raw.parse().ok();
+            network_write_lock.registration =
+                Some(types::EncryptablePulicSockAddr::new(iv, public_sock_addr));
+            network_write_lock.identity = Some(identity.clone());
+        }
+        (socket, virtual_network)
+    };
+
+    {
+        let mut network_write_lock = virtual_network.write().unwrap();
+        network_write_lock.routes = args.advertise_routes.clone();
+        network_write_lock.exit_node = args.exit_node;
+        network_write_lock.use_exit_node = args.use_exit_node;
+        network_write_lock.exit_routes = args.exit_routes.clone();
+    }
+    if !args.advertise_routes.is_empty() {
+        warn_if_not_forwarding();
+    }
+
+    // from now on heartbeat thread is running, stop it if we fail to join
+    let result = obtain_ip(&args, &mut buf, &socket, &virtual_network);
+    if result.is_err() {
+        left.store(true, Ordering::Relaxed);
+        result?;
+    }
+
+    let if_name = args
+        .if_name
+        .clone()
+        .unwrap_or_else(|| free_interface_name(networks));
+    let tun_iface = Arc::new(
+        match tun::create_tun_interface(
+            virtual_network.read().unwrap().private_ip,
+            virtual_network.read().unwrap().private_ip6,
+            &virtual_network.read().unwrap().subnet,
+            Some(if_name.clone()),
+            virtual_network.read().unwrap().mode,
+        ) {
+            Ok(t) => t,
+            Err(e) => {
+                left.store(true, Ordering::Relaxed);
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!(
+                        "failed to create Tun interface, Error: {}, are you running as root?",
+                        e
+                    ),
+                ));
+            }
+        },
+    );
+
+    {
+        let mtu = virtual_network.read().unwrap().mtu_for(UNDERLAY_MTU);
+        match tun::set_mtu(&tun_iface, mtu) {
+            Ok(_) => println!("{} interface mtu set to {}", "[LOG]".blue(), mtu),
+            Err(e) => eprintln!(
+                "{} failed to set interface mtu to {}, big packets will be fragmented, Error: {}",
+                "[WARNING]".yellow(),
+                mtu,
+                e
+            ),
+        }
+    }
+
+    {
+        let network_read_lock = virtual_network.read().unwrap();
+        for peer in network_read_lock.peers.iter() {
+            tun::add_peer_routes(&tun_iface, &network_read_lock, peer);
+        }
+    }
+
+    if let Err(e) = setup_exit_node(&args, &tun_iface, &virtual_network, &if_name) {
+        left.store(true, Ordering::Relaxed);
+        return Err(e);
+    }
+
+    // from now on timeout only lets threads notice that network was left
+    socket.set_read_timeout(Some(Duration::from_millis(LEAVE_POLL_INTERVAL_MS as u64)))?;
+
+    virtual_network.read().unwrap().publish(); // peers and exit node are known now
+    let joined = Arc::new(JoinedNetwork {
+        network: virtual_network.clone(),
+        routing: virtual_network.read().unwrap().routing.clone(),
+        tun_iface,
+        socket,
+        if_name,
+        left,
+    });
+    networks
+        .write()
+        .unwrap()
+        .insert(virtual_network.read().unwrap().net_tag, joined.clone());
+
+    {
+        let joined_clone = joined.clone();
+        std::thread::spawn(move || tun::read_tun_iface(joined_clone));
+    } // just let me have my thread
+    {
+        let joined_clone = joined.clone();
+        let networks_clone = networks.clone();
+        std::thread::spawn(move || listen(joined_clone, networks_clone));
+    }
+    {
+        let joined_clone = joined.clone();
+        std::thread::spawn(move || mtu::probe_path_mtu(joined_clone));
+    }
+
+    Ok(joined)
+}
+
+fn setup_exit_node(
+    args: &NetworkArgs,
+    tun_iface: &VirtualIface,
+    virtual_network: &RwLock<Network>,
+    if_name: &str,
+) -> std::io::Result<()> {
+    if args.exit_node {
+        tun::enable_exit_node(if_name, &virtual_network.read().unwrap().subnet)?;
+        println!("{} serving as exit node", "[LOG]".blue());
+    }
+    if let Some(exit_ip) = args.use_exit_node {
+        // exit node doesn't have to be online yet, packets are dropped until it joins
+        if !virtual_network
+            .read()
+            .unwrap()
+            .peers
+            .iter()
+            .any(|p| p.private_ip == exit_ip && p.exit_node)
+        {
+            eprintln!(
+                "{} peer {} is not exit node at the moment",
+                "[WARNING]".yellow(),
+                exit_ip
+            );
+        }
+        tun::use_exit_node(tun_iface, &args.exit_routes)?;
+        println!(
+            "{} routing {} through exit node {}",
+            "[LOG]".blue(),
+            match args.exit_routes.is_empty() {
+                true => "default traffic".to_owned(),
+                false => args
+                    .exit_routes
+                    .iter()
+                    .map(|r| r.to_string())
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            },
+            exit_ip
+        );
+    }
+    Ok(())
+}
+
+/// peers can send us packets for advertised routes but kernel has to forward them
+fn warn_if_not_forwarding() {
+    #[cfg(target_os = "linux")]
+    if let Ok(v) = std::fs::read_to_string("/proc/sys/net/ipv4/ip_forward")
+        && v.trim() == "0"
+    {
+        eprintln!(
+            "{} ip forwarding is disabled, advertised routes won't be reachable, enable it with: sysctl net.ipv4.ip_forward=1",
+            "[WARNING]".yellow()
+        );
+    }
+}
+
+fn check_subnet_free(network: &Network, networks: &Networks) -> std::io::Result<()> {
+    match networks
+        .read()
+        .unwrap()
+        .values()
+        .find(|j| j.network.read().unwrap().subnet.overlaps(&network.subnet))
+    {
+        Some(j) => Err(std::io::Error::new(
+            std::io::ErrorKind::AddrInUse,
+            format!(
+                "subnet {} of network {} overlaps with already joined network {}",
+                network.subnet,
+                network.net_id,
+                j.network.read().unwrap().net_id
+            ),
+        )),
+        None => Ok(()),
+    }
+}
+
+/// ask peers for their addresses, pick ours and introduce ourself
+fn obtain_ip(
+    args: &NetworkArgs,
+    buf: &mut [u8; UDP_BUFFER_SIZE],
+    socket: &UdpSocket,
+    virtual_network: &Arc<RwLock<Network>>,
+) -> std::io::Result<()> {
+    // all loops here will be auto skiped if there are no peers yet
+    let mut ips_used: HashSet<std::net::Ipv4Addr> = HashSet::new();
+    println!(
+        "{} reaching to other peers to obtain ip address",
+        "[LOG]".blue()
+    );
+    let mut network_write_lock = virtual_network.write().unwrap(); // avoid deadlock
+
+    let encrypted = network_write_lock.encrypted;
+    let cipher = network_write_lock.cipher;
+    let key = network_write_lock.key.clone();
+    let net_tag = network_write_lock.net_tag;
+    // keys agreed on in query that authenticate our hello and identities of peers
+    let mut handshakes: HashMap<std::net::SocketAddr, (shared::crypto::SecretKey, IdentityKey)> =
+        HashMap::new();
+    let mut wrong_password: bool = false;
+    network_write_lock.peers.iter_mut().for_each(|peer| {
+        println!(
+            "{} firing salvo of PUNCHING packets to {}",
+            "[LOG]".blue(),
+            peer.sock_addr
+        );
+        for _ in 0..MAPPING_SHOT_COUNT {
+            match socket.send_to(&[P2PMethods::DO_NOTHING as u8], peer.sock_addr) {
+                Ok(s) => {
+                    #[cfg(debug_assertions)]
+                    eprintln!("send {} bytes", s);
+                }
+                Err(e) => eprintln!("{} failed to send puching packet: {}", "[ERROR]".red(), e),
+            }
+        }
+        println!(
+            "{} packets away!, awiting a bit for NAT mappings to estabilish",
+            "[LOG]".blue()
+        );
+        std::thread::sleep(Duration::from_millis(2000));
+        for _ in 0..STANDARD_RETRY_MAX {
+            match net::P2P_query(
+                buf,
+                &peer.sock_addr,
+                socket,
+                encrypted,
+                cipher,
+                &key,
+                net_tag,
+            ) {
+                Ok((addrs, handshake)) => {
+                    if let Some(handshake) = handshake {
+                        handshakes.insert(peer.sock_addr, handshake);
+                    }
+                    ips_used.insert(addrs.ip);
+                    peer.private_ip = addrs.ip;
+                    peer.private_ip6 = addrs.ip6;
+                    peer.routes = addrs.routes;
+                    peer.exit_node = addrs.exit_node;
+                    break;
+                }
+                Err(e) => {
+                    eprintln!(
+                        "{} while getting ip from peer: {}, Error: {}",
+                        "[ERROR]".red(),
+                        peer.sock_addr,
+                        e
+                    );
+                    if e.downcast_ref::<std::io::Error>().map(|e| e.kind())
+                        == Some(std::io::ErrorKind::PermissionDenied)
+                    {
+                        wrong_password = true;
+                        break; // retrying won't help
+                    }
+                    std::thread::sleep(Duration::from_millis(2000));
+                }
+            }
+        }
+    });
+
+    let subnet = network_write_lock.subnet;
+    network_write_lock.private_ip = match args.address {
+        Some(addr) if !subnet.is_host(addr) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "requested address {} is not usable host address in {}",
+                    addr, subnet
+                ),
+            ));
+        }
+        Some(addr) if !ips_used.contains(&addr) => addr,
+        Some(addr) if args.address_fallback => {
+            eprintln!(
+                "{} requested address {} is already taken, falling back to automatic one",
+                "[WARNING]".yellow(),
+                addr
+            );
+            std::net::Ipv4Addr::UNSPECIFIED
+        }
+        Some(addr) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AddrInUse,
+                format!(
+                    "requested address {} is already taken by another peer",
+                    addr
+                ),
+            ));
+        }
+        None => std::net::Ipv4Addr::UNSPECIFIED,
+    };
+    if network_write_lock.private_ip.is_unspecified() {
+        network_write_lock.private_ip = match subnet.hosts().find(|ip| !ips_used.contains(ip)) {
+            Some(ip) => ip,
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!("no free address left in {}", subnet),
+                ));
+            }
+        };
+    }
+
+    network_write_lock.private_ip6 = network_write_lock.ip6_for(network_write_lock.private_ip);
+
+    network_write_lock
+        .peers
+        .retain(|peer| peer.private_ip != std::net::Ipv4Addr::UNSPECIFIED); // remove all peers without ip
+    if wrong_password && network_write_lock.peers.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            "wrong password, no peer accepted it",
+        ));
+    }
+
+    let addrs = network_write_lock.addrs();
+    let identity = network_write_lock
+        .identity
+        .clone()
+        .expect("identity is set when joining");
+    let prologue = network_write_lock.handshake_prologue();
+    let owner = network_write_lock.owner;
+    let mut allowlist: Option<types::Allowlist> = network_write_lock.allowlist.clone();
+    let mut update_secret: Option<shared::crypto::SecretKey> =
+        network_write_lock.update_secret.clone();
+    let mut refused: bool = false;
+    for peer in network_write_lock.peers.iter_mut() {
+        // psk is used only by this hello, it is wiped when handshake is dropped
+        let handshake = handshakes.remove(&peer.sock_addr);
+        let peer_identity = handshake.as_ref().map(|&(_, i)| i);
+        match net::P2P_hello(
+            buf,
+            &peer.sock_addr,
+            socket,
+            &addrs,
+            encrypted,
+            &key,
+            net_tag,
+            handshake.map(|(psk, peer_identity)| net::HelloHandshake {
+                psk,
+                identity: &identity,
+                peer_identity,
+                prologue: &prologue,
+            }),
+        ) {
+            Ok(answer) => {
+                if update_secret.is_none() {
+                    update_secret = answer.update_secret;
+                }
+                if let Some(session) = answer.session {
+                    peer.start_session(session);
+                    peer.identity = peer_identity;
+                    if let Some(owner) = owner
+                        && !answer.allowlist.is_empty()
+                    {
+                        match types::Allowlist::from_bytes(&answer.allowlist, &owner, &net_tag) {
+                            Some(l) if allowlist.as_ref().is_none_or(|a| a.version < l.version) => {
+                                allowlist = Some(l)
+                            }
+                            Some(_) => {}
+                            None => eprintln!(
+                                "{} peer: {} sent allowlist not signed by owner, ignoring it",
+                                "[WARNING]".yellow(),
+                                peer.sock_addr
+                            ),
+                        }
+                    }
+                }
+                eprintln!(
+                    "{} registered with peer: {}",
+                    "[SUCCESS]".green(),
+                    peer.sock_addr
+                )
+            }
+            Err(ServerErrorResponses::IP_IN_USE) => {
+                // peer claimed the address after we queried it
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::AddrInUse,
+                    format!(
+                        "peer: {} refused us, address {} is already in use",
+                        peer.sock_addr, addrs.ip
+                    ),
+                ));
+            }
+            Err(e) => {
+                refused |= matches!(e, ServerErrorResponses::NOT_ON_ALLOWLIST);
+                eprintln!(
+                    "{} failed to register with peer: {}, Error: {}",
+                    "[ERROR]".red(),
+                    peer.sock_addr,
+                    e
+                )
+            }
+        }
+    }
+    if owner.is_some() {
+        if refused && !network_write_lock.peers.iter().any(|p| p.session.is_some()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "our identity {} is not on allowlist of network",
+                    identity.public().fingerprint()
+                ),
+            ));
+        }
+        // peers answering us have to be members too
+        network_write_lock
+            .peers
+            .retain(|p| match (&allowlist, p.identity) {
+                (Some(list), Some(i)) if !list.allows(&i) => {
+                    eprintln!(
+                        "{} peer: {} with identity {} is not on allowlist, ignoring it",
+                        "[WARNING]".yellow(),
+                        p.sock_addr,
+                        i.fingerprint()
+                    );
+                    false
+                }
+                _ => true,
+            });
+        if allowlist.is_none() && !network_write_lock.peers.is_empty() {
+            eprintln!(
+                "{} no peer sent allowlist of network, hello of new peers will be refused until owner sends it",
+                "[WARNING]".yellow()
+            );
+        }
+        network_write_lock.allowlist = allowlist;
+    }
+
+    if encrypted && update_secret.is_none() && !network_write_lock.peers.is_empty() {
+        eprintln!(
+            "{} no peer sent update secret of network, we won't be able to change its password",
+            "[WARNING]".yellow()
+        );
+    }
+    network_write_lock.update_secret = update_secret;
+    Ok(())
+}
+
+/// receive packets on socket of joined network and hand them to network they belong to
+fn listen(joined: Arc<JoinedNetwork>, networks: Networks) {
+    println!("{} listener started!", "[LOG]".blue());
+    #[cfg(target_os = "linux")]
+    match listen_batched(&joined, &networks) {
+        Ok(_) => return, // network was left
+        Err(e) => eprintln!(
+            "{} batched receiving not available, falling back to one packet per syscall, Error: {}",
+            "[WARNING]".yellow(),
+            e
+        ),
+    }
+
+    let mut buf: [u8; UDP_BUFFER_SIZE] = [0; UDP_BUFFER_SIZE];
+    smol::block_on(async {
+        while !joined.left.load(Ordering::Relaxed) {
+            match joined.socket.recv_from(&mut buf) {
+                Ok((data_lenght, src)) => {
+                    dispatch(&joined, &networks, &mut buf[..data_lenght], src).await
+                }
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) => {
+                    eprintln!(
+                        "{} failed to read from socket Error: {}\n{}",
+                        "[WARNING]".red(),
+                        e,
+                        "Retrying".bright_yellow()
+                    );
+                }
+            }
+        }
+    });
+}
+
+/// many datagrams per syscall, datagrams coalesced by kernel are split back
+#[cfg(target_os = "linux")]
+fn listen_batched(joined: &Arc<JoinedNetwork>, networks: &Networks) -> std::io::Result<()> {
+    if let Err(e) = batch::enable_gro(&joined.socket) {
+        eprintln!(
+            "{} kernel won't coalesce received datagrams, Error: {}",
+            "[WARNING]".yellow(),
+            e
+        );
+    }
+    let mut recv_batch = batch::RecvBatch::new();
+    smol::block_on(async {
+        while !joined.left.load(Ordering::Relaxed) {
+            match recv_batch.recv(&joined.socket) {
+                Ok(_) => {
+                    for (datagram, src) in recv_batch.datagrams() {
+                        dispatch(joined, networks, datagram, src).await;
+                    }
+                }
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut => {}
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => return Err(e), // ancient kernel
+                Err(e) => {
+                    eprintln!(
+                        "{} failed to read from socket Error: {}\n{}",
+                        "[WARNING]".red(),
+                        e,
+                        "Retrying".bright_yellow()
+                    );
+                }
+            }
+        }
+        Ok(())
+    })
+}
+
+/// pick network datagram belongs to by its net tag and handle it there
+async fn dispatch(
+    joined: &Arc<JoinedNetwork>,
+    networks: &Networks,
+    buf: &mut [u8],
+    src: std::net::SocketAddr,
+) {
+    let data_lenght = buf.len();
+    if data_lenght == 0 {
+        return;
+    }
+    #[cfg(debug_assertions)]
+    eprintln!("recived method 0x{:02x} spawning handler", buf[0]);
+    let target: Arc<JoinedNetwork> = match buf[0] {
+        x if x == P2PMethods::PEER_QUERY as u8
+            || x == P2PMethods::PEER_HELLO as u8
+            || x == P2PMethods::PEER_GOODBYE as u8
+            || x == P2PMethods::PACKET as u8
+            || x == P2PMethods::PACKET_COMPRESSED as u8
+            || x == P2PMethods::ALLOWLIST as u8
+            || x == P2PMethods::NETWORK_KEY as u8
+            || x == P2PMethods::NEW_CLIENT_NOTIFY as u8
+            || x == P2PMethods::PMTU_PROBE as u8
+            || x == P2PMethods::PMTU_PROBE_ACK as u8 =>
+        {
+            let min_len = match x {
+                x if x == P2PMethods::PEER_QUERY as u8 => P2PStandardDataPositions::IV as usize,
+                x if x == P2PMethods::PMTU_PROBE as u8 || x == P2PMethods::PMTU_PROBE_ACK as u8 => {
+                    PmtuProbeDataPositions::END as usize
+                }
+                _ => P2PStandardDataPositions::DATA as usize,
+            };
+            if data_lenght < min_len {
+                // one byte acknowledgment of request we did not wait for
+                #[cfg(debug_assertions)]
+                eprintln!("acknowledgment 0x{:02x} from {}", buf[0], src);
+                return;
+            }
+            let net_tag: [u8; NET_TAG_SIZE] = buf[P2PStandardDataPositions::NET_TAG as usize
+                ..P2PStandardDataPositions::NET_TAG as usize + NET_TAG_SIZE]
+                .try_into()
+                .unwrap();
+            match networks.read().unwrap().get(&net_tag) {
+                Some(j) => j.clone(),
+                None => {
+                    eprintln!(
+                        "{} packet from {} for network we are not member of, Droping!",
+                        "[WARNING]".bright_yellow(),
+                        src
+                    );
+                    return;
+                }
+            }
+        }
+        _ => joined.clone(),
+    };
+    let routing = target.routing.load();
+    net::handle_incoming_connection(
+        buf,
+        src,
+        target.network.clone(),
+        &routing,
+        target.tun_iface.clone(),
+        joined.socket.clone(),
+        data_lenght,
+    )
+    .await;
+}
+
+/// owner adds member to allowlist or removes it and sends new list to peers,
+/// removed member is dropped by everybody, returns version of new list
+pub fn change_allowlist(
+    joined: &JoinedNetwork,
+    key: IdentityKey,
+    allow: bool,
+) -> std::io::Result<u64> {
+    let mut network = joined.network.write().unwrap();
+    if !network.is_owner() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::PermissionDenied,
+            format!(
+                "only owner can change allowlist of network {}",
+                network.net_id
+            ),
+        ));
+    }
+    let identity = network
+        .identity
+        .clone()
+        .expect("identity is set when joining");
+    let (version, mut members) = match &network.allowlist {
+        Some(list) => (list.version, list.members.clone()),
+        None => (0, vec![identity.public()]),
+    };
+    match (allow, members.contains(&key)) {
+        (true, true) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} is already on allowlist", key.fingerprint()),
+            ));
+        }
+        (false, false) => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("{} is not on allowlist", key.fingerprint()),
+            ));
+        }
+        (false, true) if key == identity.public() => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "owner can't remove itself from allowlist",
+            ));
+        }
+        (true, false) => members.push(key),
+        (false, true) => members.retain(|k| *k != key),
+    }
+    let allowlist = types::Allowlist::signed(&identity, &network.net_tag, version + 1, members);
+    network.allowlist = Some(allowlist.clone());
+
+    // removed member gets it too so it knows why peers stopped talking to it
+    let routing = joined.routing.load();
+    for peer in routing.peers.iter() {
+        if let Err(e) = net::P2P_allowlist(peer, &joined.socket, &routing, &allowlist) {
+            eprintln!(
+                "{} failed to send allowlist to peer: {}, Error: {}",
+                "[WARNING]".yellow(),
+                peer.sock_addr,
+                e
+            );
+        }
+    }
+    if !allow {
+        net::remove_peers(&mut network, &joined.tun_iface, |p| p.identity != Some(key));
+        network.publish();
+    }
+    Ok(allowlist.version)
+}
+
+/// derive key from new password with new salt and send it to peers, registrar has to take
+/// new salt first so nobody joins with old one, peers that miss it have to rejoin
+pub fn change_password(joined: &JoinedNetwork, password: &str) -> std::io::Result<()> {
+    let (net_id, kdf, registrar) = {
+        let network = joined.network.read().unwrap();
+        if !network.encrypted {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("network {} isn't encrypted", network.net_id),
+            ));
+        }
+        if network.registrar_id != network.net_id {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "id of network {} is hidden with its password, create new network instead",
+                    network.net_id
+                ),
+            ));
+        }
+        if network.owner.is_some() && !network.is_owner() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "only owner can change password of network {}",
+                    network.net_id
+                ),
+            ));
+        }
+        if network.update_secret.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "update secret of network {} is not known, registrar won't take new salt",
+                    network.net_id
+                ),
+            ));
+        }
+        let registrar = network.registrar.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, "registrar is not known")
+        })?;
+        (network.net_id.clone(), network.kdf, registrar)
+    };
+
+    let mut salt: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
+    rand::rng().fill_bytes(&mut salt);
+    let key = shared::crypto::derive_key(password.as_bytes(), &salt, &kdf)
+        .map_err(|e| std::io::Error::other(format!("failed to derive key from password: {}", e)))?;
+    let mut update_secret = shared::crypto::SecretKey::default();
+    rand::rng().fill_bytes(&mut *update_secret);
+    let (request, registration) = {
+        let network = joined.network.read().unwrap();
+        net::update_request(&network, &key, &salt, Some(&update_secret)).ok_or_else(|| {
+            std::io::Error::other(format!(
+                "failed to build registration update of network {}",
+                net_id
+            ))
+        })?
+    };
+
+    if !net::send_update(&joined.socket, &joined.network, registrar, &request)? {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            format!(
+                "registrar didn't take new salt of network {}, password wasn't changed",
+                net_id
+            ),
+        ));
+    }
+
+    let routing = joined.routing.load();
+    for peer in routing.peers.iter() {
+        if let Err(e) =
+            net::P2P_network_key(peer, &joined.socket, &routing, &key, &salt, &update_secret)
+        {
+            eprintln!(
+                "{} failed to send new network key to peer: {}, Error: {}",
+                "[WARNING]".yellow(),
+                peer.sock_addr,
+                e
+            );
+        }
+    }
+    net::switch_key(
+        &mut joined.network.write().unwrap(),
+        key,
+        salt,
+        update_secret,
+        registration,
+    );
+    println!("{} password of network {} changed", "[LOG]".blue(), net_id);
+    Ok(())
+}
+
+/// say goodbye to peers and stop threads serving network
+pub fn leave_network(joined: &JoinedNetwork, networks: &Networks) {
+    let network = joined.network.read().unwrap();
+    let routing = joined.routing.load();
+    routing.peers.iter().for_each(|peer| {
+        if let Err(e) = net::P2P_goodbye(peer, &joined.socket, &routing) {
+            eprintln!(
+                "{} failed to say goodbye to peer: {}, Error: {}",
+                "[WARNING]".yellow(),
+                peer.sock_addr,
+                e
+            );
+        }
+    });
+    if network.exit_node {
+        tun::disable_exit_node(&joined.if_name, &network.subnet);
+    }
+    if network.use_exit_node.is_some() {
+        tun::stop_using_exit_node(&network.exit_routes);
+    }
+    joined.left.store(true, Ordering::Relaxed);
+    networks.write().unwrap().remove(&network.net_tag);
+    println!("{} left network {}", "[LOG]".blue(), network.net_id);
+}
@@ -1,28 +1,64 @@
+#[cfg(target_os = "linux")]
+mod batch;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod join;
+mod mtu;
 mod net;
 mod tun;
 mod types;
 use colored::Colorize;
 use pea_2_pea::*;
-use rand::RngCore;
-use rayon::prelude::*;
 
-use std::{
-    net::UdpSocket,
-    process::exit,
-    sync::{Arc, RwLock},
-    time::Duration,
-};
-
-use crate::types::Network;
+use std::process::exit;
+use std::sync::Arc;
 
 #[derive(clap::Parser)]
 #[command(name = "pea_2_pea")]
 #[command(about = "A CLI tool for pea_2_pea P2P vpn client")]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    network: NetworkArgs,
+
+    #[arg(short = 'd', long = "daemon")]
+    #[arg(help = "run in background and accept commands on control socket")]
+    daemon: bool,
+
+    #[arg(long = "control-socket", global = true)]
+    #[arg(help = format!("path to daemon control socket Default: {}", DEFAULT_CONTROL_SOCKET))]
+    control_socket: Option<std::path::PathBuf>,
+
+    #[arg(long = "identity", global = true)]
+    #[arg(help = format!("path to identity key file, created when missing Default: ~/.config/pea_2_pea/{}", IDENTITY_FILE_NAME))]
+    identity: Option<std::path::PathBuf>,
+
+    #[arg(short = 'V', long = "version")]
+    version: bool,
+
+    #[arg(short = 'S', long = "symmetric_NAT_bypass_mode")]
+    #[arg(help = "NOT IMPLEMENTED")]
+    symmetric_nat_bypass_mode: bool,
+}
+
+#[derive(clap::Args, Clone)]
+struct NetworkArgs {
+    #[arg(short = 'c', long = "config")]
+    #[arg(help = "path to config file with profiles Default: ~/.config/pea_2_pea/client.toml")]
+    config: Option<std::path::PathBuf>,
+
+    #[arg(long = "profile")]
+    #[arg(
+        help = "use profile from config file, command line options override its values, can be repeated to join multiple networks"
+    )]
+    profile: Vec<String>,
+
     #[arg(short = 'r', long = "registrar")]
-    #[arg(help = "registrar ip address or hostname")]
-    #[arg(required_unless_present = "version")]
-    registrar: Option<String>,
+    #[arg(help = "registrar ip address or hostname, can be repeated to try multiple registrars")]
+    registrar: Vec<String>,
 
     #[arg(short = 'p', long = "registrar-port")]
     #[arg(help = format!("optional Port number for the registrar service (1-65535) Default: {}", SERVER_PORT))]
@@ -30,334 +66,374 @@ struct Cli {
 
     #[arg(short = 'n', long = "network-id")]
     #[arg(help = "your virtual network id that allows other people to connect to you")]
-    #[arg(required_unless_present = "version")]
     network_id: Option<String>,
 
-    #[arg(short = 'P', long = "password")]
+    #[command(flatten)]
+    password: PasswordArgs,
+
+    #[arg(long = "hide-id")]
+    #[arg(
+        help = "registrar gets only keyed hash of network id, all peers have to use it too, registrar can still guess weak passwords offline from the hash"
+    )]
+    hide_id: bool,
+
+    #[arg(long = "no-hide-id", overrides_with = "hide_id")]
+    #[arg(help = "send network id to registrar even if profile hides it")]
+    no_hide_id: bool,
+
+    #[arg(short = 'a', long = "address")]
+    #[arg(help = "request static virtual ip address, refuses to start if another peer holds it")]
+    address: Option<std::net::Ipv4Addr>,
+
+    #[arg(short = 'f', long = "address-fallback")]
+    #[arg(help = "if requested address is taken fall back to automatically assigned one")]
+    #[arg(requires = "address")]
+    address_fallback: bool,
+
+    #[arg(long = "no-address-fallback", overrides_with = "address_fallback")]
+    #[arg(help = "do not fall back to automatically assigned address even if profile does")]
+    no_address_fallback: bool,
+
+    #[arg(short = 's', long = "subnet")]
+    #[arg(help = format!("virtual subnet in CIDR notation used when creating network Default: {}", shared::subnet::Subnet::default()))]
+    subnet: Option<shared::subnet::Subnet>,
+
+    #[arg(short = 't', long = "tap")]
+    #[arg(
+        help = "tunnel ethernet frames instead of ip packets when creating network, needed by games using IPX or raw broadcasts"
+    )]
+    tap: bool,
+
+    #[arg(long = "no-tap", overrides_with = "tap")]
+    #[arg(help = "tunnel ip packets even if profile sets tap")]
+    no_tap: bool,
+
+    #[arg(short = 'z', long = "compress")]
+    #[arg(
+        help = "compress packets with lz4 when creating network, helps with games and remote desktop over slow links"
+    )]
+    compress: bool,
+
+    #[arg(long = "no-compress", overrides_with = "compress")]
+    #[arg(help = "do not compress packets even if profile does")]
+    no_compress: bool,
+
+    #[arg(long = "cipher")]
+    #[arg(
+        help = "cipher of packets when creating encrypted network: aes-256-gcm, chacha20-poly1305 or unauthenticated aes-256-cbc Default: aes-256-gcm"
+    )]
+    cipher: Option<CipherSuite>,
+
+    #[arg(long = "allow-member")]
+    #[arg(
+        help = "identity key of member when creating network with allowlist, can be repeated, creator owns network and can change allowlist later"
+    )]
+    allow_members: Vec<shared::crypto::IdentityKey>,
+
+    #[arg(short = 'R', long = "advertise-route")]
+    #[arg(help = "subnet behind this peer that other peers can reach through it, can be repeated")]
+    advertise_routes: Vec<shared::subnet::Subnet>,
+
+    #[arg(short = 'E', long = "exit-node")]
+    #[arg(help = "forward traffic of other peers to internet, linux only, needs iptables")]
+    exit_node: bool,
+
+    #[arg(long = "no-exit-node", overrides_with = "exit_node")]
+    #[arg(help = "do not serve as exit node even if profile does")]
+    no_exit_node: bool,
+
+    #[arg(long = "use-exit-node", conflicts_with = "exit_node")]
+    #[arg(help = "route traffic through peer with this virtual ip that serves as exit node")]
+    use_exit_node: Option<std::net::Ipv4Addr>,
+
+    #[arg(long = "exit-route", requires = "use_exit_node")]
     #[arg(
-        help = "encryption password for your virtual network if not provided transmitions will be unencrypted"
+        help = "route only this subnet through exit node instead of default route, can be repeated"
     )]
-    password: Option<String>,
+    exit_routes: Vec<shared::subnet::Subnet>,
 
     #[arg(short = 'i', long = "interface-name")]
-    #[arg(help = "select tun interface name Default: pea0")]
+    #[arg(help = "select tun interface name Default: first free of pea0, pea1, ...")]
     if_name: Option<String>,
 
     #[arg(short = 'v', long = "verbose")]
     verbose: bool,
 
-    #[arg(short = 'V', long = "version")]
-    version: bool,
+    #[arg(long = "no-verbose", overrides_with = "verbose")]
+    #[arg(help = "turn off verbose output set by profile")]
+    no_verbose: bool,
+}
 
-    #[arg(short = 'S', long = "symmetric_NAT_bypass_mode")]
-    #[arg(help = "NOT IMPLEMENTED")]
-    symmetric_nat_bypass_mode: bool,
+// -P is the only one other users can see in process list
+#[derive(clap::Args, Clone)]
+struct PasswordArgs {
+    #[arg(id = "password", short = 'P', long = "password")]
+    #[arg(
+        help = "encryption password for your virtual network if not provided transmitions will be unencrypted, other users can see it in process list"
+    )]
+    value: Option<zeroize::Zeroizing<String>>,
+
+    #[arg(
+        id = "password_file",
+        long = "password-file",
+        conflicts_with = "password"
+    )]
+    #[arg(help = "read password from first line of file, - reads it from standard input")]
+    file: Option<std::path::PathBuf>,
+
+    #[arg(id = "password_env", long = "password-env", conflicts_with_all = ["password", "password_file"])]
+    #[arg(help = "read password from environment variable")]
+    env: Option<String>,
+
+    #[arg(id = "ask_password", long = "ask-password", conflicts_with_all = ["password", "password_file", "password_env"])]
+    #[arg(help = "ask for password without showing it")]
+    ask: bool,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    #[command(about = "show state of networks joined by running daemon")]
+    Status {
+        #[arg(short = 'n', long = "network-id")]
+        #[arg(help = "show only this network")]
+        network_id: Option<String>,
+    },
+    #[command(about = "list peers of running daemon")]
+    Peers {
+        #[arg(short = 'n', long = "network-id")]
+        #[arg(help = "list only peers of this network")]
+        network_id: Option<String>,
+    },
+    #[command(
+        about = "say goodbye to peers and leave network, daemon stops when no network is left"
+    )]
+    Leave {
+        #[arg(short = 'n', long = "network-id")]
+        #[arg(help = "leave only this network Default: all")]
+        network_id: Option<String>,
+    },
+    #[command(about = "join network given by options, starts daemon if it is not running")]
+    Join(Box<NetworkArgs>),
+    #[command(about = "show identity key of this client and its fingerprint")]
+    Identity,
+    #[command(about = "add member to allowlist of network owned by running daemon")]
+    Allow {
+        key: shared::crypto::IdentityKey,
+        #[arg(short = 'n', long = "network-id")]
+        #[arg(help = "network to change Default: all owned ones")]
+        network_id: Option<String>,
+    },
+    #[command(
+        about = "remove member from allowlist of network owned by running daemon, it is dropped by peers"
+    )]
+    Revoke {
+        key: shared::crypto::IdentityKey,
+        #[arg(short = 'n', long = "network-id")]
+        #[arg(help = "network to change Default: all owned ones")]
+        network_id: Option<String>,
+    },
+    #[command(about = "change password of network joined by running daemon, peers get new key")]
+    Password {
+        #[arg(short = 'n', long = "network-id")]
+        #[arg(help = "network to change, needed when more networks are joined")]
+        network_id: Option<String>,
+        #[command(flatten)]
+        new: PasswordArgs,
+    },
+}
+
+/// keypair identifying us in networks with allowlist
+fn load_identity(path: Option<std::path::PathBuf>) -> std::io::Result<shared::crypto::Identity> {
+    let path = match path.or_else(config::default_identity_path) {
+        Some(p) => p,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "cannot determine identity file location use --identity",
+            ));
+        }
+    };
+    shared::crypto::Identity::load_or_create(&path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!("failed to load identity {}, Error: {}", path.display(), e),
+        )
+    })
 }
 
 fn print_version() {
     println!("Pea 2 Pea {}", VERSION);
 }
 
-fn main() -> std::io::Result<()> {
-    let cli = <Cli as clap::Parser>::parse();
-    if cli.version {
-        print_version();
-        exit(0);
+/// expand profiles given with --profile into one set of options per network
+fn resolve_profiles(mut args: NetworkArgs) -> std::io::Result<Vec<NetworkArgs>> {
+    let profile_names = std::mem::take(&mut args.profile);
+    // password given on command line is asked for once and shared by all profiles
+    config::read_password(&mut args.password, args.network_id.as_deref())?;
+    if profile_names.is_empty() {
+        return Ok(vec![args]);
     }
-    let network_id = cli.network_id.unwrap();
-    let registrar = cli.registrar.unwrap();
-    if network_id.len() > 0xff {
-        eprintln!("network id cannot have more then 255 charactes");
-        exit(7); // posix for E2BIG
+    if profile_names.len() > 1
+        && (args.network_id.is_some() || args.if_name.is_some() || args.address.is_some())
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "network id, interface name and address cannot be shared by multiple profiles",
+        ));
     }
-    let mut buf: [u8; UDP_BUFFER_SIZE] = [0; UDP_BUFFER_SIZE];
-    let (socket, virtual_network, _my_public_sock_addr) = {
-        let socket: Arc<UdpSocket> = Arc::new(|| -> std::io::Result<UdpSocket> {
-            match UdpSocket::bind("0.0.0.0:0") {
-                // bind to OS assigned random port
-                Ok(socket) => return Ok(socket),
-                Err(e) => Err(e), // exit on error
-            }
-        })()
-        .expect("Failed to bind to any available port")
-        .into();
-
-        #[cfg(not(feature = "no-timeout"))]
-        socket.set_read_timeout(Some(Duration::new(10, 0)))?; // set timeout to 10 seconds
-
-        let server_port: u16 = (|| -> u16 {
-            match cli.registrar_port {
-                Some(port_proveded) => return port_proveded,
-                None => return SERVER_PORT,
-            }
-        })();
-
-        #[allow(non_snake_case)] // i think this is valid snake case but rustc doesnt think so
-        let server_SocketAddr: core::net::SocketAddr = format!("{}:{}", registrar, server_port)
-            .parse()
-            .expect(&format!(
-                "{}:{} is invalid sock addr",
-                registrar, server_port
+    let config_path = match args.config.clone().or_else(config::default_config_path) {
+        Some(p) => p,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "cannot determine config file location use --config",
             ));
-
-        // query here
-        let public_sock_addr_raw: String =
-            match net::query_request(&mut buf, &server_SocketAddr, &socket) {
-                Ok(s) => s,
-                Err(e) => return Err(ServerErrorResponses::into_io_error(e)),
+        }
+    };
+    let mut config = config::load(&config_path)?;
+    profile_names
+        .iter()
+        .map(|profile_name| {
+            let profile = match config.profiles.remove(profile_name) {
+                Some(p) => p,
+                None => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::NotFound,
+                        format!(
+                            "profile {} not found in {}",
+                            profile_name,
+                            config_path.display()
+                        ),
+                    ));
+                }
             };
-        println!(
-            "{} my bublic sockaddr: {}",
-            "[LOG]".blue(),
-            public_sock_addr_raw
-        );
-
-        let mut salt: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
-        let mut iv: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
-        let (mut public_sock_addr, encryption_key) = match cli.password {
-            Some(ref p) => {
-                let mut rng = rand::rng();
-                rng.fill_bytes(&mut salt);
-                rng.fill_bytes(&mut iv);
-                let enc_key_tmp = shared::crypto::derive_key_from_password(p.as_bytes(), &salt);
-                #[cfg(debug_assertions)]
-                eprintln!(
-                    "key: {}",
-                    enc_key_tmp
-                        .iter()
-                        .map(|x| format!("{:02X} ", x))
-                        .collect::<String>()
-                );
-                (
-                    shared::crypto::encrypt(&enc_key_tmp, &iv, public_sock_addr_raw.as_bytes())
-                        .unwrap()
-                        .into_boxed_slice(),
-                    enc_key_tmp,
+            let mut profile_args = args.clone();
+            profile.apply(&mut profile_args).map_err(|e| {
+                std::io::Error::new(
+                    e.kind(),
+                    format!("failed to apply profile {}, Error: {}", profile_name, e),
                 )
-            }
-            None => (
-                public_sock_addr_raw.as_bytes().to_vec().into_boxed_slice(),
-                [0u8; 32],
-            ),
-        };
-
-        let virtual_network: Arc<RwLock<Network>> = RwLock::new({
-            match net::get_request(
-                &mut buf,
-                &server_SocketAddr,
-                &socket,
-                &network_id,
-                &cli.password,
-            ) {
-                Ok(n) => {
-                    eprintln!("Network exists joining it");
-                    public_sock_addr =
-                        shared::crypto::encrypt(&n.key, &iv, public_sock_addr_raw.as_bytes())
-                            .unwrap()
-                            .into_boxed_slice();
-                    let _ = net::send_heartbeat(
-                        &mut buf,
-                        &server_SocketAddr,
-                        socket.clone(),
-                        &n,
-                        &public_sock_addr,
-                        &iv,
-                    );
-                    n
-                }
-                Err(e) if e.kind() == ServerResponse::ID_DOESNT_EXIST => {
-                    eprintln!("Network does not exist creating it!");
-                    let tmp_v_net: Network = Network::new(
-                        match cli.password {
-                            Some(_) => true,
-                            None => false,
-                        },
-                        encryption_key,
-                        network_id,
-                        salt,
-                        Vec::with_capacity(1),
-                    );
-                    net::register_request(
-                        &mut buf,
-                        &server_SocketAddr,
-                        &socket,
-                        &tmp_v_net,
-                        &public_sock_addr,
-                        &iv,
-                    )
-                    .unwrap();
-
-                    let _ = net::send_heartbeat(
-                        // send heart beat to start periodic heart beat
-                        &mut buf,
-                        &server_SocketAddr,
-                        socket.clone(),
-                        &tmp_v_net,
-                        &public_sock_addr,
-                        &iv,
-                    );
-
-                    tmp_v_net
-                }
-                Err(e) => {
-                    eprintln!("Failed to get data from server. Reason: {}", e);
-                    exit(5); //EIO
-                }
-            }
+            })?;
+            Ok(profile_args)
         })
-        .into();
-        (
-            socket,
-            virtual_network,
-            types::EncryptablePulicSockAddr::new(iv, public_sock_addr),
-        )
-    };
+        .collect()
+}
 
-    {
-        // all loops here will be auto skiped if there are no peers yet
-        let mut ips_used: [bool; u8::MAX as usize + 1] = [false; u8::MAX as usize + 1];
-        ips_used[0] = true; // ignore net addr
-        ips_used[u8::MAX as usize] = true; // ignore broadcast
-        println!(
-            "{} reaching to other peers to obtain ip address",
-            "[LOG]".blue()
-        );
-        let mut network_write_lock = virtual_network.write().unwrap(); // avoid deadlock
-
-        let encrypted = network_write_lock.encrypted;
-        let key = network_write_lock.key;
-        network_write_lock.peers.iter_mut().for_each(|peer| {
-            println!(
-                "{} firing salvo of PUNCHING packets to {}",
-                "[LOG]".blue(),
-                peer.sock_addr
-            );
-            for _ in 0..MAPPING_SHOT_COUNT {
-                match socket.send_to(&[P2PMethods::DO_NOTHING as u8], peer.sock_addr) {
-                    Ok(s) => {
-                        #[cfg(debug_assertions)]
-                        eprintln!("send {} bytes", s);
-                    }
-                    Err(e) => eprintln!("{} failed to send puching packet: {}", "[ERROR]".red(), e),
-                }
-            }
-            println!(
-                "{} packets away!, awiting a bit for NAT mappings to estabilish",
-                "[LOG]".blue()
-            );
-            std::thread::sleep(Duration::from_millis(2000));
-            for _ in 0..STANDARD_RETRY_MAX {
-                match net::P2P_query(&mut buf, &peer.sock_addr, &socket, encrypted, key) {
-                    Ok(ip) => {
-                        ips_used[ip.octets()[3] as usize] = true;
-                        peer.private_ip = ip;
-                        break;
-                    }
-                    Err(e) => {
-                        eprintln!(
-                            "{} while getting ip from peer: {}, Error: {}",
-                            "[ERROR]".red(),
-                            peer.sock_addr,
-                            e
-                        );
-                        std::thread::sleep(Duration::from_millis(2000));
-                    }
-                }
+fn main() -> std::io::Result<()> {
+    let mut cli = <Cli as clap::Parser>::parse();
+    if cli.version {
+        print_version();
+        exit(0);
+    }
+    let control_socket: std::path::PathBuf = cli
+        .control_socket
+        .take()
+        .unwrap_or(DEFAULT_CONTROL_SOCKET.into());
+    let identity_path: Option<std::path::PathBuf> = cli.identity.take();
+    if let Some(Command::Identity) = cli.command {
+        match load_identity(identity_path) {
+            Ok(identity) => {
+                println!(
+                    "identity: {}\nfingerprint: {}",
+                    identity.public(),
+                    identity.public().fingerprint()
+                );
+                exit(0);
             }
-        });
-
-        network_write_lock.private_ip = std::net::Ipv4Addr::new(
-            DEFAULT_NETWORK_PREFIX[0],
-            DEFAULT_NETWORK_PREFIX[1],
-            DEFAULT_NETWORK_PREFIX[2],
-            ips_used.par_iter().position_first(|&b| !b).unwrap() as u8,
-        ); // find first element that is false
-
-        network_write_lock
-            .peers
-            .retain(|peer| peer.private_ip != std::net::Ipv4Addr::UNSPECIFIED); // remove all peers without ip
-
-        network_write_lock.peers.iter().for_each(|peer| {
-            match net::P2P_hello(
-                &mut buf,
-                &peer.sock_addr,
-                &socket,
-                network_write_lock.private_ip,
-                encrypted,
-                key,
-            ) {
-                Ok(_) => eprintln!(
-                    "{} registered with peer: {}",
-                    "[SUCCESS]".green(),
-                    peer.sock_addr
-                ),
-                Err(e) => eprintln!(
-                    "{} failed to register with peer: {}, Error: {}",
-                    "[ERROR]".red(),
-                    peer.sock_addr,
-                    e
-                ),
+            Err(e) => {
+                eprintln!("{} {}", "[CRITICAL]".red().bold(), e);
+                exit(join::exit_code(&e));
             }
-        });
+        }
+    }
+    if let Some(command) = cli.command.take() {
+        #[cfg(unix)]
+        return daemon::run_command(command, control_socket, identity_path);
+        #[cfg(not(unix))]
+        {
+            let _ = (command, identity_path);
+            eprintln!("daemon is supported only on unix systems");
+            exit(95); // EOPNOTSUPP
+        }
+    }
+    let networks_args = match resolve_profiles(cli.network) {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("{} {}", "[CRITICAL]".red().bold(), e);
+            exit(join::exit_code(&e));
+        }
+    };
+    for args in networks_args.iter() {
+        if let Err(e) = join::validate(args) {
+            eprintln!("{}", e);
+            exit(join::exit_code(&e));
+        }
     }
 
-    let tun_iface = Arc::new(
-        match tun::create_tun_interface(virtual_network.read().unwrap().private_ip, cli.if_name) {
-            Ok(t) => t,
+    let identity: Arc<shared::crypto::Identity> = match load_identity(identity_path) {
+        Ok(i) => Arc::new(i),
+        Err(e) => {
+            eprintln!("{} {}", "[CRITICAL]".red().bold(), e);
+            exit(join::exit_code(&e));
+        }
+    };
+
+    let networks: types::Networks = Default::default();
+    #[cfg(unix)]
+    let daemon_state: Option<Arc<daemon::DaemonState>> = if cli.daemon {
+        daemon::daemonize()?;
+        match daemon::listen(&control_socket, networks.clone(), identity.clone()) {
+            Ok(state) => Some(state),
             Err(e) => {
                 eprintln!(
-                    "{} failed to create Tun interface, Error: {}, are you running as root?",
+                    "{} failed to open control socket, Error: {}",
                     "[CRITICAL]".red().bold(),
                     e
                 );
-                return Err(e);
+                exit(98); // EADDRINUSE
             }
-        },
-    );
-
-    // timeout is no longer needed
-    #[cfg(not(feature = "no-timeout"))]
-    socket.set_read_timeout(None)?;
+        }
+    } else {
+        None
+    };
+    #[cfg(not(unix))]
+    if cli.daemon {
+        eprintln!("daemon is supported only on unix systems");
+        exit(95); // EOPNOTSUPP
+    }
 
-    {
-        let tun_iface_clone = tun_iface.clone();
-        let socket_clone = socket.clone();
-        let virtual_network_clone = virtual_network.clone();
-
-        std::thread::spawn(move || {
-            tun::read_tun_iface(tun_iface_clone, socket_clone, virtual_network_clone)
-        });
-    } // just let me have my thread
-
-    smol::block_on(async {
-        println!("{} listener started!", "[LOG]".blue());
-        loop {
-            buf.fill(0);
-            match socket.recv_from(&mut buf) {
-                Ok((data_lenght, src)) => {
-                    #[cfg(debug_assertions)]
-                    eprintln!("recived method 0x{:02x} spawning handler", buf[0]);
-                    smol::spawn(net::handle_incoming_connection(
-                        buf,
-                        src,
-                        virtual_network.clone(),
-                        tun_iface.clone(),
-                        socket.clone(),
-                        data_lenght,
-                    ))
-                    .await;
-                }
-                Err(e) => {
-                    eprintln!(
-                        "{} failed to read from socket Error: {}\n{}",
-                        "[WARNING]".red(),
-                        e,
-                        "Retrying".bright_yellow()
-                    );
-                }
+    for args in networks_args {
+        let network_id = args.network_id.clone().unwrap_or_default();
+        if let Err(e) = join::join_network(args, &networks, &identity) {
+            eprintln!(
+                "{} failed to join network {}, Error: {}",
+                "[CRITICAL]".red().bold(),
+                network_id,
+                e
+            );
+            // don't leave peers of already joined networks with dead address
+            for joined in networks.read().unwrap().clone().values() {
+                join::leave_network(joined, &networks);
+            }
+            #[cfg(unix)]
+            if daemon_state.is_some() {
+                let _ = std::fs::remove_file(&control_socket);
             }
+            exit(join::exit_code(&e));
         }
-    });
+    }
+
+    #[cfg(unix)]
+    if let Some(state) = daemon_state {
+        state
+            .ready
+            .store(true, std::sync::atomic::Ordering::Relaxed);
+        println!("{} joined network, detaching from terminal", "[LOG]".blue());
+        daemon::detach_output()?;
+    }
 
-    Ok(())
+    // network threads do all the work from now on
+    loop {
+        std::thread::park();
+    }
 }
@@ -0,0 +1,459 @@
+use colored::Colorize;
+use pea_2_pea::*;
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
+};
+
+use crate::{net, types::JoinedNetwork};
+
+const ETHERTYPE_IPV4: u16 = 0x0800;
+const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+
+const LENGTH_IN_IPV4_OFFSET: usize = 2;
+const FRAGMENT_IN_IPV4_OFFSET: usize = 6; // flags and fragment offset
+const CHECKSUM_IN_IPV4_OFFSET: usize = 10;
+const IPV4_DONT_FRAGMENT: u16 = 0x4000;
+const IPV4_FRAGMENT_OFFSET_MASK: u16 = 0x1fff;
+const DEFAULT_TTL: u8 = 64;
+
+const DATA_OFFSET_IN_TCP_OFFSET: usize = 12;
+const FLAGS_IN_TCP_OFFSET: usize = 13;
+const CHECKSUM_IN_TCP_OFFSET: usize = 16;
+const TCP_SYN: u8 = 0x02;
+const TCP_OPTION_END: u8 = 0;
+const TCP_OPTION_NOP: u8 = 1;
+const TCP_OPTION_MSS: u8 = 2;
+const TCP_OPTION_MSS_SIZE: usize = 4;
+
+const ICMP_DEST_UNREACHABLE: u8 = 3;
+const ICMP_FRAG_NEEDED: u8 = 4;
+const CHECKSUM_IN_ICMP_OFFSET: usize = 2;
+const MTU_IN_ICMP_OFFSET: usize = 6;
+const ICMP_QUOTED_DATA_SIZE: usize = 8; // sender needs only ports from quoted packet
+
+/// offset of ip header in packet or frame, None if it doesn't carry ip
+fn ip_offset(packet: &[u8], mode: NetworkMode) -> Option<usize> {
+    let offset = match mode {
+        NetworkMode::TUN => 0,
+        NetworkMode::TAP if packet.len() > ETHERNET_HEADER_SIZE => {
+            match u16::from_be_bytes([packet[ETHERTYPE_OFFSET], packet[ETHERTYPE_OFFSET + 1]]) {
+                ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => ETHERNET_HEADER_SIZE,
+                _ => return None, // arp, ipx and vlan tagged frames are left alone
+            }
+        }
+        NetworkMode::TAP => return None,
+    };
+    (packet.len() > offset).then_some(offset)
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+/// internet checksum used by ipv4, icmp and tcp
+fn checksum(data: &[u8]) -> u16 {
+    !fold(
+        data.chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]) as u32)
+            .sum(),
+    )
+}
+
+/// checksum after one 16 bit word changed, RFC 1624
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    !fold(!checksum as u32 + !old as u32 + new as u32)
+}
+
+/// lower mss announced in tcp syn so segments of connection fit into mtu
+pub fn clamp_mss(packet: &mut [u8], mode: NetworkMode, mtu: usize) {
+    let ip = match ip_offset(packet, mode) {
+        Some(o) => o,
+        None => return,
+    };
+    let (tcp, mss_max) = match packet[ip] >> 4 {
+        4 if packet.len() >= ip + IPV4_HEADER_SIZE
+            && packet[ip + PROTOCOL_IN_IPV4_OFFSET] == IPPROTO_TCP
+            && u16::from_be_bytes([
+                packet[ip + FRAGMENT_IN_IPV4_OFFSET],
+                packet[ip + FRAGMENT_IN_IPV4_OFFSET + 1],
+            ]) & IPV4_FRAGMENT_OFFSET_MASK
+                == 0 =>
+        {
+            (
+                ip + (packet[ip] & 0x0f) as usize * 4,
+                mtu.saturating_sub(IPV4_HEADER_SIZE + TCP_HEADER_SIZE),
+            )
+        }
+        // extension headers are not followed, tcp right after ipv6 header is what everyone sends
+        6 if packet.len() >= ip + IPV6_HEADER_SIZE
+            && packet[ip + NEXT_HEADER_IN_IPV6_OFFSET] == IPPROTO_TCP =>
+        {
+            (
+                ip + IPV6_HEADER_SIZE,
+                mtu.saturating_sub(IPV6_HEADER_SIZE + TCP_HEADER_SIZE),
+            )
+        }
+        _ => return,
+    };
+    if packet.len() < tcp + TCP_HEADER_SIZE || packet[tcp + FLAGS_IN_TCP_OFFSET] & TCP_SYN == 0 {
+        return;
+    }
+    let options_end =
+        (tcp + (packet[tcp + DATA_OFFSET_IN_TCP_OFFSET] >> 4) as usize * 4).min(packet.len());
+    let mut i = tcp + TCP_HEADER_SIZE;
+    while i < options_end {
+        match packet[i] {
+            TCP_OPTION_END => return,
+            TCP_OPTION_NOP => i += 1,
+            TCP_OPTION_MSS
+                if i + TCP_OPTION_MSS_SIZE <= options_end
+                    && packet[i + 1] as usize == TCP_OPTION_MSS_SIZE =>
+            {
+                let mss = u16::from_be_bytes([packet[i + 2], packet[i + 3]]);
+                if mss as usize > mss_max {
+                    #[cfg(debug_assertions)]
+                    eprintln!("clamping mss {} to {}", mss, mss_max);
+                    let sum = u16::from_be_bytes([
+                        packet[tcp + CHECKSUM_IN_TCP_OFFSET],
+                        packet[tcp + CHECKSUM_IN_TCP_OFFSET + 1],
+                    ]);
+                    packet[i + 2..i + 4].copy_from_slice(&(mss_max as u16).to_be_bytes());
+                    packet[tcp + CHECKSUM_IN_TCP_OFFSET..tcp + CHECKSUM_IN_TCP_OFFSET + 2]
+                        .copy_from_slice(&update_checksum(sum, mss, mss_max as u16).to_be_bytes());
+                }
+                return;
+            }
+            _ => match packet.get(i + 1) {
+                Some(&len) if len >= 2 => i += len as usize,
+                _ => return, // malformed options
+            },
+        }
+    }
+}
+
+/// icmp fragmentation needed telling sender of ipv4 packet with DF set to use smaller packets
+/// None if packet fits into mtu or may be fragmented, hop_ip is virtual ip of peer that can't take it
+pub fn frag_needed(
+    packet: &[u8],
+    mode: NetworkMode,
+    mtu: usize,
+    hop_ip: Ipv4Addr,
+) -> Option<Vec<u8>> {
+    let ip = ip_offset(packet, mode)?;
+    if packet.len() - ip <= mtu
+        || packet[ip] >> 4 != 4
+        || packet.len() < ip + IPV4_HEADER_SIZE + ICMP_HEADER_SIZE
+        || u16::from_be_bytes([
+            packet[ip + FRAGMENT_IN_IPV4_OFFSET],
+            packet[ip + FRAGMENT_IN_IPV4_OFFSET + 1],
+        ]) & IPV4_DONT_FRAGMENT
+            == 0
+    {
+        return None;
+    }
+    let header_len = (packet[ip] & 0x0f) as usize * 4;
+    if packet[ip + PROTOCOL_IN_IPV4_OFFSET] == IPPROTO_ICMP
+        && packet.get(ip + header_len) == Some(&ICMP_DEST_UNREACHABLE)
+    {
+        return None; // no errors about errors
+    }
+    let quoted = &packet[ip..(ip + header_len + ICMP_QUOTED_DATA_SIZE).min(packet.len())];
+
+    let reply_len = IPV4_HEADER_SIZE + ICMP_HEADER_SIZE + quoted.len();
+    let mut reply: Vec<u8> = vec![0u8; reply_len];
+    reply[0] = 0x45; // version 4 without options
+    reply[LENGTH_IN_IPV4_OFFSET..LENGTH_IN_IPV4_OFFSET + 2]
+        .copy_from_slice(&(reply_len as u16).to_be_bytes());
+    reply[TTL_IN_IPV4_OFFSET] = DEFAULT_TTL;
+    reply[PROTOCOL_IN_IPV4_OFFSET] = IPPROTO_ICMP;
+    reply[SRC_IN_IPV4_OFFSET..SRC_IN_IPV4_OFFSET + IPV4_SIZE].copy_from_slice(&hop_ip.octets());
+    reply[DEST_IN_IPV4_OFFSET..DEST_IN_IPV4_OFFSET + IPV4_SIZE]
+        .copy_from_slice(&packet[ip + SRC_IN_IPV4_OFFSET..ip + SRC_IN_IPV4_OFFSET + IPV4_SIZE]);
+    let sum = checksum(&reply[..IPV4_HEADER_SIZE]);
+    reply[CHECKSUM_IN_IPV4_OFFSET..CHECKSUM_IN_IPV4_OFFSET + 2].copy_from_slice(&sum.to_be_bytes());
+
+    let icmp = &mut reply[IPV4_HEADER_SIZE..];
+    icmp[0] = ICMP_DEST_UNREACHABLE;
+    icmp[1] = ICMP_FRAG_NEEDED;
+    icmp[MTU_IN_ICMP_OFFSET..MTU_IN_ICMP_OFFSET + 2].copy_from_slice(&(mtu as u16).to_be_bytes());
+    icmp[ICMP_HEADER_SIZE..].copy_from_slice(quoted);
+    let sum = checksum(icmp);
+    icmp[CHECKSUM_IN_ICMP_OFFSET..CHECKSUM_IN_ICMP_OFFSET + 2].copy_from_slice(&sum.to_be_bytes());
+
+    if mode == NetworkMode::TAP {
+        // frame goes back to sender as if peer's host sent it
+        let mut header: Vec<u8> = Vec::with_capacity(ETHERNET_HEADER_SIZE);
+        header.extend_from_slice(&packet[SRC_MAC_OFFSET..SRC_MAC_OFFSET + MAC_SIZE]);
+        header.extend_from_slice(&packet[..MAC_SIZE]);
+        header.extend_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        reply.splice(0..0, header);
+    }
+    Some(reply)
+}
+
+/// probes must not be fragmented and must ignore path mtu kernel already learned
+#[cfg(target_os = "linux")]
+fn set_probing(socket: &std::net::UdpSocket, probing: bool) -> std::io::Result<()> {
+    let value: libc::c_int = match probing {
+        true => libc::IP_PMTUDISC_PROBE,
+        false => libc::IP_PMTUDISC_WANT,
+    };
+    match unsafe {
+        libc::setsockopt(
+            std::os::fd::AsRawFd::as_raw_fd(socket),
+            libc::IPPROTO_IP,
+            libc::IP_MTU_DISCOVER,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// find largest packet that gets to each peer, peers are probed shortly after they show up and every PMTU_PROBE_INTERVAL_S
+/// on other systems than linux probes may get fragmented so path mtu stays at UNDERLAY_MTU
+pub fn probe_path_mtu(joined: Arc<JoinedNetwork>) {
+    while !joined.left.load(Ordering::Relaxed) {
+        let (probed, net_tag): (Vec<SocketAddr>, [u8; NET_TAG_SIZE]) = {
+            let mut network = joined.network.write().unwrap();
+            (
+                network
+                    .peers
+                    .iter_mut()
+                    .filter(|p| p.pmtu_probe_at <= Instant::now())
+                    .map(|p| {
+                        p.pmtu_probe_at =
+                            Instant::now() + Duration::from_secs(PMTU_PROBE_INTERVAL_S);
+                        p.pmtu_acked = 0;
+                        p.pmtu_probed = (1 << PMTU_PROBE_SIZES.len()) - 1;
+                        p.sock_addr
+                    })
+                    .collect(),
+                network.net_tag,
+            )
+        };
+        if probed.is_empty() {
+            std::thread::sleep(Duration::from_millis(LEAVE_POLL_INTERVAL_MS as u64));
+            continue;
+        }
+
+        #[cfg(target_os = "linux")]
+        if let Err(e) = set_probing(&joined.socket, true) {
+            eprintln!(
+                "{} failed to disable fragmentation of probes, Error: {}",
+                "[WARNING]".yellow(),
+                e
+            );
+        }
+        let mut unsent: Vec<(SocketAddr, usize)> = Vec::new();
+        for dst in probed.iter() {
+            for (i, size) in PMTU_PROBE_SIZES.into_iter().enumerate() {
+                // probe bigger than our own link is refused right away, its ack can only be forged
+                if net::P2P_pmtu_probe(dst, &joined.socket, size, net_tag).is_err() {
+                    unsent.push((*dst, i));
+                }
+            }
+        }
+        if !unsent.is_empty() {
+            let mut network = joined.network.write().unwrap();
+            for &(dst, i) in unsent.iter() {
+                if let Some(peer) = network.peers.iter_mut().find(|p| p.sock_addr == dst) {
+                    peer.pmtu_probed &= !(1 << i);
+                }
+            }
+        }
+        #[cfg(target_os = "linux")]
+        if let Err(e) = set_probing(&joined.socket, false) {
+            eprintln!(
+                "{} failed to enable fragmentation after probing, Error: {}",
+                "[WARNING]".yellow(),
+                e
+            );
+        }
+
+        std::thread::sleep(Duration::from_millis(PMTU_PROBE_WAIT_MS));
+        let mut network = joined.network.write().unwrap();
+        let mut changed = false;
+        for peer in network
+            .peers
+            .iter_mut()
+            .filter(|p| probed.contains(&p.sock_addr))
+        {
+            peer.pmtu_probed = 0;
+            // no ack means peer doesn't know probes or all of them got lost, keep what we have
+            if peer.pmtu_acked != 0 && peer.pmtu_acked != peer.path_mtu {
+                println!(
+                    "{} path mtu to peer: {} is {}",
+                    "[LOG]".blue(),
+                    peer.sock_addr,
+                    peer.pmtu_acked
+                );
+                peer.path_mtu = peer.pmtu_acked;
+                changed = true;
+            }
+        }
+        if changed {
+            network.publish();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: [u8; IPV4_SIZE] = [10, 0, 0, 1];
+    const DST: [u8; IPV4_SIZE] = [10, 0, 0, 2];
+    const TCP_ACK: u8 = 0x10;
+
+    /// ipv4 packet carrying tcp segment with mss option, both checksums are right
+    fn tcp_packet(flags: u8, mss: u16, dont_fragment: bool, payload: usize) -> Vec<u8> {
+        let tcp_len = TCP_HEADER_SIZE + TCP_OPTION_MSS_SIZE + payload;
+        let mut packet = vec![0u8; IPV4_HEADER_SIZE + tcp_len];
+        packet[0] = 0x45;
+        let len = packet.len() as u16;
+        packet[LENGTH_IN_IPV4_OFFSET..LENGTH_IN_IPV4_OFFSET + 2]
+            .copy_from_slice(&len.to_be_bytes());
+        if dont_fragment {
+            packet[FRAGMENT_IN_IPV4_OFFSET..FRAGMENT_IN_IPV4_OFFSET + 2]
+                .copy_from_slice(&IPV4_DONT_FRAGMENT.to_be_bytes());
+        }
+        packet[TTL_IN_IPV4_OFFSET] = DEFAULT_TTL;
+        packet[PROTOCOL_IN_IPV4_OFFSET] = IPPROTO_TCP;
+        packet[SRC_IN_IPV4_OFFSET..SRC_IN_IPV4_OFFSET + IPV4_SIZE].copy_from_slice(&SRC);
+        packet[DEST_IN_IPV4_OFFSET..DEST_IN_IPV4_OFFSET + IPV4_SIZE].copy_from_slice(&DST);
+        let sum = checksum(&packet[..IPV4_HEADER_SIZE]);
+        packet[CHECKSUM_IN_IPV4_OFFSET..CHECKSUM_IN_IPV4_OFFSET + 2]
+            .copy_from_slice(&sum.to_be_bytes());
+
+        let tcp = IPV4_HEADER_SIZE;
+        packet[tcp + DATA_OFFSET_IN_TCP_OFFSET] =
+            (((TCP_HEADER_SIZE + TCP_OPTION_MSS_SIZE) / 4) as u8) << 4;
+        packet[tcp + FLAGS_IN_TCP_OFFSET] = flags;
+        packet[tcp + TCP_HEADER_SIZE] = TCP_OPTION_MSS;
+        packet[tcp + TCP_HEADER_SIZE + 1] = TCP_OPTION_MSS_SIZE as u8;
+        packet[tcp + TCP_HEADER_SIZE + 2..tcp + TCP_HEADER_SIZE + 4]
+            .copy_from_slice(&mss.to_be_bytes());
+        let sum = tcp_checksum(&packet);
+        packet[tcp + CHECKSUM_IN_TCP_OFFSET..tcp + CHECKSUM_IN_TCP_OFFSET + 2]
+            .copy_from_slice(&sum.to_be_bytes());
+        packet
+    }
+
+    /// checksum of segment with pseudo header, 0 when stored one is right
+    fn tcp_checksum(packet: &[u8]) -> u16 {
+        let segment = &packet[IPV4_HEADER_SIZE..];
+        let mut data: Vec<u8> = [&SRC[..], &DST[..], &[0, IPPROTO_TCP]].concat();
+        data.extend_from_slice(&(segment.len() as u16).to_be_bytes());
+        data.extend_from_slice(segment);
+        checksum(&data)
+    }
+
+    fn mss(packet: &[u8]) -> u16 {
+        let i = packet.len() - TCP_OPTION_MSS_SIZE + 2;
+        u16::from_be_bytes([packet[i], packet[i + 1]])
+    }
+
+    #[test]
+    fn clamp_mss_lowers_mss_of_syn() {
+        let mut packet = tcp_packet(TCP_SYN, 1460, true, 0);
+        clamp_mss(&mut packet, NetworkMode::TUN, 1400);
+        assert_eq!(
+            mss(&packet) as usize,
+            1400 - IPV4_HEADER_SIZE - TCP_HEADER_SIZE
+        );
+        assert_eq!(tcp_checksum(&packet), 0);
+    }
+
+    #[test]
+    fn clamp_mss_keeps_small_mss_and_other_segments() {
+        let syn = tcp_packet(TCP_SYN, 1000, true, 0);
+        let mut packet = syn.clone();
+        clamp_mss(&mut packet, NetworkMode::TUN, 1400);
+        assert_eq!(packet, syn);
+
+        let ack = tcp_packet(TCP_ACK, 1460, true, 0);
+        let mut packet = ack.clone();
+        clamp_mss(&mut packet, NetworkMode::TUN, 1400);
+        assert_eq!(packet, ack);
+    }
+
+    #[test]
+    fn clamp_mss_in_tap_frame() {
+        let mut frame = vec![0u8; ETHERNET_HEADER_SIZE];
+        frame[ETHERTYPE_OFFSET..ETHERTYPE_OFFSET + 2]
+            .copy_from_slice(&ETHERTYPE_IPV4.to_be_bytes());
+        frame.extend_from_slice(&tcp_packet(TCP_SYN, 1460, true, 0));
+        clamp_mss(&mut frame, NetworkMode::TAP, 1400);
+        assert_eq!(
+            mss(&frame) as usize,
+            1400 - IPV4_HEADER_SIZE - TCP_HEADER_SIZE
+        );
+        assert_eq!(tcp_checksum(&frame[ETHERNET_HEADER_SIZE..]), 0);
+    }
+
+    #[test]
+    fn clamp_mss_tiny_mtu() {
+        let mut packet = tcp_packet(TCP_SYN, 1460, true, 0);
+        clamp_mss(&mut packet, NetworkMode::TUN, 10);
+        assert_eq!(mss(&packet), 0);
+        assert_eq!(tcp_checksum(&packet), 0);
+    }
+
+    #[test]
+    fn frag_needed_answers_big_packet() {
+        let packet = tcp_packet(TCP_ACK, 1460, true, 1000);
+        let hop = Ipv4Addr::new(10, 0, 0, 3);
+        let reply = frag_needed(&packet, NetworkMode::TUN, 576, hop).unwrap();
+        assert_eq!(checksum(&reply[..IPV4_HEADER_SIZE]), 0);
+        assert_eq!(reply[PROTOCOL_IN_IPV4_OFFSET], IPPROTO_ICMP);
+        assert_eq!(
+            reply[SRC_IN_IPV4_OFFSET..SRC_IN_IPV4_OFFSET + IPV4_SIZE],
+            hop.octets()
+        );
+        assert_eq!(
+            reply[DEST_IN_IPV4_OFFSET..DEST_IN_IPV4_OFFSET + IPV4_SIZE],
+            SRC
+        );
+
+        let icmp = &reply[IPV4_HEADER_SIZE..];
+        assert_eq!(checksum(icmp), 0);
+        assert_eq!(
+            (icmp[0], icmp[1]),
+            (ICMP_DEST_UNREACHABLE, ICMP_FRAG_NEEDED)
+        );
+        assert_eq!(
+            u16::from_be_bytes([icmp[MTU_IN_ICMP_OFFSET], icmp[MTU_IN_ICMP_OFFSET + 1]]),
+            576
+        );
+        assert_eq!(
+            icmp[ICMP_HEADER_SIZE..],
+            packet[..IPV4_HEADER_SIZE + ICMP_QUOTED_DATA_SIZE]
+        );
+    }
+
+    #[test]
+    fn frag_needed_ignores_packets_it_shouldnt_answer() {
+        let hop = Ipv4Addr::new(10, 0, 0, 3);
+        let fits = tcp_packet(TCP_ACK, 1460, true, 100);
+        assert!(frag_needed(&fits, NetworkMode::TUN, 576, hop).is_none());
+        let may_fragment = tcp_packet(TCP_ACK, 1460, false, 1000);
+        assert!(frag_needed(&may_fragment, NetworkMode::TUN, 576, hop).is_none());
+
+        // no errors about errors
+        let mut error = tcp_packet(TCP_ACK, 1460, true, 1000);
+        error[PROTOCOL_IN_IPV4_OFFSET] = IPPROTO_ICMP;
+        error[IPV4_HEADER_SIZE] = ICMP_DEST_UNREACHABLE;
+        assert!(frag_needed(&error, NetworkMode::TUN, 576, hop).is_none());
+    }
+}
@@ -1,14 +1,23 @@
 use std::{
     net::{Ipv4Addr, SocketAddr, UdpSocket},
     str::FromStr,
-    sync::{Arc, RwLock},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+    },
 };
 
 use super::types;
 use colored::Colorize;
-use pea_2_pea::{shared::net::send_and_recv_with_retry, *};
+use pea_2_pea::{
+    shared::crypto::{Identity, IdentityKey, KdfParams, SecretKey, SessionKeys},
+    shared::net::send_and_recv_with_retry,
+    shared::subnet::Subnet,
+    *,
+};
 use rand::{RngCore, rng};
 use sha2::Digest;
+use zeroize::Zeroize;
 
 pub fn query_request(
     buf: &mut [u8; UDP_BUFFER_SIZE],
@@ -47,11 +56,19 @@ pub fn register_request(
 ) -> Result<usize, ServerErrorResponses> {
     #[cfg(debug_assertions)]
     println!("REGISTER method");
+    let params: Box<[u8]> = network.params();
+    // registrar takes new salt only with secret of this hash, creator of network makes it
+    let secret_hash: Option<[u8; UPDATE_SECRET_SIZE]> = network
+        .update_secret
+        .as_ref()
+        .map(|s| shared::crypto::update_secret_hash(&**s));
     let mut send_buf: Box<[u8]> = vec![
         0u8;
         RegisterRequestDataPositions::DATA as usize
-            + network.net_id.len()
+            + network.registrar_id.len()
             + public_sock_addr.len()
+            + params.len()
+            + secret_hash.map_or(0, |h| h.len())
     ]
     .into_boxed_slice();
 
@@ -73,11 +90,11 @@ pub fn register_request(
     send_buf[0] = ServerMethods::REGISTER as u8; // set metod identification byte
     send_buf[RegisterRequestDataPositions::ENCRYPTED as usize] = network.encrypted as u8;
 
-    send_buf[RegisterRequestDataPositions::ID_LEN as usize] = network.net_id.len() as u8;
+    send_buf[RegisterRequestDataPositions::ID_LEN as usize] = network.registrar_id.len() as u8;
 
     send_buf[RegisterRequestDataPositions::DATA as usize
-        ..RegisterRequestDataPositions::DATA as usize + network.net_id.len()]
-        .copy_from_slice(network.net_id.as_bytes()); // store network id
+        ..RegisterRequestDataPositions::DATA as usize + network.registrar_id.len()]
+        .copy_from_slice(network.registrar_id.as_bytes()); // store network id
 
     send_buf[RegisterRequestDataPositions::IV as usize
         ..RegisterRequestDataPositions::IV as usize + BLOCK_SIZE as usize]
@@ -88,12 +105,22 @@ pub fn register_request(
 
     send_buf[RegisterRequestDataPositions::SOCKADDR_LEN as usize] = public_sock_addr.len() as u8;
 
-    send_buf[RegisterRequestDataPositions::DATA as usize + network.net_id.len()
+    send_buf[RegisterRequestDataPositions::DATA as usize + network.registrar_id.len()
         ..RegisterRequestDataPositions::DATA as usize
-            + network.net_id.len()
+            + network.registrar_id.len()
             + public_sock_addr.len()]
         .copy_from_slice(&public_sock_addr);
 
+    send_buf[RegisterRequestDataPositions::PARAMS_LEN as usize] = params.len() as u8;
+
+    let params_start: usize = RegisterRequestDataPositions::DATA as usize
+        + network.registrar_id.len()
+        + public_sock_addr.len();
+    send_buf[params_start..params_start + params.len()].copy_from_slice(&params);
+    if let Some(secret_hash) = secret_hash {
+        send_buf[params_start + params.len()..].copy_from_slice(&secret_hash);
+    }
+
     match send_and_recv_with_retry(buf, &send_buf, dst, socket, STANDARD_RETRY_MAX) {
         Ok((data_lenght, _)) => return Ok(data_lenght),
         Err(e) => return Err(e),
@@ -105,18 +132,18 @@ pub fn get_request(
     dst: &SocketAddr,
     socket: &UdpSocket,
     network_id: &String,
-    password: &Option<String>,
+    registrar_id: &String,
+    password: &Option<zeroize::Zeroizing<String>>,
 ) -> Result<types::Network, ServerErrorResponses> {
     #[cfg(debug_assertions)]
     println!("GET method");
     let mut send_buf: Box<[u8]> =
-        vec![0u8; GetRequestDataPositions::ID as usize + network_id.len()].into_boxed_slice();
+        vec![0u8; GetRequestDataPositions::ID as usize + registrar_id.len()].into_boxed_slice();
     send_buf[0] = ServerMethods::GET as u8;
     send_buf[GetRequestDataPositions::ID as usize
-        ..GetRequestDataPositions::ID as usize + network_id.len()]
-        .copy_from_slice(network_id.as_bytes());
+        ..GetRequestDataPositions::ID as usize + registrar_id.len()]
+        .copy_from_slice(registrar_id.as_bytes());
 
-    // this is unused now it will be used to bounds check in the future
     let data_lenght: usize =
         match send_and_recv_with_retry(buf, &send_buf, dst, socket, STANDARD_RETRY_MAX) {
             Ok((data_lenght, _)) => data_lenght,
@@ -126,7 +153,11 @@ pub fn get_request(
     let encrypted: bool = if buf[GetResponseDataPositions::ENCRYPTED as usize] != 0 {
         match password {
             Some(_) => true,
-            None => panic!("Network is encrypted but no password was provided"),
+            None => {
+                return Err(ServerErrorResponses::GENERAL_ERROR(
+                    "network is encrypted but no password was provided".to_owned(),
+                ));
+            }
         }
     } else {
         match password {
@@ -140,60 +171,106 @@ pub fn get_request(
         false
     };
 
-    let mut num_of_clients: u8 = buf[GetResponseDataPositions::NUM_OF_CLIENTS as usize];
+    let mut num_of_clients: u16 = u16::from_be_bytes(
+        buf[GetResponseDataPositions::NUM_OF_CLIENTS as usize
+            ..GetResponseDataPositions::NUM_OF_CLIENTS as usize + 2]
+            .try_into()
+            .unwrap(),
+    );
 
     let salt: [u8; BLOCK_SIZE as usize] = buf[GetResponseDataPositions::SALT as usize
         ..GetResponseDataPositions::SALT as usize + BLOCK_SIZE as usize]
         .try_into()
         .unwrap();
 
-    let mut offset: usize = 0;
+    let params_len: usize = buf[GetResponseDataPositions::PARAMS_LEN as usize] as usize;
+    if GetResponseDataPositions::PARAMS as usize + params_len > data_lenght {
+        return Err(ServerErrorResponses::GENERAL_ERROR(
+            "GET response is too short".to_owned(),
+        ));
+    }
+    if params_len < NetworkParamsPositions::END as usize {
+        return Err(ServerErrorResponses::GENERAL_ERROR(format!(
+            "network params are too short: {} bytes, network was created by incompatible client",
+            params_len
+        )));
+    }
+    let params: &[u8] = &buf[GetResponseDataPositions::PARAMS as usize
+        ..GetResponseDataPositions::PARAMS as usize + params_len];
+
+    let subnet: Subnet = Subnet::new(
+        Ipv4Addr::from(
+            <[u8; IPV4_SIZE]>::try_from(
+                &params[NetworkParamsPositions::SUBNET as usize
+                    ..NetworkParamsPositions::SUBNET as usize + IPV4_SIZE],
+            )
+            .unwrap(),
+        ),
+        params[NetworkParamsPositions::PREFIX_LEN as usize],
+    )?;
+    if subnet.prefix_len() < MIN_NETWORK_PREFIX_LEN || subnet.host_count() < 2 {
+        return Err(ServerErrorResponses::GENERAL_ERROR(format!(
+            "network subnet {} is not usable for virtual network",
+            subnet
+        )));
+    }
+    let mode: NetworkMode = NetworkMode::try_from(params[NetworkParamsPositions::MODE as usize])?;
+    let compression: Compression =
+        Compression::try_from(params[NetworkParamsPositions::COMPRESSION as usize])?;
+    let cipher: CipherSuite =
+        CipherSuite::try_from(params[NetworkParamsPositions::CIPHER as usize])?;
+    let kdf: KdfParams = KdfParams {
+        kdf: Kdf::try_from(params[NetworkParamsPositions::KDF as usize])?,
+        memory: u32::from_be_bytes(
+            params[NetworkParamsPositions::KDF_MEMORY as usize
+                ..NetworkParamsPositions::KDF_ITERATIONS as usize]
+                .try_into()
+                .unwrap(),
+        ),
+        iterations: u32::from_be_bytes(
+            params[NetworkParamsPositions::KDF_ITERATIONS as usize
+                ..NetworkParamsPositions::KDF_PARALLELISM as usize]
+                .try_into()
+                .unwrap(),
+        ),
+        parallelism: params[NetworkParamsPositions::KDF_PARALLELISM as usize],
+    };
+
+    // client blocks start right after params
+    let mut offset: usize = GetResponseDataPositions::PARAMS as usize + params_len;
     let mut peers: Vec<types::Peer> = Vec::with_capacity(1); // at least one client
 
-    let key: [u8; 32] = match password {
-        Some(p) => shared::crypto::derive_key_from_password(p.as_bytes(), &salt),
-        None => [0; 32],
+    let key: SecretKey = match password {
+        Some(p) => shared::crypto::derive_key(p.as_bytes(), &salt, &kdf).map_err(|e| {
+            ServerErrorResponses::GENERAL_ERROR(format!(
+                "failed to derive key from password: {}",
+                e
+            ))
+        })?,
+        None => SecretKey::default(),
     };
-    #[cfg(debug_assertions)]
-    eprintln!(
-        "key: {}",
-        key.iter()
-            .map(|x| format!("{:02X} ", x))
-            .collect::<String>()
-    );
 
     while num_of_clients != 0 {
-        let sock_addr_len: u8 = buf[GetResponseDataPositions::CLIENTS as usize + offset];
+        // registrar tells count of clients, it doesn't have to match what it sent
+        if offset + 1 + BLOCK_SIZE > data_lenght
+            || offset + 1 + BLOCK_SIZE + buf[offset] as usize > data_lenght
+        {
+            return Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "GET response is too short for {} more clients",
+                num_of_clients
+            )));
+        }
+        let sock_addr_len: u8 = buf[offset];
         let mut iv: [u8; BLOCK_SIZE as usize] = [0; BLOCK_SIZE as usize];
-        let sock_addr_raw: Box<[u8]> =
-            buf[GetResponseDataPositions::CLIENTS as usize + 1 + offset + BLOCK_SIZE as usize
-                ..GetResponseDataPositions::CLIENTS as usize
-                    + 1
-                    + offset
-                    + BLOCK_SIZE as usize
-                    + sock_addr_len as usize]
-                .to_vec()
-                .into_boxed_slice();
+        let sock_addr_raw: Box<[u8]> = buf[offset + 1 + BLOCK_SIZE as usize
+            ..offset + 1 + BLOCK_SIZE as usize + sock_addr_len as usize]
+            .to_vec()
+            .into_boxed_slice();
 
         loop {
             // loop used to easily skip peer
-            let peer: SocketAddr = if encrypted {
-                iv.copy_from_slice(
-                    &buf[GetResponseDataPositions::CLIENTS as usize + 1 + offset
-                        ..GetResponseDataPositions::CLIENTS as usize
-                            + 1
-                            + offset
-                            + BLOCK_SIZE as usize],
-                );
-                #[cfg(debug_assertions)]
-                eprintln!(
-                    "IV: {}\nSockAddr: {}",
-                    iv.iter().map(|x| format!("{:02X} ", x)).collect::<String>(),
-                    sock_addr_raw
-                        .iter()
-                        .map(|x| format!("{:02X} ", x))
-                        .collect::<String>(),
-                );
+            let peer: SocketAddr = if encrypted && cipher == CipherSuite::AES_256_CBC {
+                iv.copy_from_slice(&buf[offset + 1..offset + 1 + BLOCK_SIZE as usize]);
                 match SocketAddr::from_str(&{
                     // sacrificed a goat to borrow checker to make this work
                     let decrypted = match shared::crypto::decrypt(&key, &iv, &sock_addr_raw) {
@@ -243,13 +320,23 @@ pub fn get_request(
         num_of_clients -= 1;
     }
 
-    return Ok(types::Network::new(
+    let mut network = types::Network::new(
         encrypted,
         key,
         network_id.to_string(),
+        registrar_id.to_string(),
         salt,
+        subnet,
+        mode,
         peers,
-    ));
+    );
+    network.compression = compression;
+    network.cipher = cipher;
+    network.kdf = kdf;
+    network.owner = params
+        .get(NetworkParamsPositions::END as usize..OWNED_NETWORK_PARAMS_SIZE)
+        .map(|k| IdentityKey(k.try_into().unwrap()));
+    return Ok(network);
 }
 
 pub fn send_heartbeat(
@@ -259,20 +346,44 @@ pub fn send_heartbeat(
     network: &types::Network,
     my_public_sock_addr: &Box<[u8]>,
     iv: &[u8; BLOCK_SIZE as usize],
+    left: Arc<AtomicBool>,
 ) -> Result<usize, ServerErrorResponses> {
     #[cfg(debug_assertions)]
     println!("HEARTBEAT method");
+    let send_buf: Box<[u8]> = heartbeat_request(network, my_public_sock_addr, iv);
+    network.heartbeat.store(Arc::new(send_buf.clone()));
+
+    {
+        let sock_clone = socket.clone();
+        let heartbeat_clone = network.heartbeat.clone();
+        let dst_clone: SocketAddr = dst.clone();
+        std::thread::spawn(move || {
+            periodic_heart_beat(sock_clone, heartbeat_clone, dst_clone, left);
+        });
+    }
+
+    match send_and_recv_with_retry(buf, &send_buf, dst, &socket, STANDARD_RETRY_MAX) {
+        Ok((data_lenght, _)) => return Ok(data_lenght),
+        Err(e) => return Err(e),
+    }
+}
+
+fn heartbeat_request(
+    network: &types::Network,
+    my_public_sock_addr: &[u8],
+    iv: &[u8; BLOCK_SIZE as usize],
+) -> Box<[u8]> {
     let mut send_buf: Box<[u8]> = vec![
         0u8;
         HeartBeatRequestDataPositions::IV as usize
             + BLOCK_SIZE as usize
             + my_public_sock_addr.len()
-            + network.net_id.len()
+            + network.registrar_id.len()
     ]
     .into_boxed_slice();
 
     send_buf[0] = ServerMethods::HEARTBEAT as u8;
-    send_buf[HeartBeatRequestDataPositions::ID_LEN as usize] = network.net_id.len() as u8;
+    send_buf[HeartBeatRequestDataPositions::ID_LEN as usize] = network.registrar_id.len() as u8;
     send_buf[HeartBeatRequestDataPositions::SOCKADDR_LEN as usize] =
         my_public_sock_addr.len() as u8;
 
@@ -281,12 +392,12 @@ pub fn send_heartbeat(
         .copy_from_slice(iv);
 
     send_buf[HeartBeatRequestDataPositions::DATA as usize
-        ..HeartBeatRequestDataPositions::DATA as usize + network.net_id.len()]
-        .copy_from_slice(network.net_id.as_bytes());
+        ..HeartBeatRequestDataPositions::DATA as usize + network.registrar_id.len()]
+        .copy_from_slice(network.registrar_id.as_bytes());
 
-    send_buf[HeartBeatRequestDataPositions::DATA as usize + network.net_id.len()
+    send_buf[HeartBeatRequestDataPositions::DATA as usize + network.registrar_id.len()
         ..HeartBeatRequestDataPositions::DATA as usize
-            + network.net_id.len()
+            + network.registrar_id.len()
             + my_public_sock_addr.len()]
         .copy_from_slice(&my_public_sock_addr);
 
@@ -299,40 +410,164 @@ pub fn send_heartbeat(
             .map(|x| format!("{:02X} ", x))
             .collect::<String>(),
     );
+    send_buf
+}
 
-    {
-        let sock_clone = socket.clone();
-        let send_buf_clone: Box<[u8]> = send_buf.clone();
-        let dst_clone: SocketAddr = dst.clone();
-        std::thread::spawn(move || {
-            periodic_heart_beat(sock_clone, send_buf_clone, dst_clone);
-        });
+/// update of our registration after password change, carries new salt and our sock addr,
+/// on aes-256-cbc networks sock addr is encrypted with new key so it gets new iv,
+/// returns request and registration it makes, changing salt reveals update secret of network
+/// and commits to next one, following salt somebody else changed needs no secret
+pub fn update_request(
+    network: &types::Network,
+    key: &[u8; 32],
+    salt: &[u8; BLOCK_SIZE],
+    next_secret: Option<&[u8; UPDATE_SECRET_SIZE]>,
+) -> Option<(Box<[u8]>, types::EncryptablePulicSockAddr)> {
+    let current = network.registration.as_ref()?;
+    let registration = match network.encrypts_sock_addrs() {
+        true => {
+            let mut iv: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
+            rng().fill_bytes(&mut iv);
+            let sock_addr =
+                shared::crypto::encrypt(key, &iv, network.public_sock_addr?.to_string().as_bytes())
+                    .ok()?;
+            types::EncryptablePulicSockAddr::new(iv, sock_addr.into_boxed_slice())
+        }
+        false => types::EncryptablePulicSockAddr::new(current.iv, current.sock_addr.clone()),
+    };
+    let mut send_buf: Box<[u8]> = vec![
+        0u8;
+        UpdateRequestDataPositions::DATA as usize
+            + network.registrar_id.len()
+            + registration.sock_addr.len()
+    ]
+    .into_boxed_slice();
+    send_buf[0] = ServerMethods::UPDATE as u8;
+    send_buf[UpdateRequestDataPositions::ID_LEN as usize] = network.registrar_id.len() as u8;
+    send_buf[UpdateRequestDataPositions::SOCKADDR_LEN as usize] =
+        registration.sock_addr.len() as u8;
+    send_buf[UpdateRequestDataPositions::SALT as usize..UpdateRequestDataPositions::IV as usize]
+        .copy_from_slice(salt);
+    send_buf[UpdateRequestDataPositions::IV as usize..UpdateRequestDataPositions::SECRET as usize]
+        .copy_from_slice(&registration.iv);
+    if let Some(next_secret) = next_secret {
+        send_buf[UpdateRequestDataPositions::SECRET as usize
+            ..UpdateRequestDataPositions::NEXT_SECRET_HASH as usize]
+            .copy_from_slice(&**network.update_secret.as_ref()?);
+        send_buf[UpdateRequestDataPositions::NEXT_SECRET_HASH as usize
+            ..UpdateRequestDataPositions::DATA as usize]
+            .copy_from_slice(&shared::crypto::update_secret_hash(next_secret));
     }
+    send_buf[UpdateRequestDataPositions::DATA as usize
+        ..UpdateRequestDataPositions::DATA as usize + network.registrar_id.len()]
+        .copy_from_slice(network.registrar_id.as_bytes());
+    send_buf[UpdateRequestDataPositions::DATA as usize + network.registrar_id.len()..]
+        .copy_from_slice(&registration.sock_addr);
+    Some((send_buf, registration))
+}
 
-    match send_and_recv_with_retry(buf, &send_buf, dst, &socket, STANDARD_RETRY_MAX) {
-        Ok((data_lenght, _)) => return Ok(data_lenght),
-        Err(e) => return Err(e),
+/// send registration update until registrar confirms it, listener notes the confirmation
+pub fn send_update(
+    socket: &UdpSocket,
+    network: &RwLock<types::Network>,
+    registrar: SocketAddr,
+    request: &[u8],
+) -> std::io::Result<bool> {
+    network.write().unwrap().last_update_ack = None;
+    for _ in 0..STANDARD_RETRY_MAX {
+        socket.send_to(request, registrar)?;
+        std::thread::sleep(std::time::Duration::from_secs(1));
+        if network.read().unwrap().last_update_ack.is_some() {
+            return Ok(true);
+        }
     }
+    Ok(false)
+}
+
+/// start using key derived from new password, periodic heartbeat carries new registration
+/// from now on so registrar keeps matching it
+pub fn switch_key(
+    network: &mut types::Network,
+    key: SecretKey,
+    salt: [u8; BLOCK_SIZE],
+    update_secret: SecretKey,
+    registration: types::EncryptablePulicSockAddr,
+) {
+    network.change_key(key, salt, update_secret);
+    network.heartbeat.store(Arc::new(heartbeat_request(
+        network,
+        &registration.sock_addr,
+        &registration.iv,
+    )));
+    network.registration = Some(registration);
+    network.publish();
 }
 
+/// addresses of peer, key agreed on by PAKE that authenticates our hello and identity of peer
+type QueryAnswer = (types::PeerAddrs, Option<(SecretKey, IdentityKey)>);
+
 #[allow(non_snake_case)]
 pub fn P2P_query(
     buf: &mut [u8; UDP_BUFFER_SIZE],
     dst: &SocketAddr,
     socket: &UdpSocket,
     encrypted: bool, // avoid deadlock
-    key: [u8; 32],
-) -> Result<std::net::Ipv4Addr, Box<dyn std::error::Error>> {
+    cipher: CipherSuite,
+    key: &[u8; 32],
+    net_tag: [u8; NET_TAG_SIZE],
+) -> Result<QueryAnswer, Box<dyn std::error::Error>> {
     #[cfg(debug_assertions)]
     println!("P2P QUERY method");
 
-    let (data_lenght, _) = send_and_recv_with_retry(
-        buf,
-        &[P2PMethods::PEER_QUERY as u8],
-        dst,
-        socket,
-        STANDARD_RETRY_MAX,
-    )?;
+    // on aead networks query runs PAKE, answer encrypted with network key would let anybody
+    // who asks check guessed passwords offline
+    let pake = match encrypted && cipher != CipherSuite::AES_256_CBC {
+        true => Some(shared::crypto::pake_start(key, &net_tag, true)),
+        false => None,
+    };
+    let mut send_buf: Box<[u8]> = match pake {
+        Some((_, ref message)) => {
+            vec![0u8; P2PStandardDataPositions::DATA as usize + message.len()].into()
+        }
+        None => vec![0u8; P2PStandardDataPositions::IV as usize].into(),
+    };
+    send_buf[0] = P2PMethods::PEER_QUERY as u8;
+    send_buf[P2PStandardDataPositions::NET_TAG as usize
+        ..P2PStandardDataPositions::NET_TAG as usize + NET_TAG_SIZE]
+        .copy_from_slice(&net_tag);
+    if let Some((_, ref message)) = pake {
+        send_buf[P2PStandardDataPositions::DATA as usize..].copy_from_slice(message);
+    }
+
+    let (data_lenght, _) =
+        send_and_recv_with_retry(buf, &send_buf, dst, socket, STANDARD_RETRY_MAX)?;
+
+    if let Some((state, _)) = pake {
+        if data_lenght < P2PStandardDataPositions::DATA as usize + PAKE_MESSAGE_SIZE {
+            return Err("answer to query is too short, peer has incompatible client".into());
+        }
+        let (header, data) =
+            buf[..data_lenght].split_at_mut(P2PStandardDataPositions::DATA as usize);
+        let (message, encrypted_addrs) = data.split_at_mut(PAKE_MESSAGE_SIZE);
+        let keys = shared::crypto::pake_finish(state, message)?;
+        let iv = &header[P2PStandardDataPositions::IV as usize
+            ..P2PStandardDataPositions::IV as usize + BLOCK_SIZE];
+        let len = shared::crypto::open_in_place(cipher, &keys.answer, iv, header, encrypted_addrs)
+            .map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "wrong password, peer doesn't use same one",
+                )
+            })?;
+        if len < IDENTITY_KEY_SIZE {
+            return Err("answer to query is too short, peer has incompatible client".into());
+        }
+        let (identity, addrs) = encrypted_addrs[..len].split_at(IDENTITY_KEY_SIZE);
+        return Ok((
+            types::PeerAddrs::from_str(std::str::from_utf8(addrs)?)?,
+            Some((keys.psk, IdentityKey(identity.try_into().unwrap()))),
+        ));
+    }
 
     let iv: [u8; BLOCK_SIZE] = buf
         [P2PStandardDataPositions::IV as usize..P2PStandardDataPositions::IV as usize + BLOCK_SIZE]
@@ -341,9 +576,9 @@ pub fn P2P_query(
 
     let tmp_decrypted: Vec<u8>;
 
-    return Ok(std::net::Ipv4Addr::from_str(if encrypted {
+    let addrs = types::PeerAddrs::from_str(if encrypted {
         match shared::crypto::decrypt(
-            &key,
+            key,
             &iv,
             &buf[P2PStandardDataPositions::DATA as usize..data_lenght - 1],
         ) {
@@ -366,25 +601,86 @@ pub fn P2P_query(
             Ok(s) => s,
             Err(e) => return Err(Box::new(e)),
         }
-    })?);
+    })?;
+    Ok((addrs, None))
+}
+
+/// what hello on aead network needs, psk and identity of peer come from query
+pub struct HelloHandshake<'a> {
+    pub psk: SecretKey,
+    pub identity: &'a Identity,
+    pub peer_identity: IdentityKey,
+    pub prologue: &'a [u8],
+}
+
+/// what peer sent in answer to hello, session keys and allowlist come only on aead networks
+#[derive(Default)]
+pub struct HelloAnswer {
+    pub session: Option<SessionKeys>,
+    pub update_secret: Option<SecretKey>,
+    pub allowlist: Vec<u8>,
+}
+
+/// our update secret in front of hello answer payload, zeroed when we don't know it
+fn hello_answer_secret(network: &types::Network) -> Vec<u8> {
+    match &network.update_secret {
+        Some(secret) => secret.to_vec(),
+        None => vec![0u8; UPDATE_SECRET_SIZE],
+    }
+}
+
+/// update secret in front of hello answer payload, zeroed one means peer doesn't know it
+fn hello_update_secret(payload: &[u8]) -> Option<SecretKey> {
+    let secret = SecretKey::new(
+        payload
+            .get(
+                HelloAnswerPositions::UPDATE_SECRET as usize
+                    ..HelloAnswerPositions::ALLOWLIST as usize,
+            )?
+            .try_into()
+            .ok()?,
+    );
+    match *secret == [0u8; UPDATE_SECRET_SIZE] {
+        true => None,
+        false => Some(secret),
+    }
 }
 
 #[allow(non_snake_case)]
+#[allow(clippy::too_many_arguments)]
 pub fn P2P_hello(
     buf: &mut [u8; UDP_BUFFER_SIZE],
     dst: &SocketAddr,
     socket: &UdpSocket,
-    private_ip: Ipv4Addr,
+    addrs: &types::PeerAddrs,
     encrypted: bool, // avoid deadlock
-    key: [u8; 32],
-) -> Result<usize, ServerErrorResponses> {
-    let private_ip_str = private_ip.to_string();
-    let (private_ip_final, iv) = if encrypted {
+    key: &[u8; 32],
+    net_tag: [u8; NET_TAG_SIZE],
+    handshake: Option<HelloHandshake>, // used instead of key on aead networks
+) -> Result<HelloAnswer, ServerErrorResponses> {
+    let private_ip_str = addrs.to_string();
+    // on aead networks addresses go in first handshake message and peer answers with second one
+    let handshake = match handshake {
+        Some(h) => Some(
+            shared::crypto::handshake_initiate(
+                &h.psk,
+                h.prologue,
+                h.identity,
+                &h.peer_identity,
+                private_ip_str.as_bytes(),
+            )
+            .map_err(|e| ServerErrorResponses::GENERAL_ERROR(e.to_string()))?,
+        ),
+        None => None,
+    };
+    let (private_ip_final, iv) = if let Some((_, message)) = &handshake {
+        (message.clone().into_boxed_slice(), [0u8; BLOCK_SIZE])
+    } else if encrypted {
         let mut rng = rng();
         let mut iv: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
         rng.fill_bytes(&mut iv);
         (
-            shared::crypto::encrypt(&key, &iv, &private_ip_str.as_bytes())
+            shared::crypto::encrypt(key, &iv, &private_ip_str.as_bytes())
                 .unwrap()
                 .into_boxed_slice(),
             iv,
@@ -410,85 +706,478 @@ pub fn P2P_hello(
     );
 
     send_buf[0] = P2PMethods::PEER_HELLO as u8;
+    send_buf[P2PStandardDataPositions::NET_TAG as usize
+        ..P2PStandardDataPositions::NET_TAG as usize + NET_TAG_SIZE]
+        .copy_from_slice(&net_tag);
     send_buf
         [P2PStandardDataPositions::IV as usize..P2PStandardDataPositions::IV as usize + BLOCK_SIZE]
         .copy_from_slice(&iv);
 
     send_buf[P2PStandardDataPositions::DATA as usize..].copy_from_slice(&private_ip_final);
 
-    match send_and_recv_with_retry(buf, &send_buf, dst, socket, STANDARD_RETRY_MAX) {
-        Ok((data_lenght, _)) => return Ok(data_lenght),
-        Err(e) => return Err(e),
+    let (data_lenght, _) =
+        send_and_recv_with_retry(buf, &send_buf, dst, socket, STANDARD_RETRY_MAX)?;
+    match handshake {
+        Some((state, _)) => match shared::crypto::handshake_finish(state, &buf[1..data_lenght]) {
+            Ok((session, payload)) => {
+                let payload = zeroize::Zeroizing::new(payload);
+                Ok(HelloAnswer {
+                    session: Some(session),
+                    update_secret: hello_update_secret(&payload),
+                    allowlist: payload
+                        .get(HelloAnswerPositions::ALLOWLIST as usize..)
+                        .unwrap_or_default()
+                        .to_vec(),
+                })
+            }
+            Err(e) => Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "peer didn't finish handshake: {}",
+                e
+            ))),
+        },
+        None if encrypted && data_lenght > 1 + BLOCK_SIZE => {
+            match shared::crypto::decrypt(
+                key,
+                &buf[1..1 + BLOCK_SIZE],
+                &buf[1 + BLOCK_SIZE..data_lenght],
+            ) {
+                Ok(payload) => Ok(HelloAnswer {
+                    update_secret: hello_update_secret(&zeroize::Zeroizing::new(payload)),
+                    ..Default::default()
+                }),
+                Err(e) => Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                    "failed to decrypt answer of peer: {}",
+                    e
+                ))),
+            }
+        }
+        None => Ok(HelloAnswer::default()),
+    }
+}
+
+/// tell peer we are leaving, peer answers but listener takes care of that
+#[allow(non_snake_case)]
+pub fn P2P_goodbye(
+    peer: &types::PeerRoute,
+    socket: &UdpSocket,
+    routing: &types::RoutingTable,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    // sealed like packets so on aead networks only session keys are used on the wire
+    let send_buf = crate::tun::seal_packet(
+        routing,
+        P2PMethods::PEER_GOODBYE as u8,
+        Ipv4Addr::from(routing.sender).to_string().as_bytes(),
+        Some(peer),
+    )
+    .ok_or("failed to seal goodbye")?;
+
+    Ok(socket.send_to(&send_buf, peer.sock_addr)?)
+}
+
+/// send allowlist to peer, it is signed by owner so anybody can pass it on
+#[allow(non_snake_case)]
+pub fn P2P_allowlist(
+    peer: &types::PeerRoute,
+    socket: &UdpSocket,
+    routing: &types::RoutingTable,
+    allowlist: &types::Allowlist,
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let send_buf = crate::tun::seal_packet(
+        routing,
+        P2PMethods::ALLOWLIST as u8,
+        &allowlist.to_bytes(),
+        Some(peer),
+    )
+    .ok_or("failed to seal allowlist")?;
+
+    Ok(socket.send_to(&send_buf, peer.sock_addr)?)
+}
+
+/// send salt, key derived from new password and update secret that goes with them to peer,
+/// sealed with keys it knows now
+#[allow(non_snake_case)]
+pub fn P2P_network_key(
+    peer: &types::PeerRoute,
+    socket: &UdpSocket,
+    routing: &types::RoutingTable,
+    key: &[u8; 32],
+    salt: &[u8; BLOCK_SIZE],
+    update_secret: &[u8; UPDATE_SECRET_SIZE],
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut data = zeroize::Zeroizing::new([0u8; NetworkKeyPositions::END as usize]);
+    data[NetworkKeyPositions::SALT as usize..NetworkKeyPositions::KEY as usize]
+        .copy_from_slice(salt);
+    data[NetworkKeyPositions::KEY as usize..NetworkKeyPositions::UPDATE_SECRET as usize]
+        .copy_from_slice(key);
+    data[NetworkKeyPositions::UPDATE_SECRET as usize..].copy_from_slice(update_secret);
+    let send_buf =
+        crate::tun::seal_packet(routing, P2PMethods::NETWORK_KEY as u8, &*data, Some(peer))
+            .ok_or("failed to seal network key")?;
+
+    Ok(socket.send_to(&send_buf, peer.sock_addr)?)
+}
+
+/// forget peers keep returns false for together with their routes and learned macs,
+/// caller publishes routing table
+pub fn remove_peers(
+    network: &mut types::Network,
+    tun_iface: &types::VirtualIface,
+    keep: impl Fn(&types::Peer) -> bool,
+) {
+    let (kept, removed): (Vec<types::Peer>, Vec<types::Peer>) = std::mem::take(&mut network.peers)
+        .into_iter()
+        .partition(|p| keep(p));
+    network.peers = kept;
+    for peer in removed.iter() {
+        if network.peers.iter().any(|p| p.sock_addr == peer.sock_addr) {
+            continue;
+        }
+        network
+            .mac_table
+            .retain(|_, sock_addr| *sock_addr != peer.sock_addr); // frames for removed peer will be flooded
+        for route in peer.routes.iter().filter(|r| !r.overlaps(&network.subnet)) {
+            if let Err(e) = crate::tun::remove_route(tun_iface, route) {
+                eprintln!(
+                    "{} failed to remove route {}, Error: {}",
+                    "[WARNING]".yellow(),
+                    route,
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// probe padded to size of ip packet on underlay, peer acks it only if it gets through whole
+#[allow(non_snake_case)]
+pub fn P2P_pmtu_probe(
+    dst: &SocketAddr,
+    socket: &UdpSocket,
+    size: usize,
+    net_tag: [u8; NET_TAG_SIZE],
+) -> std::io::Result<usize> {
+    let mut send_buf: Box<[u8]> = vec![0u8; size - IPV4_HEADER_SIZE - UDP_HEADER_SIZE].into();
+    send_buf[0] = P2PMethods::PMTU_PROBE as u8;
+    send_buf[PmtuProbeDataPositions::NET_TAG as usize
+        ..PmtuProbeDataPositions::NET_TAG as usize + NET_TAG_SIZE]
+        .copy_from_slice(&net_tag);
+    send_buf[PmtuProbeDataPositions::SIZE as usize..PmtuProbeDataPositions::END as usize]
+        .copy_from_slice(&(size as u16).to_be_bytes());
+    socket.send_to(&send_buf, dst)
+}
+
+/// remember which peer frame came from so replies to it are not flooded
+fn learn_mac(
+    network: &RwLock<types::Network>,
+    routing: &types::RoutingTable,
+    frame: &[u8],
+    src: SocketAddr,
+) {
+    if routing.mode != NetworkMode::TAP || frame.len() < ETHERNET_HEADER_SIZE {
+        return;
+    }
+    let src_mac: [u8; MAC_SIZE] = frame[SRC_MAC_OFFSET..SRC_MAC_OFFSET + MAC_SIZE]
+        .try_into()
+        .unwrap();
+    if src_mac[0] & 1 == 1 {
+        return; // group address can't be source
+    }
+    if routing.peer(&src).is_none() || routing.mac_table.get(&src_mac) == Some(&src) {
+        return;
+    }
+    let mut network_write_lock = network.write().unwrap();
+    network_write_lock.mac_table.insert(src_mac, src);
+    network_write_lock.publish();
+}
+
+fn remember_flood(network: &RwLock<types::Network>, routing: &types::RoutingTable, packet: &[u8]) {
+    if crate::tun::is_flooded(packet, routing) == Some(true) {
+        network
+            .write()
+            .unwrap()
+            .remember_flood(crate::tun::flood_key(packet, routing.mode));
+    }
+}
+
+/// peer whose virtual ip is in nonce, its session key opens packet
+fn sender_of<'a>(routing: &'a types::RoutingTable, iv: &[u8]) -> Option<&'a types::PeerRoute> {
+    let sender = Ipv4Addr::from(
+        <[u8; IPV4_SIZE]>::try_from(
+            &iv[PacketIvPositions::SENDER as usize..PacketIvPositions::COUNTER as usize],
+        )
+        .unwrap(),
+    );
+    let peer = routing.peer_with_ip(sender);
+    #[cfg(debug_assertions)]
+    if peer.is_none() {
+        eprintln!("packet from unknown peer {} dropped", sender);
+    }
+    peer
+}
+
+/// check counter in authenticated nonce against window of its sender, so packet
+/// captured and sent again from any address is dropped
+fn is_replayed(sender: &types::PeerRoute, iv: &[u8]) -> bool {
+    let counter = u64::from_be_bytes(
+        iv[PacketIvPositions::COUNTER as usize..PacketIvPositions::EPOCH as usize]
+            .try_into()
+            .unwrap(),
+    );
+    let replayed = !sender.replay.lock().unwrap().accept(counter);
+    #[cfg(debug_assertions)]
+    if replayed {
+        eprintln!(
+            "replayed packet {} from {} dropped",
+            counter, sender.private_ip
+        );
+    }
+    replayed
+}
+
+/// decrypt data of packet sealed by tun::seal_packet, None if it should be dropped
+fn open_packet<'a>(
+    routing: &types::RoutingTable,
+    buf: &'a mut [u8],
+    decrypted: &'a mut Vec<u8>,
+) -> Option<&'a mut [u8]> {
+    let (header, payload) = buf.split_at_mut(P2PStandardDataPositions::DATA as usize);
+    let iv = &header
+        [P2PStandardDataPositions::IV as usize..P2PStandardDataPositions::IV as usize + BLOCK_SIZE];
+    let sender = match routing.sessions {
+        true => Some(sender_of(routing, iv)?),
+        false => None,
+    };
+    let opened = match (routing.encrypted, routing.cipher) {
+        (true, CipherSuite::AES_256_CBC) => {
+            match shared::crypto::decrypt(&routing.key, iv, payload) {
+                Ok(d) => {
+                    *decrypted = d;
+                    Ok(&mut decrypted[..])
+                }
+                Err(e) => Err(e),
+            }
+        }
+        (true, suite) => match sender.and_then(|p| p.session.as_ref()) {
+            Some(session) => {
+                let epoch = u32::from_be_bytes(
+                    iv[PacketIvPositions::EPOCH as usize..PacketIvPositions::END as usize]
+                        .try_into()
+                        .unwrap(),
+                );
+                let mut session = session.lock().unwrap();
+                match session.recv_key(epoch) {
+                    Some(key) => shared::crypto::open_in_place(suite, &key, iv, header, payload)
+                        .map(|len| {
+                            session.advance_recv(epoch, key);
+                            &mut payload[..len]
+                        }),
+                    None => Err(format!("no key for epoch {} of sender", epoch).into()),
+                }
+            }
+            None => Err("no session with sender, it didn't finish hello".into()),
+        },
+        (false, _) => Ok(payload),
+    };
+    let data = match opened {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!(
+                "{} failed to decrypt packet, Error: {}",
+                "[WARNING]".yellow(),
+                e
+            );
+            return None;
+        }
+    };
+    if let Some(sender) = sender
+        && is_replayed(sender, iv)
+    {
+        return None;
+    }
+    Some(data)
+}
+
+/// finish PAKE started by joining peer and send it our addresses encrypted with agreed key
+fn answer_pake_query(
+    query: &[u8],
+    src: SocketAddr,
+    network: &RwLock<types::Network>,
+    routing: &types::RoutingTable,
+    socket: &UdpSocket,
+) {
+    let message = match query.get(P2PStandardDataPositions::DATA as usize..) {
+        Some(m) if m.len() == PAKE_MESSAGE_SIZE => m,
+        _ => {
+            eprintln!(
+                "{} query from {} without PAKE, peer has incompatible client",
+                "[WARNING]".yellow(),
+                src
+            );
+            return;
+        }
+    };
+    let mut network_write_lock = network.write().unwrap();
+    // retried query gets same answer, new one would leave peer with other keys than ours
+    let answer: Box<[u8]> = match network_write_lock
+        .pakes
+        .get(&src)
+        .filter(|p| *p.query == *message)
+    {
+        Some(p) => p.answer.clone(),
+        None => {
+            let (state, pake_message) =
+                shared::crypto::pake_start(&routing.key, &routing.net_tag, false);
+            let keys = match shared::crypto::pake_finish(state, message) {
+                Ok(k) => k,
+                Err(e) => {
+                    eprintln!(
+                        "{} failed to answer query from {}, Error: {}",
+                        "[WARNING]".yellow(),
+                        src,
+                        e
+                    );
+                    return;
+                }
+            };
+            let mut iv = [0u8; BLOCK_SIZE];
+            rng().fill_bytes(&mut iv);
+            let addrs = network_write_lock.addrs().to_string();
+            let identity = network_write_lock
+                .identity
+                .as_ref()
+                .expect("identity is set when joining")
+                .public();
+            let mut answer: Vec<u8> = Vec::with_capacity(
+                P2PStandardDataPositions::DATA as usize
+                    + PAKE_MESSAGE_SIZE
+                    + IDENTITY_KEY_SIZE
+                    + addrs.len()
+                    + AEAD_TAG_SIZE,
+            );
+            answer.push(P2PMethods::PEER_QUERY as u8);
+            answer.extend_from_slice(&routing.net_tag);
+            answer.extend_from_slice(&iv);
+            answer.extend_from_slice(&pake_message);
+            // joining peer needs our identity key to start handshake in hello
+            answer.extend_from_slice(&identity.0);
+            answer.extend_from_slice(addrs.as_bytes());
+            let (header, data) = answer.split_at_mut(P2PStandardDataPositions::DATA as usize);
+            match shared::crypto::seal_in_place(
+                routing.cipher,
+                &keys.answer,
+                &iv,
+                header,
+                &mut data[PAKE_MESSAGE_SIZE..],
+            ) {
+                Ok(tag) => answer.extend_from_slice(&tag),
+                Err(e) => {
+                    eprintln!("Failed to encrypt answer to query: {}", e);
+                    return;
+                }
+            }
+            let answer: Box<[u8]> = answer.into();
+            network_write_lock.remember_pake(
+                src,
+                types::Pake {
+                    query: message.into(),
+                    answer: answer.clone(),
+                    psk: keys.psk,
+                    at: std::time::Instant::now(),
+                },
+            );
+            answer
+        }
+    };
+    drop(network_write_lock);
+    if let Err(e) = socket.send_to(&answer, src) {
+        eprintln!("Error sending data: {}", e);
+    }
+}
+
+/// syn from peer announces mss that has to fit into path back to it
+fn clamp_mss_from(routing: &types::RoutingTable, packet: &mut [u8], src: SocketAddr) {
+    if let Some(peer) = routing.peer(&src) {
+        crate::mtu::clamp_mss(packet, routing.mode, peer.mtu);
     }
 }
 
 pub async fn handle_incoming_connection(
-    buf: [u8; UDP_BUFFER_SIZE],
+    buf: &mut [u8],
     src: SocketAddr,
     network: Arc<RwLock<types::Network>>,
-    tun_iface: Arc<tappers::Tun>,
+    routing: &types::RoutingTable,
+    tun_iface: Arc<types::VirtualIface>,
     socket: Arc<std::net::UdpSocket>,
     data_lenght: usize,
 ) {
     #[cfg(debug_assertions)]
     eprintln!("recived method 0x{:02x}", buf[0]);
     match buf[0] {
-        x if x == P2PMethods::PACKET as u8 => {
+        x if x == P2PMethods::PACKET as u8 || x == P2PMethods::PACKET_COMPRESSED as u8 => {
             #[cfg(debug_assertions)]
             println!("PACKET from different peer receved");
 
-            if network.read().unwrap().encrypted {
-                match shared::crypto::decrypt(
-                    &network.read().unwrap().key,
-                    &buf[P2PStandardDataPositions::IV as usize
-                        ..P2PStandardDataPositions::IV as usize + BLOCK_SIZE],
-                    &buf[P2PStandardDataPositions::DATA as usize..data_lenght as usize],
-                ) {
-                    Ok(data) => {
-                        #[cfg(debug_assertions)]
+            let mut decrypted: Vec<u8> = Vec::new();
+            let mut decompressed: Vec<u8>;
+            let mut data: &mut [u8] =
+                match open_packet(routing, &mut buf[..data_lenght], &mut decrypted) {
+                    Some(d) => d,
+                    None => return,
+                };
+            let received = data.len();
+            if x == P2PMethods::PACKET_COMPRESSED as u8 {
+                match shared::compression::decompress(data) {
+                    Ok(d) => {
+                        decompressed = d;
+                        data = &mut decompressed;
+                    }
+                    Err(e) => {
                         eprintln!(
-                            "packet contets: {}\nhash: {:x}",
-                            data.iter()
-                                .map(|x| format!("{:02X} ", x))
-                                .collect::<String>(),
-                            {
-                                let mut hasher = sha2::Sha256::new();
-                                hasher.update(&data);
-                                hasher.finalize()
-                            }
+                            "{} failed to decompress packet, Error: {}",
+                            "[WARNING]".yellow(),
+                            e
                         );
-                        match tun_iface.send(&data) {
-                            Ok(_) => {}
-                            Err(e) => eprintln!(
-                                "{} failed to write packet to tun interface, Error: {}",
-                                "[WARNING]".yellow(),
-                                e
-                            ),
-                        }
+                        return;
                     }
-                    Err(e) => eprintln!(
-                        "{} failed to decrypt packet, Error: {}",
-                        "[WARNING]".yellow(),
-                        e
-                    ),
                 }
-            } else {
-                match tun_iface
-                    .send(&buf[P2PStandardDataPositions::DATA as usize..data_lenght as usize])
+            }
+            if routing.compression != Compression::NONE {
+                routing
+                    .compression_stats
+                    .count_received(data.len(), received);
+            }
+            #[cfg(debug_assertions)]
+            eprintln!(
+                "packet contets: {}\nhash: {:x}",
+                data.iter()
+                    .map(|x| format!("{:02X} ", x))
+                    .collect::<String>(),
                 {
-                    Ok(_) => {}
-                    Err(e) => eprintln!(
-                        "{} failed to write packet to tun interface, Error: {}",
-                        "[WARNING]".yellow(),
-                        e
-                    ),
-                };
+                    let mut hasher = sha2::Sha256::new();
+                    hasher.update(&*data);
+                    hasher.finalize()
+                }
+            );
+
+            learn_mac(&network, routing, data, src);
+            remember_flood(&network, routing, data);
+            clamp_mss_from(routing, data, src);
+            match tun_iface.send(data) {
+                Ok(_) => {}
+                Err(e) => eprintln!(
+                    "{} failed to write packet to tun interface, Error: {}",
+                    "[WARNING]".yellow(),
+                    e
+                ),
             }
         }
         x if x == P2PMethods::PEER_QUERY as u8 => {
+            if routing.sessions {
+                answer_pake_query(&buf[..data_lenght], src, &network, routing, &socket);
+                return;
+            }
             let encrypted = network.read().unwrap().encrypted;
-            let private_ip = network.read().unwrap().private_ip;
-            let private_ip_str = private_ip.to_string();
+            let private_ip_str = network.read().unwrap().addrs().to_string();
             let mut send_buf: Box<[u8]> = if encrypted {
                 vec![
                     0;
@@ -503,6 +1192,9 @@ pub async fn handle_incoming_connection(
             };
 
             send_buf[0] = P2PMethods::PEER_QUERY as u8;
+            send_buf[P2PStandardDataPositions::NET_TAG as usize
+                ..P2PStandardDataPositions::NET_TAG as usize + NET_TAG_SIZE]
+                .copy_from_slice(&network.read().unwrap().net_tag);
             let mut iv = [0u8; BLOCK_SIZE];
             if encrypted {
                 let mut rng = rng();
@@ -547,10 +1239,40 @@ pub async fn handle_incoming_connection(
             }
 
             let tmp_data: Vec<u8>;
+            let hello = &buf[P2PStandardDataPositions::DATA as usize..data_lenght];
+            let mut session: Option<SessionKeys> = None;
+            let mut answer: Vec<u8> = vec![P2PMethods::PEER_HELLO as u8];
             {
                 let mut network_write_lock = network.write().unwrap();
-                let key: [u8; 32] = network_write_lock.key;
+                let key: SecretKey = network_write_lock.key.clone();
                 let encrypted: bool = network_write_lock.encrypted;
+                let sessions: bool = network_write_lock.uses_sessions();
+                // hello is retried when our answer gets lost, answering with new keys would leave peer with old ones
+                if let Some(peer) = network_write_lock.peers.iter().find(|p| {
+                    p.sock_addr == src && !p.hello_answer.is_empty() && *p.hello == *hello
+                }) {
+                    answer.extend_from_slice(&peer.hello_answer);
+                    drop(network_write_lock);
+                    if let Err(e) = socket.send_to(&answer, &src) {
+                        eprintln!("Error sending data: {}", e);
+                    }
+                    return;
+                }
+                // network key isn't used directly, peer proves it knows password by PAKE in query
+                let psk: SecretKey = match sessions {
+                    true => match network_write_lock.take_pake_psk(&src) {
+                        Some(psk) => psk,
+                        None => {
+                            eprintln!(
+                                "{} hello from {} that didn't query us first, ignoring it",
+                                "[WARNING]".yellow(),
+                                src
+                            );
+                            return;
+                        }
+                    },
+                    false => key.clone(),
+                };
                 #[cfg(debug_assertions)]
                 eprintln!(
         "registering network:\niv: {}\nIP: {}",
@@ -561,51 +1283,154 @@ pub async fn handle_incoming_connection(
             .map(|x| format!("{:02X} ", x))
             .collect::<String>(),
     );
-                network_write_lock.peers.push(types::Peer::new(
-                src,
-                Some(
-                    match std::net::Ipv4Addr::from_str(
-                        match std::str::from_utf8(if encrypted {
-                            match shared::crypto::decrypt(&key, &buf[P2PStandardDataPositions::IV as usize
-                        ..P2PStandardDataPositions::IV as usize + BLOCK_SIZE], &buf[P2PStandardDataPositions::DATA as usize..data_lenght as usize]) {
-                                Ok(data) => {tmp_data = data; &tmp_data},
-                                Err(e) => {
-                                eprintln!(
-                                    "{} failed to decrypt ip from peer, ignoring it Error: {}",
-                                    "[WARNING]".yellow(),
-                                    e
-                                );
-                                return;
-                            },
-                            }
-                        } else {
-                            &buf[P2PStandardDataPositions::DATA as usize..data_lenght as usize]
-                        }) {
-                            Ok(s) => s,
-                            Err(e) => {
-                                eprintln!(
-                                    "{} failed to parse ip from peer, ignoring it Error: {}",
-                                    "[WARNING]".yellow(),
-                                    e
-                                );
-                                return;
-                            }
-                        },
+                let mut peer_identity: Option<IdentityKey> = None;
+                let data: &[u8] = if sessions {
+                    let identity = network_write_lock
+                        .identity
+                        .clone()
+                        .expect("identity is set when joining");
+                    let prologue = network_write_lock.handshake_prologue();
+                    let (state, identity_of_peer, payload) = match shared::crypto::handshake_respond(
+                        &psk, &prologue, &identity, hello,
                     ) {
-                        Ok(ip) => ip,
+                        Ok(h) => h,
                         Err(e) => {
                             eprintln!(
-                                "{} failed to parse ip from peer, ignoring it Error: {}",
+                                "{} failed to finish handshake with peer, ignoring it Error: {}",
                                 "[WARNING]".yellow(),
                                 e
                             );
                             return;
                         }
-                    },
-                ),
-            ));
+                    };
+                    if !network_write_lock.admits(&identity_of_peer) {
+                        eprintln!(
+                            "{} refusing hello from {}, its identity {} is not on allowlist",
+                            "[WARNING]".yellow(),
+                            src,
+                            identity_of_peer.fingerprint()
+                        );
+                        drop(network_write_lock);
+                        if let Err(e) =
+                            socket.send_to(&[ServerResponse::NOT_ON_ALLOWLIST as u8], &src)
+                        {
+                            eprintln!("Error sending data: {}", e);
+                        }
+                        return;
+                    }
+                    // peer learns members and update secret from our answer, joining peers have no
+                    // other way to get them
+                    let mut answer_payload =
+                        zeroize::Zeroizing::new(hello_answer_secret(&network_write_lock));
+                    if let Some(list) = &network_write_lock.allowlist {
+                        answer_payload.extend_from_slice(&list.to_bytes());
+                    }
+                    match shared::crypto::handshake_answer(state, &answer_payload) {
+                        Ok((handshake_answer, keys)) => {
+                            answer.extend_from_slice(&handshake_answer);
+                            session = Some(keys);
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "{} failed to finish handshake with peer, ignoring it Error: {}",
+                                "[WARNING]".yellow(),
+                                e
+                            );
+                            return;
+                        }
+                    }
+                    peer_identity = Some(identity_of_peer);
+                    tmp_data = payload;
+                    &tmp_data
+                } else if encrypted {
+                    match shared::crypto::decrypt(
+                        &key,
+                        &buf[P2PStandardDataPositions::IV as usize
+                            ..P2PStandardDataPositions::IV as usize + BLOCK_SIZE],
+                        &buf[P2PStandardDataPositions::DATA as usize..data_lenght],
+                    ) {
+                        Ok(data) => {
+                            // update secret goes back encrypted same way as addresses in hello
+                            let mut iv: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
+                            rng().fill_bytes(&mut iv);
+                            let secret =
+                                zeroize::Zeroizing::new(hello_answer_secret(&network_write_lock));
+                            if let Ok(secret) = shared::crypto::encrypt(&key, &iv, &secret) {
+                                answer.extend_from_slice(&iv);
+                                answer.extend_from_slice(&secret);
+                            }
+                            tmp_data = data;
+                            &tmp_data
+                        }
+                        Err(e) => {
+                            eprintln!(
+                                "{} failed to decrypt ip from peer, ignoring it Error: {}",
+                                "[WARNING]".yellow(),
+                                e
+                            );
+                            return;
+                        }
+                    }
+                } else {
+                    &buf[P2PStandardDataPositions::DATA as usize..data_lenght]
+                };
+                let peer_addrs: types::PeerAddrs = match std::str::from_utf8(data)
+                    .map_err(|e| e.to_string())
+                    .and_then(|s| types::PeerAddrs::from_str(s).map_err(|e| e.to_string()))
+                {
+                    Ok(addrs) => addrs,
+                    Err(e) => {
+                        eprintln!(
+                            "{} failed to parse ip from peer, ignoring it Error: {}",
+                            "[WARNING]".yellow(),
+                            e
+                        );
+                        return;
+                    }
+                };
+                let peer_ip: Ipv4Addr = peer_addrs.ip;
+
+                if peer_ip == network_write_lock.private_ip
+                    || network_write_lock
+                        .peers
+                        .iter()
+                        .any(|p| p.private_ip == peer_ip && p.sock_addr != src)
+                {
+                    eprintln!(
+                        "{} peer: {} wants ip {} that is already in use, refusing it",
+                        "[WARNING]".yellow(),
+                        src,
+                        peer_ip
+                    );
+                    drop(network_write_lock);
+                    match socket.send_to(&[ServerResponse::IP_IN_USE as u8], &src) {
+                        Ok(s) => {
+                            #[cfg(debug_assertions)]
+                            eprintln!("send {} bytes", s);
+                        }
+                        Err(e) => {
+                            eprintln!("Error sending data: {}", e);
+                        }
+                    }
+                    return;
+                }
+                let mut peer = types::Peer::new(src, Some(peer_ip));
+                peer.private_ip6 = peer_addrs.ip6;
+                peer.routes = peer_addrs.routes;
+                peer.exit_node = peer_addrs.exit_node;
+                if let Some(keys) = session {
+                    peer.start_session(keys);
+                    peer.identity = peer_identity;
+                    peer.hello = hello.into();
+                    peer.hello_answer = answer[1..].into();
+                }
+                crate::tun::add_peer_routes(&tun_iface, &network_write_lock, &peer);
+                // hello is retried when our ack gets lost, peer listed twice would get floods twice
+                network_write_lock.peers.retain(|p| p.sock_addr != src);
+                network_write_lock.peers.push(peer);
+                network_write_lock.publish();
             }
-            match socket.send_to(&[P2PMethods::PEER_HELLO as u8], &src) {
+            match socket.send_to(&answer, &src) {
                 Ok(s) => {
                     #[cfg(debug_assertions)]
                     eprintln!("send {} bytes", s);
@@ -618,26 +1443,28 @@ pub async fn handle_incoming_connection(
         x if x == P2PMethods::PEER_GOODBYE as u8 => {
             println!("{} peer goodbye receved from: {}", "[LOG]".blue(), src);
 
-            let mut network_lock = network.write().unwrap();
-
-            let key = network_lock.key;
-            let encrypted: bool = network_lock.encrypted;
-
-            let mut data_tmp: Vec<u8> = Vec::with_capacity(BLOCK_SIZE); // block size
+            if data_lenght < P2PStandardDataPositions::DATA as usize {
+                return; // ack of our goodbye
+            }
+            let mut decrypted: Vec<u8> = Vec::new();
+            let departed: Ipv4Addr =
+                match open_packet(routing, &mut buf[..data_lenght], &mut decrypted)
+                    .map(|d| std::str::from_utf8(d).map(Ipv4Addr::from_str))
+                {
+                    Some(Ok(Ok(ip))) => ip,
+                    Some(_) => {
+                        eprintln!("{} error parsing ip of departed peer", "[ERROR]".red());
+                        return;
+                    }
+                    None => return,
+                };
 
-            network_lock.peers.retain(|peer| !{peer.private_ip == match std::net::Ipv4Addr::from_str(match std::str::from_utf8( if encrypted {
-                match shared::crypto::decrypt(&key, &buf[P2PStandardDataPositions::IV as usize..P2PStandardDataPositions::IV as usize + BLOCK_SIZE], &buf[P2PStandardDataPositions::DATA as usize..data_lenght as usize-1 /*compensate for size and index diference*/]) {
-                    Ok(data) => {data_tmp = data;
-                                             &data_tmp},
-                    Err(e) => {eprintln!("{} error parsing ip, Error: {}", "[ERROR]".red(), e); return false;},
-                }
-            } else {&buf[P2PStandardDataPositions::DATA as usize..data_lenght as usize-1 /*compensate for size and index diference*/]}) {
-                    Ok(s) => s,
-                    Err(e) => {eprintln!("{} error parsing ip, Error: {}", "[ERROR]".red(), e); return false;},
-                }) {
-                    Ok(ip) => ip,
-                    Err(e) => {eprintln!("{} error parsing ip, Error: {}", "[ERROR]".red(), e); return false;},
-                } && peer.sock_addr == src});
+            let mut network_lock = network.write().unwrap();
+            remove_peers(&mut network_lock, &tun_iface, |peer| {
+                !(peer.private_ip == departed && peer.sock_addr == src)
+            });
+            network_lock.publish();
+            drop(network_lock);
             match socket.send_to(&[P2PMethods::PEER_GOODBYE as u8], &src) {
                 Ok(s) => {
                     #[cfg(debug_assertions)]
@@ -648,6 +1475,155 @@ pub async fn handle_incoming_connection(
                 }
             }
         }
+        x if x == P2PMethods::ALLOWLIST as u8 => {
+            let mut decrypted: Vec<u8> = Vec::new();
+            let data = match open_packet(routing, &mut buf[..data_lenght], &mut decrypted) {
+                Some(d) => d,
+                None => return,
+            };
+            let mut network_lock = network.write().unwrap();
+            let owner = match network_lock.owner {
+                Some(o) => o,
+                None => {
+                    eprintln!(
+                        "{} peer: {} sent allowlist but network has no owner, ignoring it",
+                        "[WARNING]".yellow(),
+                        src
+                    );
+                    return;
+                }
+            };
+            let allowlist = match types::Allowlist::from_bytes(data, &owner, &routing.net_tag) {
+                Some(l) => l,
+                None => {
+                    eprintln!(
+                        "{} peer: {} sent allowlist not signed by owner, ignoring it",
+                        "[WARNING]".yellow(),
+                        src
+                    );
+                    return;
+                }
+            };
+            if network_lock
+                .allowlist
+                .as_ref()
+                .is_some_and(|a| a.version >= allowlist.version)
+            {
+                return; // we have it already
+            }
+            println!(
+                "{} allowlist of network {} changed, {} members",
+                "[LOG]".blue(),
+                network_lock.net_id,
+                allowlist.members.len()
+            );
+            if let Some(identity) = &network_lock.identity
+                && !allowlist.allows(&identity.public())
+            {
+                eprintln!(
+                    "{} owner removed us from allowlist of network {}",
+                    "[WARNING]".yellow(),
+                    network_lock.net_id
+                );
+            }
+            remove_peers(&mut network_lock, &tun_iface, |p| {
+                p.identity.is_none_or(|i| allowlist.allows(&i))
+            });
+            network_lock.allowlist = Some(allowlist);
+            network_lock.publish();
+        }
+        x if x == P2PMethods::NETWORK_KEY as u8 => {
+            let mut decrypted: Vec<u8> = Vec::new();
+            let data = match open_packet(routing, &mut buf[..data_lenght], &mut decrypted) {
+                Some(d) if d.len() == NetworkKeyPositions::END as usize => d,
+                Some(d) => {
+                    d.zeroize();
+                    eprintln!(
+                        "{} peer: {} sent network key of wrong size, ignoring it",
+                        "[WARNING]".yellow(),
+                        src
+                    );
+                    return;
+                }
+                None => return,
+            };
+            let salt: [u8; BLOCK_SIZE] = data
+                [NetworkKeyPositions::SALT as usize..NetworkKeyPositions::KEY as usize]
+                .try_into()
+                .unwrap();
+            let key = SecretKey::new(
+                data[NetworkKeyPositions::KEY as usize
+                    ..NetworkKeyPositions::UPDATE_SECRET as usize]
+                    .try_into()
+                    .unwrap(),
+            );
+            let update_secret = SecretKey::new(
+                data[NetworkKeyPositions::UPDATE_SECRET as usize
+                    ..NetworkKeyPositions::END as usize]
+                    .try_into()
+                    .unwrap(),
+            );
+            data.zeroize();
+            decrypted.zeroize();
+
+            let mut network_lock = network.write().unwrap();
+            if salt == network_lock.salt {
+                return; // we have it already
+            }
+            // only owner can change password of network with allowlist
+            if let Some(owner) = network_lock.owner
+                && network_lock
+                    .peers
+                    .iter()
+                    .find(|p| p.sock_addr == src)
+                    .and_then(|p| p.identity)
+                    != Some(owner)
+            {
+                eprintln!(
+                    "{} peer: {} sent network key but it isn't owner of network, ignoring it",
+                    "[WARNING]".yellow(),
+                    src
+                );
+                return;
+            }
+            let (request, registration) = match update_request(&network_lock, &key, &salt, None) {
+                Some(u) => u,
+                None => {
+                    eprintln!(
+                        "{} failed to update registration of network {} with new key",
+                        "[ERROR]".red(),
+                        network_lock.net_id
+                    );
+                    return;
+                }
+            };
+            // registrar keeps sock addr encrypted with old key until it gets this, heartbeat
+            // with new one would add us again, listener has to be free to get confirmation
+            if let Some(registrar) = network_lock.registrar {
+                let network = network.clone();
+                std::thread::spawn(move || {
+                    match send_update(&socket, &network, registrar, &request) {
+                        Ok(true) => {}
+                        Ok(false) => eprintln!(
+                            "{} registrar didn't confirm registration update, new peers may not reach us until heartbeat",
+                            "[WARNING]".yellow()
+                        ),
+                        Err(e) => eprintln!(
+                            "{} failed to send registration update to registrar, Error: {}",
+                            "[WARNING]".yellow(),
+                            e
+                        ),
+                    }
+                });
+            }
+            switch_key(&mut network_lock, key, salt, update_secret, registration);
+            println!(
+                "{} password of network {} was changed by peer: {}",
+                "[LOG]".blue(),
+                network_lock.net_id,
+                src
+            );
+        }
         x if x == P2PMethods::NEW_CLIENT_NOTIFY as u8 => {
             println!(
                 "{} Notified about new client, creating NAT mapping",
@@ -667,7 +1643,7 @@ pub async fn handle_incoming_connection(
 
             let data_tmp: Box<[u8]>;
             let peer_addr: std::net::SocketAddr = match std::net::SocketAddr::from_str(
-                match std::str::from_utf8(if network.read().unwrap().encrypted {
+                match std::str::from_utf8(if network.read().unwrap().encrypts_sock_addrs() {
                     match shared::crypto::decrypt(
                         &network.read().unwrap().key,
                         &buf[P2PStandardDataPositions::IV as usize
@@ -721,6 +1697,37 @@ pub async fn handle_incoming_connection(
                 }
             }
         }
+        x if x == P2PMethods::PMTU_PROBE as u8 => {
+            // size we really got, not what probe claims
+            let size = (data_lenght + IPV4_HEADER_SIZE + UDP_HEADER_SIZE) as u16;
+            buf[0] = P2PMethods::PMTU_PROBE_ACK as u8;
+            buf[PmtuProbeDataPositions::SIZE as usize..PmtuProbeDataPositions::END as usize]
+                .copy_from_slice(&size.to_be_bytes());
+            if let Err(e) = socket.send_to(&buf[..PmtuProbeDataPositions::END as usize], src) {
+                eprintln!("Error sending data: {}", e);
+            }
+        }
+        x if x == P2PMethods::PMTU_PROBE_ACK as u8 => {
+            let size = u16::from_be_bytes([
+                buf[PmtuProbeDataPositions::SIZE as usize],
+                buf[PmtuProbeDataPositions::SIZE as usize + 1],
+            ]) as usize;
+            #[cfg(debug_assertions)]
+            eprintln!("probe of {} bytes got to {}", size, src);
+            // ack isn't authenticated, only sizes we just probed that peer are taken
+            let Some(probe) = PMTU_PROBE_SIZES.iter().position(|&s| s == size) else {
+                return;
+            };
+            if let Some(peer) = network
+                .write()
+                .unwrap()
+                .peers
+                .iter_mut()
+                .find(|p| p.sock_addr == src && p.pmtu_probed & 1 << probe != 0)
+            {
+                peer.pmtu_acked = peer.pmtu_acked.max(size.min(UNDERLAY_MTU));
+            }
+        }
         x if x == P2PMethods::DO_NOTHING as u8 => {
             println!(
                 "{} punching succesful DO_NOTHING receved",
@@ -729,6 +1736,11 @@ pub async fn handle_incoming_connection(
         }
         x if x == ServerMethods::HEARTBEAT as u8 => {
             println!("{} heart beat recive confirmed", "[OK]".green());
+            network.write().unwrap().last_heartbeat_ack = Some(chrono::Utc::now().timestamp());
+        }
+        x if x == ServerMethods::UPDATE as u8 => {
+            println!("{} registration update confirmed", "[OK]".green());
+            network.write().unwrap().last_update_ack = Some(chrono::Utc::now().timestamp());
         }
         _ => {
             eprintln!(
@@ -740,13 +1752,21 @@ pub async fn handle_incoming_connection(
     }
 }
 
-pub fn periodic_heart_beat(socket: Arc<UdpSocket>, send_buf: Box<[u8]>, dst: SocketAddr) {
+pub fn periodic_heart_beat(
+    socket: Arc<UdpSocket>,
+    heartbeat: Arc<arc_swap::ArcSwap<Box<[u8]>>>,
+    dst: SocketAddr,
+    left: Arc<AtomicBool>,
+) {
     println!("{} periodic heartbeat started", "[LOG]".blue());
     loop {
         std::thread::sleep(std::time::Duration::from_secs(30));
+        if left.load(Ordering::Relaxed) {
+            return;
+        }
         println!("{} sending heartbeat to server", "[LOG]".blue());
 
-        match socket.send_to(&send_buf, dst) {
+        match socket.send_to(&heartbeat.load(), dst) {
             Ok(size) => {
                 #[cfg(debug_assertions)]
                 println!("send {} bytes", size);
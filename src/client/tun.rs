@@ -1,77 +1,537 @@
-use pea_2_pea::*;
+use arc_swap::ArcSwap;
+use colored::Colorize;
+use pea_2_pea::{shared::subnet::Subnet, *};
 use rand::RngCore;
-use rayon::prelude::*;
 use sha2::Digest;
-use std::sync::{Arc, RwLock};
+use std::{
+    hash::{Hash, Hasher},
+    sync::{Arc, RwLock, atomic::Ordering},
+};
 use tappers::Interface;
 
-use crate::types::Network;
+use crate::types::{JoinedNetwork, Network, Peer, PeerRoute, RoutingTable, VirtualIface};
 
 pub fn create_tun_interface(
     private_ip: std::net::Ipv4Addr,
+    private_ip6: std::net::Ipv6Addr,
+    subnet: &Subnet,
     if_name: Option<String>,
-) -> Result<tappers::Tun, std::io::Error> {
-    #[cfg(not(target_os = "windows"))]
-    let mut tun_iface: tappers::Tun = tappers::Tun::new_named(Interface::new(
-        &if_name.unwrap_or(DEFAULT_INTERFACE_NAME.to_owned()),
-    )?)?;
-    #[cfg(target_os = "windows")]
-    let mut tun_iface: tappers::Tun = tappers::Tun::new()?;
+    mode: NetworkMode,
+) -> Result<VirtualIface, std::io::Error> {
     #[cfg(not(target_os = "windows"))]
     {
+        let if_name = Interface::new(&if_name.unwrap_or(DEFAULT_INTERFACE_NAME.to_owned()))?;
         let mut addr_req = tappers::AddAddressV4::new(private_ip);
-        addr_req.set_netmask(24);
-        let mut broadcast_addr_oct = private_ip.octets();
-        broadcast_addr_oct[3] = 255;
-        addr_req.set_broadcast(std::net::Ipv4Addr::from(broadcast_addr_oct));
-        tun_iface.add_addr(addr_req)?;
+        addr_req.set_netmask(subnet.prefix_len());
+        addr_req.set_broadcast(subnet.broadcast());
+        let mut addr6_req = tappers::AddAddressV6::new(private_ip6);
+        addr6_req.set_netmask(ULA_PREFIX_LEN);
+        match mode {
+            NetworkMode::TUN => {
+                let mut tun_iface: tappers::Tun = tappers::Tun::new_named(if_name)?;
+                tun_iface.add_addr(addr_req)?;
+                tun_iface.add_addr(addr6_req)?;
+                tun_iface.set_up()?;
+                Ok(VirtualIface::Tun(tun_iface))
+            }
+            NetworkMode::TAP => {
+                let mut tap_iface: tappers::Tap = tappers::Tap::new_named(if_name)?;
+                tap_iface.add_addr(addr_req)?;
+                tap_iface.add_addr(addr6_req)?;
+                tap_iface.set_up()?;
+                Ok(VirtualIface::Tap(tap_iface))
+            }
+        }
     }
     #[cfg(target_os = "windows")]
-    std::process::Command::new("netsh").args([
-        "interface",
-        "ipv4",
-        "set",
-        "address",
-        &format!(
-            "name=\"{}\"",
-            tun_iface.name()?.name().into_string().unwrap()
-        ),
-        "static",
-        &private_ip.to_string(),
-        "255.255.255.0",
-    ]);
+    {
+        let _ = if_name;
+        if mode == NetworkMode::TAP {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "tap mode is not supported on windows",
+            ));
+        }
+        let mut tun_iface: tappers::Tun = tappers::Tun::new()?;
+        run_command(
+            "netsh",
+            &[
+                "interface",
+                "ipv4",
+                "set",
+                "address",
+                &format!(
+                    "name=\"{}\"",
+                    tun_iface.name()?.name().into_string().unwrap()
+                ),
+                "static",
+                &private_ip.to_string(),
+                &subnet.netmask().to_string(),
+            ],
+        )?;
+        run_command(
+            "netsh",
+            &[
+                "interface",
+                "ipv6",
+                "add",
+                "address",
+                &format!(
+                    "interface=\"{}\"",
+                    tun_iface.name()?.name().into_string().unwrap()
+                ),
+                &format!("{}/{}", private_ip6, ULA_PREFIX_LEN),
+            ],
+        )?;
+
+        tun_iface.set_up()?;
+        return Ok(VirtualIface::Tun(tun_iface));
+    }
+}
 
-    tun_iface.set_up()?;
-    return Ok(tun_iface);
+fn run_command(program: &str, args: &[&str]) -> std::io::Result<()> {
+    let status = std::process::Command::new(program).args(args).status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(std::io::Error::other(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            status
+        ))),
+    }
 }
 
-pub fn read_tun_iface(
-    tun_iface: Arc<tappers::Tun>,
-    socket: Arc<std::net::UdpSocket>,
-    network: Arc<RwLock<Network>>,
-) {
+/// packets bigger than this would not fit into one datagram with our headers
+pub fn set_mtu(tun_iface: &VirtualIface, mtu: usize) -> std::io::Result<()> {
+    let if_name = tun_iface.name()?;
+    #[cfg(target_os = "linux")]
+    return run_command(
+        "ip",
+        &["link", "set", "dev", &if_name, "mtu", &mtu.to_string()],
+    );
+    #[cfg(target_os = "windows")]
+    return run_command(
+        "netsh",
+        &[
+            "interface",
+            "ipv4",
+            "set",
+            "subinterface",
+            &format!("\"{}\"", if_name),
+            &format!("mtu={}", mtu),
+            "store=active",
+        ],
+    );
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    return run_command("ifconfig", &[&if_name, "mtu", &mtu.to_string()]);
+}
+
+/// route subnet advertised by peer through our interface
+pub fn add_route(tun_iface: &VirtualIface, route: &Subnet) -> std::io::Result<()> {
+    let if_name = tun_iface.name()?;
+    #[cfg(target_os = "linux")]
+    return run_command(
+        "ip",
+        &["route", "replace", &route.to_string(), "dev", &if_name],
+    );
+    #[cfg(target_os = "windows")]
+    return run_command(
+        "netsh",
+        &[
+            "interface",
+            "ipv4",
+            "add",
+            "route",
+            &route.to_string(),
+            &format!("interface=\"{}\"", if_name),
+        ],
+    );
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    return run_command(
+        "route",
+        &[
+            "-n",
+            "add",
+            "-net",
+            &route.to_string(),
+            "-interface",
+            &if_name,
+        ],
+    );
+}
+
+pub fn remove_route(tun_iface: &VirtualIface, route: &Subnet) -> std::io::Result<()> {
+    let if_name = tun_iface.name()?;
+    #[cfg(target_os = "linux")]
+    return run_command("ip", &["route", "del", &route.to_string(), "dev", &if_name]);
+    #[cfg(target_os = "windows")]
+    return run_command(
+        "netsh",
+        &[
+            "interface",
+            "ipv4",
+            "delete",
+            "route",
+            &route.to_string(),
+            &format!("interface=\"{}\"", if_name),
+        ],
+    );
+    #[cfg(not(any(target_os = "linux", target_os = "windows")))]
+    return run_command(
+        "route",
+        &[
+            "-n",
+            "delete",
+            "-net",
+            &route.to_string(),
+            "-interface",
+            &if_name,
+        ],
+    );
+}
+
+/// install routes of peer, peer must not take over our virtual subnet
+pub fn add_peer_routes(tun_iface: &VirtualIface, network: &Network, peer: &Peer) {
+    for route in peer.routes.iter() {
+        if route.overlaps(&network.subnet) {
+            eprintln!(
+                "{} peer: {} advertises route {} overlapping virtual subnet, ignoring it",
+                "[WARNING]".yellow(),
+                peer.sock_addr,
+                route
+            );
+            continue;
+        }
+        match add_route(tun_iface, route) {
+            Ok(_) => println!(
+                "{} route {} via peer: {}",
+                "[LOG]".blue(),
+                route,
+                peer.sock_addr
+            ),
+            Err(e) => eprintln!(
+                "{} failed to add route {}, Error: {}",
+                "[WARNING]".yellow(),
+                route,
+                e
+            ),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn exit_node_rules(op: &str, if_name: &str, subnet: &Subnet) -> [Vec<String>; 3] {
+    let subnet = subnet.to_string();
+    [
+        vec![
+            "-t",
+            "nat",
+            op,
+            "POSTROUTING",
+            "-s",
+            &subnet,
+            "!",
+            "-d",
+            &subnet,
+            "-j",
+            "MASQUERADE",
+        ],
+        vec![op, "FORWARD", "-i", if_name, "-j", "ACCEPT"],
+        vec![
+            op,
+            "FORWARD",
+            "-o",
+            if_name,
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "RELATED,ESTABLISHED",
+            "-j",
+            "ACCEPT",
+        ],
+    ]
+    .map(|rule| rule.iter().map(|a| a.to_string()).collect())
+}
+
+/// forward traffic of peers to internet masquerading it behind our address
+pub fn enable_exit_node(if_name: &str, subnet: &Subnet) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        std::fs::write("/proc/sys/net/ipv4/ip_forward", "1")?;
+        for rule in exit_node_rules("-A", if_name, subnet) {
+            run_command(
+                "iptables",
+                &rule.iter().map(String::as_str).collect::<Vec<&str>>(),
+            )?;
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (if_name, subnet);
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "exit node is supported only on linux",
+        ))
+    }
+}
+
+pub fn disable_exit_node(if_name: &str, subnet: &Subnet) {
+    #[cfg(target_os = "linux")]
+    for rule in exit_node_rules("-D", if_name, subnet) {
+        if let Err(e) = run_command(
+            "iptables",
+            &rule.iter().map(String::as_str).collect::<Vec<&str>>(),
+        ) {
+            eprintln!(
+                "{} failed to remove exit node rule, Error: {}",
+                "[WARNING]".yellow(),
+                e
+            );
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = (if_name, subnet);
+}
+
+/// packets of our own sockets must not be routed into tunnel when exit node is used,
+/// every network has its own socket and exit node can be used by any of them
+pub fn mark_underlay_socket(socket: &std::net::UdpSocket) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    match unsafe {
+        libc::setsockopt(
+            std::os::fd::AsRawFd::as_raw_fd(socket),
+            libc::SOL_SOCKET,
+            libc::SO_MARK,
+            &EXIT_ROUTE_TABLE as *const u32 as *const libc::c_void,
+            std::mem::size_of::<u32>() as libc::socklen_t,
+        )
+    } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = socket;
+        Ok(())
+    }
+}
+
+/// route selected subnets or everything except peers through exit node
+pub fn use_exit_node(tun_iface: &VirtualIface, exit_routes: &[Subnet]) -> std::io::Result<()> {
+    if !exit_routes.is_empty() {
+        for route in exit_routes {
+            add_route(tun_iface, route)?;
+        }
+        return Ok(());
+    }
+    #[cfg(target_os = "linux")]
+    {
+        // same policy routing as wg-quick, our sockets are marked so they keep using main table
+        let table = EXIT_ROUTE_TABLE.to_string();
+        run_command(
+            "ip",
+            &[
+                "route",
+                "replace",
+                "default",
+                "dev",
+                &tun_iface.name()?,
+                "table",
+                &table,
+            ],
+        )?;
+        run_command(
+            "ip",
+            &["rule", "add", "not", "fwmark", &table, "table", &table],
+        )?;
+        run_command(
+            "ip",
+            &["rule", "add", "table", "main", "suppress_prefixlength", "0"],
+        )?;
+        Ok(())
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "default route through exit node is supported only on linux, use --exit-route",
+        ))
+    }
+}
+
+/// routes disappear together with interface, only rules have to be removed
+pub fn stop_using_exit_node(exit_routes: &[Subnet]) {
+    if !exit_routes.is_empty() {
+        return;
+    }
+    #[cfg(target_os = "linux")]
+    {
+        let table = EXIT_ROUTE_TABLE.to_string();
+        for rule in [
+            vec!["rule", "del", "not", "fwmark", &table, "table", &table],
+            vec!["rule", "del", "table", "main", "suppress_prefixlength", "0"],
+        ] {
+            if let Err(e) = run_command("ip", &rule) {
+                eprintln!(
+                    "{} failed to remove exit node routing rule, Error: {}",
+                    "[WARNING]".yellow(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// wait until tun has packet to read, false on timeout
+#[cfg(unix)]
+fn wait_readable(tun_iface: &VirtualIface, timeout_ms: i32) -> bool {
+    let mut pfd = libc::pollfd {
+        fd: std::os::fd::AsRawFd::as_raw_fd(tun_iface),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut pfd, 1, timeout_ms) > 0 }
+}
+
+pub fn read_tun_iface(joined: Arc<JoinedNetwork>) {
+    #[cfg(target_os = "linux")]
+    match read_tun_iface_batched(&joined) {
+        Ok(_) => return, // network was left
+        Err(e) => eprintln!(
+            "{} batched sending not available, falling back to one packet per syscall, Error: {}",
+            "[WARNING]".yellow(),
+            e
+        ),
+    }
+
     let mut buf: [u8; IP_BUFFER_SIZE] = [0u8; IP_BUFFER_SIZE];
 
     smol::block_on(async {
         #[cfg(debug_assertions)]
         eprintln!("Started listening for ip packets");
-        loop {
-            let data_lenght = tun_iface.recv(&mut buf).unwrap(); // build in auto termination, isn't it great
+        while !joined.left.load(Ordering::Relaxed) {
+            #[cfg(unix)]
+            if !wait_readable(&joined.tun_iface, LEAVE_POLL_INTERVAL_MS) {
+                continue;
+            }
+            let data_lenght = joined.tun_iface.recv(&mut buf).unwrap(); // build in auto termination, isn't it great
             smol::spawn(handle_ip_packet(
                 buf[..data_lenght].to_vec().into(),
-                network.clone(),
-                socket.clone(),
+                joined.network.clone(),
+                joined.routing.clone(),
+                joined.tun_iface.clone(),
+                joined.socket.clone(),
             ))
             .detach();
         }
     });
 }
 
-pub async fn handle_ip_packet(
-    packet_data: Box<[u8]>,
-    network: Arc<RwLock<Network>>,
-    socket: Arc<std::net::UdpSocket>,
-) {
+/// drain packets waiting in tun and send all of them with one syscall
+#[cfg(target_os = "linux")]
+fn read_tun_iface_batched(joined: &JoinedNetwork) -> std::io::Result<()> {
+    crate::batch::set_nonblocking(std::os::fd::AsRawFd::as_raw_fd(&*joined.tun_iface))?;
+    let mut buf: [u8; IP_BUFFER_SIZE] = [0u8; IP_BUFFER_SIZE];
+    let mut datagrams: Vec<(std::net::SocketAddr, Vec<u8>)> = Vec::with_capacity(UDP_BATCH_SIZE);
+    let mut gso = true;
+
+    #[cfg(debug_assertions)]
+    eprintln!("Started listening for ip packets");
+    while !joined.left.load(Ordering::Relaxed) {
+        if !wait_readable(&joined.tun_iface, LEAVE_POLL_INTERVAL_MS) {
+            continue;
+        }
+        datagrams.clear();
+        for _ in 0..UDP_BATCH_SIZE {
+            let data_lenght = match joined.tun_iface.recv(&mut buf) {
+                Ok(l) => l,
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            };
+            let routing = joined.routing.load();
+            match prepare_ip_packet(
+                &mut buf[..data_lenght],
+                &joined.network,
+                &routing,
+                &joined.tun_iface,
+            ) {
+                Some(d) => datagrams.extend(d),
+                None => {}
+            }
+        }
+        if let Err(e) = crate::batch::send_batch(&joined.socket, &datagrams, &mut gso) {
+            eprintln!("failed to send packet: {}", e);
+        }
+    }
+    Ok(())
+}
+
+/// true if packet goes to all peers, None if packet can't be routed at all
+pub fn is_flooded(packet_data: &[u8], routing: &RoutingTable) -> Option<bool> {
+    match routing.mode {
+        NetworkMode::TUN => match packet_data.first().map(|b| b >> 4) {
+            Some(4) if packet_data.len() >= DEST_IN_IPV4_OFFSET + IPV4_SIZE => {
+                let dst_ip = std::net::Ipv4Addr::from(
+                    <[u8; IPV4_SIZE]>::try_from(
+                        &packet_data[DEST_IN_IPV4_OFFSET..DEST_IN_IPV4_OFFSET + IPV4_SIZE],
+                    )
+                    .unwrap(),
+                );
+                // directed broadcast of our subnet, limited broadcast and multicast used by game discovery
+                Some(
+                    dst_ip == routing.subnet.broadcast()
+                        || dst_ip.is_broadcast()
+                        || dst_ip.is_multicast(),
+                )
+            }
+            Some(6) if packet_data.len() >= DEST_IN_IPV6_OFFSET + IPV6_SIZE => {
+                let dst_ip6 = std::net::Ipv6Addr::from(
+                    <[u8; IPV6_SIZE]>::try_from(
+                        &packet_data[DEST_IN_IPV6_OFFSET..DEST_IN_IPV6_OFFSET + IPV6_SIZE],
+                    )
+                    .unwrap(),
+                );
+                // neighbor discovery and link local addresses we don't know
+                Some(dst_ip6.is_multicast() || dst_ip6.is_unicast_link_local())
+            }
+            _ => None,
+        },
+        NetworkMode::TAP => match packet_data.len() >= ETHERNET_HEADER_SIZE {
+            true => Some(packet_data[0] & 1 == 1), // broadcast and multicast have group bit set
+            false => None,
+        },
+    }
+}
+
+/// identifies flooded packet, fields changed by routers are left out so packet is recognized when it comes back
+pub fn flood_key(packet_data: &[u8], mode: NetworkMode) -> u64 {
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    match (mode, packet_data.first().map(|b| b >> 4)) {
+        (NetworkMode::TUN, Some(4)) if packet_data.len() >= TTL_IN_IPV4_OFFSET + 4 => {
+            // ttl and checksum are skiped
+            packet_data[..TTL_IN_IPV4_OFFSET].hash(&mut hasher);
+            packet_data[TTL_IN_IPV4_OFFSET + 1].hash(&mut hasher);
+            packet_data[TTL_IN_IPV4_OFFSET + 4..].hash(&mut hasher);
+        }
+        (NetworkMode::TUN, Some(6)) if packet_data.len() > HOP_LIMIT_IN_IPV6_OFFSET => {
+            packet_data[..HOP_LIMIT_IN_IPV6_OFFSET].hash(&mut hasher);
+            packet_data[HOP_LIMIT_IN_IPV6_OFFSET + 1..].hash(&mut hasher);
+        }
+        _ => packet_data.hash(&mut hasher),
+    }
+    hasher.finish()
+}
+
+/// route and encrypt packet from tun, returns datagram and peer it goes to, None peer means all of them
+/// network is locked only for flooded packets
+pub fn prepare_ip_packet(
+    packet_data: &mut [u8],
+    network: &RwLock<Network>,
+    routing: &RoutingTable,
+    tun_iface: &VirtualIface,
+) -> Option<Vec<(std::net::SocketAddr, Vec<u8>)>> {
     #[cfg(debug_assertions)]
     eprintln!("Processing IP packet");
 
@@ -84,66 +544,212 @@ pub async fn handle_ip_packet(
             .collect::<String>(),
         {
             let mut hasher = sha2::Sha256::new();
-            hasher.update(&packet_data);
+            hasher.update(&*packet_data);
             hasher.finalize()
         }
     );
 
-    let dst_ip = std::net::Ipv4Addr::from(
-        match <[u8; 4]>::try_from(
-            &packet_data[DEST_IN_IPV4_OFFSET..DEST_IN_IPV4_OFFSET + IPV4_SIZE],
-        ) {
-            Ok(slice) => slice,
-            Err(e) => {
-                eprintln!("Procesing of IP packet failed, Invalid dst IP: {}", e);
-                return;
+    let flooded = is_flooded(packet_data, routing);
+    let mode = routing.mode;
+    // None means packet goes to all peers
+    let dst: Option<&crate::types::PeerRoute> = match flooded {
+        Some(true) => {
+            if network
+                .read()
+                .unwrap()
+                .is_recent_flood(flood_key(packet_data, mode))
+            {
+                #[cfg(debug_assertions)]
+                eprintln!("broadcast came back from peer, not flooding it again");
+                return None;
+            }
+            None
+        }
+        Some(false) => match mode {
+            NetworkMode::TUN => {
+                let found = if packet_data[0] >> 4 == 4 {
+                    routing.lookup(std::net::Ipv4Addr::from(
+                        <[u8; IPV4_SIZE]>::try_from(
+                            &packet_data[DEST_IN_IPV4_OFFSET..DEST_IN_IPV4_OFFSET + IPV4_SIZE],
+                        )
+                        .unwrap(),
+                    ))
+                } else {
+                    routing.lookup6(std::net::Ipv6Addr::from(
+                        <[u8; IPV6_SIZE]>::try_from(
+                            &packet_data[DEST_IN_IPV6_OFFSET..DEST_IN_IPV6_OFFSET + IPV6_SIZE],
+                        )
+                        .unwrap(),
+                    ))
+                };
+                match found {
+                    Some(p) => Some(p),
+                    None => return None,
+                }
+            }
+            NetworkMode::TAP => {
+                let dst_mac: [u8; MAC_SIZE] = packet_data[..MAC_SIZE].try_into().unwrap();
+                // unknown unicast is flooded
+                routing
+                    .mac_table
+                    .get(&dst_mac)
+                    .and_then(|sock_addr| routing.peer(sock_addr))
             }
         },
-    );
+        None => {
+            eprintln!("Procesing of packet failed, unknown version or packet too short");
+            return None;
+        }
+    };
+    if let Some(peer) = dst {
+        if let Some(reply) = crate::mtu::frag_needed(packet_data, mode, peer.mtu, peer.private_ip) {
+            #[cfg(debug_assertions)]
+            eprintln!(
+                "packet doesn't fit into mtu {} of path to {}",
+                peer.mtu, peer.sock_addr
+            );
+            if let Err(e) = tun_iface.send(&reply) {
+                eprintln!(
+                    "{} failed to write packet to tun interface, Error: {}",
+                    "[WARNING]".yellow(),
+                    e
+                );
+            }
+            return None;
+        }
+        crate::mtu::clamp_mss(packet_data, mode, peer.mtu);
+    }
+
+    // incompressible packets go as they are, method tells peer which one it got
+    let compressed: Option<Vec<u8>> = match routing.compression {
+        Compression::LZ4 => shared::compression::compress(packet_data),
+        Compression::NONE => None,
+    };
+    let (method, payload): (P2PMethods, &[u8]) = match compressed {
+        Some(ref c) => (P2PMethods::PACKET_COMPRESSED, c),
+        None => (P2PMethods::PACKET, packet_data),
+    };
+    if routing.compression != Compression::NONE {
+        routing
+            .compression_stats
+            .count_sent(packet_data.len(), payload.len());
+    }
+
+    let method = method as u8;
+    match dst {
+        Some(peer) => {
+            seal_packet(routing, method, payload, Some(peer)).map(|d| vec![(peer.sock_addr, d)])
+        }
+        // every peer has its own key, so flood is encrypted for each of them
+        None if routing.sessions => Some(
+            routing
+                .peers
+                .iter()
+                .filter_map(|p| {
+                    seal_packet(routing, method, payload, Some(p)).map(|d| (p.sock_addr, d))
+                })
+                .collect(),
+        ),
+        None => seal_packet(routing, method, payload, None).map(|d| {
+            routing
+                .peers
+                .iter()
+                .map(|p| (p.sock_addr, d.clone()))
+                .collect()
+        }),
+    }
+}
+
+/// header and encrypted payload, peer is needed when network uses session keys
+pub fn seal_packet(
+    routing: &RoutingTable,
+    method: u8,
+    payload: &[u8],
+    peer: Option<&PeerRoute>,
+) -> Option<Vec<u8>> {
     let mut rng = rand::rng();
 
     let mut iv: [u8; BLOCK_SIZE] = [0u8; BLOCK_SIZE];
     rng.fill_bytes(&mut iv);
 
-    let mut procesed_data: Vec<u8> = if network.read().unwrap().encrypted {
-        match shared::crypto::encrypt(&network.read().unwrap().key, &iv, &packet_data) {
-            Ok(cr) => cr,
-            Err(e) => {
-                eprintln!("Failed to encrypt packet droping it: {}", e);
-                return;
+    // session key is taken before header is built, its epoch goes into IV
+    let mut session_key: Option<shared::crypto::SecretKey> = None;
+    if routing.sessions {
+        let (peer, session) = match peer.and_then(|p| Some((p, p.session.as_ref()?))) {
+            Some(p) => p,
+            None => {
+                #[cfg(debug_assertions)]
+                eprintln!("no session with peer, droping packet");
+                return None;
             }
-        }
-    } else {
-        packet_data.to_vec()
-    };
+        };
+        let (key, epoch) = session.lock().unwrap().send_key(payload.len());
+        iv[PacketIvPositions::SENDER as usize..PacketIvPositions::COUNTER as usize]
+            .copy_from_slice(&routing.sender);
+        iv[PacketIvPositions::COUNTER as usize..PacketIvPositions::EPOCH as usize].copy_from_slice(
+            &peer
+                .packet_counter
+                .fetch_add(1, Ordering::Relaxed)
+                .to_be_bytes(),
+        );
+        iv[PacketIvPositions::EPOCH as usize..PacketIvPositions::END as usize]
+            .copy_from_slice(&epoch.to_be_bytes());
+        session_key = Some(key);
+    }
 
-    procesed_data.insert(0, P2PMethods::PACKET as u8);
-    procesed_data.splice(1..1, iv);
+    // header first so data isn't moved around to make room for it
+    let mut procesed_data: Vec<u8> =
+        Vec::with_capacity(P2PStandardDataPositions::DATA as usize + payload.len() + BLOCK_SIZE);
+    procesed_data.push(method);
+    procesed_data.extend_from_slice(&routing.net_tag);
+    procesed_data.extend_from_slice(&iv);
+    match (routing.encrypted, routing.cipher) {
+        (true, CipherSuite::AES_256_CBC) => {
+            match shared::crypto::encrypt(&routing.key, &iv, payload) {
+                Ok(cr) => procesed_data.extend_from_slice(&cr),
+                Err(e) => {
+                    eprintln!("Failed to encrypt packet droping it: {}", e);
+                    return None;
+                }
+            }
+        }
+        (true, suite) => {
+            // aead networks always use sessions, so key was taken above
+            let key = session_key?;
+            // whole header is authenticated so packet can't be moved to other network or method
+            procesed_data.extend_from_slice(payload);
+            let (header, data) =
+                procesed_data.split_at_mut(P2PStandardDataPositions::DATA as usize);
+            match shared::crypto::seal_in_place(suite, &key, &iv, header, data) {
+                Ok(tag) => procesed_data.extend_from_slice(&tag),
+                Err(e) => {
+                    eprintln!("Failed to encrypt packet droping it: {}", e);
+                    return None;
+                }
+            }
+        }
+        (false, _) => procesed_data.extend_from_slice(payload),
+    }
+    Some(procesed_data)
+}
 
-    if dst_ip.octets()[3] == 255 {
-        network.read().unwrap().peers.par_iter().for_each(|peer| {
-            // broadcast
-            match socket.send_to(&procesed_data, peer.sock_addr) {
-                Ok(_) => {}
-                Err(e) => eprintln!("failed to send packet: {}", e),
-            };
-        });
-    } else {
-        let dst = match network
-            .read()
-            .unwrap()
-            .peers
-            .par_iter()
-            .find_any(|&p| p.private_ip == dst_ip)
-            .map(|p| p.sock_addr)
-        {
-            Some(sa) => sa,
-            None => return,
-        };
+pub async fn handle_ip_packet(
+    mut packet_data: Box<[u8]>,
+    network: Arc<RwLock<Network>>,
+    routing: Arc<ArcSwap<RoutingTable>>,
+    tun_iface: Arc<VirtualIface>,
+    socket: Arc<std::net::UdpSocket>,
+) {
+    let routing = routing.load();
+    let datagrams = match prepare_ip_packet(&mut packet_data, &network, &routing, &tun_iface) {
+        Some(d) => d,
+        None => return,
+    };
 
+    for (dst, procesed_data) in datagrams {
         match socket.send_to(&procesed_data, dst) {
             Ok(_) => {}
             Err(e) => eprintln!("failed to send packet: {}", e),
-        };
+        }
     }
 }
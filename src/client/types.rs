@@ -1,10 +1,41 @@
-use pea_2_pea::*;
+use arc_swap::ArcSwap;
+use pea_2_pea::{
+    shared::{
+        crypto::{Identity, IdentityKey, KdfParams, SecretKey, SessionKeys},
+        subnet::Subnet,
+    },
+    *,
+};
+use sha2::Digest;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+};
 
 #[readonly::make]
 pub struct Peer {
     #[readonly]
     pub sock_addr: std::net::SocketAddr,
     pub private_ip: std::net::Ipv4Addr,
+    pub private_ip6: Option<Ipv6Addr>,
+    pub routes: Vec<Subnet>, // subnets reachable through peer
+    pub exit_node: bool,     // peer forwards traffic to internet
+    pub path_mtu: usize,     // largest ip packet that gets to peer over underlay
+    pub pmtu_probe_at: std::time::Instant,
+    pub pmtu_acked: usize, // largest probe acked since last probing started
+    pub pmtu_probed: u16, // bit of each PMTU_PROBE_SIZES entry sent in current probing, 0 between probings
+    pub replay: Arc<Mutex<ReplayWindow>>, // counters of packets received from peer
+    pub packet_counter: Arc<AtomicU64>, // next counter put into nonce of PACKET to peer
+    pub session: Option<Arc<Mutex<Session>>>, // keys agreed on in hello when network uses aead
+    pub identity: Option<IdentityKey>, // proven by peer in hello when network uses aead
+    // last hello we answered and our answer, retried hello gets same one so both sides keep same keys
+    pub hello: Box<[u8]>,
+    pub hello_answer: Box<[u8]>,
 }
 impl Peer {
     pub fn new(sock_addr: std::net::SocketAddr, private_ip: Option<std::net::Ipv4Addr>) -> Self {
@@ -14,8 +45,405 @@ impl Peer {
                 Some(ip) => ip,
                 None => std::net::Ipv4Addr::UNSPECIFIED,
             },
+            private_ip6: None,
+            routes: Vec::new(),
+            exit_node: false,
+            path_mtu: UNDERLAY_MTU,
+            pmtu_probe_at: std::time::Instant::now()
+                + std::time::Duration::from_secs(PMTU_FIRST_PROBE_DELAY_S),
+            pmtu_acked: 0,
+            pmtu_probed: 0,
+            replay: Arc::new(Mutex::new(ReplayWindow::default())),
+            // starting at current time keeps counters growing when client is restarted
+            packet_counter: Arc::new(AtomicU64::new(
+                std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_nanos() as u64,
+            )),
+            session: None,
+            identity: None,
+            hello: Box::default(),
+            hello_answer: Box::default(),
         }
     }
+
+    /// keys from new hello replace old session, epochs start again from 0
+    pub fn start_session(&mut self, keys: SessionKeys) {
+        self.session = Some(Arc::new(Mutex::new(Session::new(keys))));
+    }
+}
+
+/// packet counters seen from peer, sliding window of REPLAY_WINDOW_SIZE behind newest one
+#[derive(Default)]
+pub struct ReplayWindow {
+    newest: u64,
+    seen: [u64; (REPLAY_WINDOW_SIZE / 64) as usize], // bit for every counter in window
+    pub replayed: u64, // packets dropped because they were seen already or are too old
+}
+
+impl ReplayWindow {
+    /// false if packet with this counter was already received, packet has to be authenticated first
+    /// so forged counters can't move window
+    pub fn accept(&mut self, counter: u64) -> bool {
+        if counter > self.newest {
+            if counter - self.newest >= REPLAY_WINDOW_SIZE {
+                self.seen = Default::default();
+            } else {
+                for c in self.newest + 1..=counter {
+                    self.seen[(c % REPLAY_WINDOW_SIZE / 64) as usize] &= !(1 << (c % 64));
+                }
+            }
+            self.newest = counter;
+        } else if self.newest - counter >= REPLAY_WINDOW_SIZE {
+            self.replayed += 1;
+            return false;
+        }
+        let word = &mut self.seen[(counter % REPLAY_WINDOW_SIZE / 64) as usize];
+        if *word & (1 << (counter % 64)) != 0 {
+            self.replayed += 1;
+            return false;
+        }
+        *word |= 1 << (counter % 64);
+        true
+    }
+}
+
+/// session keys of peer moving through epochs, each side moves its sending key on its own and
+/// receiving key follows epoch in header of packets, shared with data path like replay window
+pub struct Session {
+    send: SecretKey,
+    send_epoch: u32,
+    send_since: std::time::Instant,
+    send_bytes: u64, // sealed with current sending key
+    recv: SecretKey,
+    recv_epoch: u32,
+    previous_recv: Option<(SecretKey, u32, std::time::Instant)>, // key, its epoch and when it stops being accepted
+}
+
+impl Session {
+    pub fn new(keys: SessionKeys) -> Self {
+        Session {
+            send: keys.send,
+            send_epoch: 0,
+            send_since: std::time::Instant::now(),
+            send_bytes: 0,
+            recv: keys.recv,
+            recv_epoch: 0,
+            previous_recv: None,
+        }
+    }
+
+    /// key and epoch to seal packet with, key is moved to next epoch first when it is used too long
+    pub fn send_key(&mut self, len: usize) -> (SecretKey, u32) {
+        if self.send_since.elapsed().as_secs() >= REKEY_AFTER_S
+            || self.send_bytes >= REKEY_AFTER_BYTES
+        {
+            self.send = shared::crypto::ratchet_key(&self.send);
+            self.send_epoch = self.send_epoch.wrapping_add(1);
+            self.send_since = std::time::Instant::now();
+            self.send_bytes = 0;
+            #[cfg(debug_assertions)]
+            eprintln!("sending key moved to epoch {}", self.send_epoch);
+        }
+        self.send_bytes += len as u64;
+        (self.send.clone(), self.send_epoch)
+    }
+
+    /// key to open packet of given epoch with, None if epoch is gone or too far ahead,
+    /// nothing changes until packet is authenticated by it and advance_recv is called
+    pub fn recv_key(&mut self, epoch: u32) -> Option<SecretKey> {
+        if self
+            .previous_recv
+            .as_ref()
+            .is_some_and(|(_, _, until)| *until <= std::time::Instant::now())
+        {
+            self.previous_recv = None;
+        }
+        if epoch == self.recv_epoch {
+            return Some(self.recv.clone());
+        }
+        if let Some((key, previous, _)) = &self.previous_recv
+            && *previous == epoch
+        {
+            return Some(key.clone());
+        }
+        let ahead = epoch.wrapping_sub(self.recv_epoch);
+        if ahead > REKEY_MAX_EPOCH_SKIP {
+            return None;
+        }
+        let mut key = self.recv.clone();
+        for _ in 0..ahead {
+            key = shared::crypto::ratchet_key(&key);
+        }
+        Some(key)
+    }
+
+    /// peer moved to next epoch, key of last one is kept for packets still on the way
+    pub fn advance_recv(&mut self, epoch: u32, key: SecretKey) {
+        let ahead = epoch.wrapping_sub(self.recv_epoch);
+        if ahead == 0 || ahead > REKEY_MAX_EPOCH_SKIP {
+            return;
+        }
+        // key of epoch before previous one is wiped when it is replaced
+        self.previous_recv = Some((
+            std::mem::replace(&mut self.recv, key),
+            self.recv_epoch,
+            std::time::Instant::now() + std::time::Duration::from_secs(REKEY_GRACE_S),
+        ));
+        self.recv_epoch = epoch;
+    }
+
+    /// epochs of sending and receiving key
+    pub fn epochs(&self) -> (u32, u32) {
+        (self.send_epoch, self.recv_epoch)
+    }
+}
+
+/// PAKE we answered in PEER_QUERY, its key authenticates hello of same peer
+pub struct Pake {
+    pub query: Box<[u8]>, // message of peer, retried query gets same answer so keys stay same
+    pub answer: Box<[u8]>,
+    pub psk: SecretKey,
+    pub at: std::time::Instant,
+}
+
+/// identity keys of members signed by owner of network, owner is allways member
+#[derive(Clone)]
+pub struct Allowlist {
+    pub version: u64,
+    pub members: Vec<IdentityKey>,
+    pub signature: [u8; SIGNATURE_SIZE],
+}
+
+impl Allowlist {
+    pub fn signed(
+        owner: &Identity,
+        net_tag: &[u8; NET_TAG_SIZE],
+        version: u64,
+        members: Vec<IdentityKey>,
+    ) -> Self {
+        let mut list = Allowlist {
+            version,
+            members,
+            signature: [0u8; SIGNATURE_SIZE],
+        };
+        let bytes = list.to_bytes();
+        list.signature =
+            owner.sign(&[&net_tag[..], &bytes[..bytes.len() - SIGNATURE_SIZE]].concat());
+        list
+    }
+
+    /// parse list received from peer, None when it isn't signed by owner
+    pub fn from_bytes(
+        bytes: &[u8],
+        owner: &IdentityKey,
+        net_tag: &[u8; NET_TAG_SIZE],
+    ) -> Option<Self> {
+        let members_len = bytes
+            .len()
+            .checked_sub(AllowlistPositions::MEMBERS as usize + SIGNATURE_SIZE)?;
+        if members_len % IDENTITY_KEY_SIZE != 0 {
+            return None;
+        }
+        let (signed, signature) = bytes.split_at(bytes.len() - SIGNATURE_SIZE);
+        let signature: [u8; SIGNATURE_SIZE] = signature.try_into().unwrap();
+        if !owner.verify(&[&net_tag[..], signed].concat(), &signature) {
+            return None;
+        }
+        Some(Allowlist {
+            version: u64::from_be_bytes(
+                signed[AllowlistPositions::VERSION as usize..AllowlistPositions::MEMBERS as usize]
+                    .try_into()
+                    .unwrap(),
+            ),
+            members: signed[AllowlistPositions::MEMBERS as usize..]
+                .chunks(IDENTITY_KEY_SIZE)
+                .map(|k| IdentityKey(k.try_into().unwrap()))
+                .collect(),
+            signature,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = Vec::with_capacity(
+            AllowlistPositions::MEMBERS as usize
+                + self.members.len() * IDENTITY_KEY_SIZE
+                + SIGNATURE_SIZE,
+        );
+        bytes.extend_from_slice(&self.version.to_be_bytes());
+        self.members
+            .iter()
+            .for_each(|k| bytes.extend_from_slice(&k.0));
+        bytes.extend_from_slice(&self.signature);
+        bytes
+    }
+
+    pub fn allows(&self, key: &IdentityKey) -> bool {
+        self.members.contains(key)
+    }
+}
+
+/// addresses peer announces in PEER_QUERY response and PEER_HELLO
+/// ipv4 goes first, optional fields follow as key=value separated by spaces
+pub struct PeerAddrs {
+    pub ip: Ipv4Addr,
+    pub ip6: Option<Ipv6Addr>,
+    pub routes: Vec<Subnet>,
+    pub exit_node: bool,
+}
+
+impl std::fmt::Display for PeerAddrs {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.ip)?;
+        if let Some(ip6) = self.ip6 {
+            write!(f, " ip6={}", ip6)?;
+        }
+        for route in self.routes.iter() {
+            write!(f, " route={}", route)?;
+        }
+        if self.exit_node {
+            write!(f, " exit=1")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PeerAddrs {
+    type Err = ServerErrorResponses;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.trim_end_matches('\0').split(' ');
+        let ip = Ipv4Addr::from_str(fields.next().unwrap_or_default())
+            .map_err(|e| ServerErrorResponses::GENERAL_ERROR(format!("{}: {}", s, e)))?;
+        let mut ip6 = None;
+        let mut routes = Vec::new();
+        let mut exit_node = false;
+        // unknown fields are from newer clients
+        for field in fields {
+            match field.split_once('=') {
+                Some(("ip6", v)) => {
+                    ip6 = Some(Ipv6Addr::from_str(v).map_err(|e| {
+                        ServerErrorResponses::GENERAL_ERROR(format!("{}: {}", v, e))
+                    })?)
+                }
+                Some(("route", v)) => routes.push(Subnet::from_str(v)?),
+                Some(("exit", v)) => exit_node = v == "1",
+                _ => {}
+            }
+        }
+        Ok(PeerAddrs {
+            ip,
+            ip6,
+            routes,
+            exit_node,
+        })
+    }
+}
+
+/// what data path needs to know about peer
+pub struct PeerRoute {
+    pub sock_addr: SocketAddr,
+    pub private_ip: Ipv4Addr,
+    pub replay: Arc<Mutex<ReplayWindow>>,
+    pub packet_counter: Arc<AtomicU64>,
+    pub session: Option<Arc<Mutex<Session>>>,
+    pub mtu: usize, // largest packet from tun that gets to peer in one datagram
+}
+
+/// immutable view of network used by data path, control plane builds new one on every change
+/// and swaps it in so packets are forwarded without taking network lock
+#[derive(Default)]
+pub struct RoutingTable {
+    pub encrypted: bool,
+    pub key: SecretKey,
+    pub net_tag: [u8; NET_TAG_SIZE],
+    pub sender: [u8; IPV4_SIZE], // our virtual ip, goes into nonce with packet counter
+    pub subnet: Subnet,
+    pub mode: NetworkMode,
+    pub cipher: CipherSuite,
+    pub sessions: bool, // packets are encrypted with session key of peer instead of network key
+    pub compression: Compression,
+    pub compression_stats: Arc<CompressionStats>, // shared with network, counters outlive snapshots
+    pub peers: Vec<PeerRoute>, // everybody floods go to, maps below index into it
+    by_ip: HashMap<Ipv4Addr, usize>,
+    by_ip6: HashMap<Ipv6Addr, usize>,
+    by_sock_addr: HashMap<SocketAddr, usize>,
+    routes: Vec<(Subnet, usize)>, // longest prefix first
+    exit: Option<usize>,
+    pub mac_table: HashMap<[u8; MAC_SIZE], SocketAddr>,
+}
+
+impl RoutingTable {
+    /// peer owning virtual ip, then peer advertising longest matching route,
+    /// then exit node for addresses outside of network
+    pub fn lookup(&self, dst: Ipv4Addr) -> Option<&PeerRoute> {
+        let index = match self.by_ip.get(&dst) {
+            Some(&i) => Some(i),
+            None => match self.routes.iter().find(|(r, _)| r.contains(dst)) {
+                Some(&(_, i)) => Some(i),
+                None if !self.subnet.contains(dst) => self.exit,
+                None => None,
+            },
+        };
+        index.map(|i| &self.peers[i])
+    }
+
+    pub fn lookup6(&self, dst: Ipv6Addr) -> Option<&PeerRoute> {
+        self.by_ip6.get(&dst).map(|&i| &self.peers[i])
+    }
+
+    pub fn peer(&self, sock_addr: &SocketAddr) -> Option<&PeerRoute> {
+        self.by_sock_addr.get(sock_addr).map(|&i| &self.peers[i])
+    }
+
+    pub fn peer_with_ip(&self, ip: Ipv4Addr) -> Option<&PeerRoute> {
+        self.by_ip.get(&ip).map(|&i| &self.peers[i])
+    }
+}
+
+/// payload bytes of packets before and after compression
+#[derive(Default)]
+pub struct CompressionStats {
+    sent_raw: AtomicU64,
+    sent: AtomicU64,
+    received_raw: AtomicU64,
+    received: AtomicU64,
+}
+
+impl CompressionStats {
+    pub fn count_sent(&self, raw: usize, sent: usize) {
+        self.sent_raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.sent.fetch_add(sent as u64, Ordering::Relaxed);
+    }
+
+    pub fn count_received(&self, raw: usize, received: usize) {
+        self.received_raw.fetch_add(raw as u64, Ordering::Relaxed);
+        self.received.fetch_add(received as u64, Ordering::Relaxed);
+    }
+}
+
+impl std::fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // ratio is how many times smaller data got, 1 when nothing was sent yet
+        let ratio = |raw: &AtomicU64, wire: &AtomicU64| {
+            let (raw, wire) = (raw.load(Ordering::Relaxed), wire.load(Ordering::Relaxed));
+            match wire {
+                0 => 1.0,
+                _ => raw as f64 / wire as f64,
+            }
+        };
+        write!(
+            f,
+            "sent {} bytes as {} (ratio {:.2}), received {} bytes as {} (ratio {:.2})",
+            self.sent_raw.load(Ordering::Relaxed),
+            self.sent.load(Ordering::Relaxed),
+            ratio(&self.sent_raw, &self.sent),
+            self.received_raw.load(Ordering::Relaxed),
+            self.received.load(Ordering::Relaxed),
+            ratio(&self.received_raw, &self.received),
+        )
+    }
 }
 
 #[readonly::make]
@@ -23,31 +451,301 @@ pub struct Network {
     #[readonly]
     pub encrypted: bool,
     #[readonly]
-    pub key: [u8; 32],
+    pub key: SecretKey,
     #[readonly]
     pub net_id: String,
     #[readonly]
+    pub registrar_id: String, // what registrar knows network by, keyed hash of net_id when it is hidden
+    #[readonly]
+    pub net_tag: [u8; NET_TAG_SIZE],
+    #[readonly]
     pub salt: [u8; BLOCK_SIZE as usize],
+    #[readonly]
+    pub subnet: Subnet,
+    #[readonly]
+    pub mode: NetworkMode,
+    pub cipher: CipherSuite, // chosen by creator, stored with network params
+    pub kdf: KdfParams,      // chosen by creator, stored with network params
+    pub compression: Compression, // chosen by creator, stored with network params
+    #[readonly]
+    pub compression_stats: Arc<CompressionStats>,
     pub peers: Vec<Peer>,
+    pub mac_table: HashMap<[u8; MAC_SIZE], std::net::SocketAddr>, // learned from frames in tap mode
+    pub pakes: HashMap<SocketAddr, Pake>, // peers that queried us and didn't say hello yet
+    pub identity: Option<Arc<Identity>>,  // ours, set when joining network
+    pub owner: Option<IdentityKey>, // creator of network with allowlist, stored with network params
+    pub allowlist: Option<Allowlist>, // only members on it can say hello, None until we get it
+    pub recent_floods: VecDeque<(u64, std::time::Instant)>,
     pub private_ip: std::net::Ipv4Addr,
+    pub private_ip6: Ipv6Addr,
+    pub routes: Vec<Subnet>,             // subnets we advertise to peers
+    pub exit_node: bool,                 // we forward peers traffic to internet
+    pub use_exit_node: Option<Ipv4Addr>, // virtual ip of exit node our traffic goes through
+    pub exit_routes: Vec<Subnet>,        // routed through exit node, empty means default route
+    pub registrar: Option<std::net::SocketAddr>,
+    pub public_sock_addr: Option<std::net::SocketAddr>,
+    pub registration: Option<EncryptablePulicSockAddr>, // our sock addr as registrar keeps it
+    #[readonly]
+    pub heartbeat: Arc<ArcSwap<Box<[u8]>>>, // request periodic heartbeat sends, replaced when key changes
+    pub last_heartbeat_ack: Option<i64>,
+    pub last_update_ack: Option<i64>, // registrar took our update after password change
+    pub update_secret: Option<SecretKey>, // registrar changes salt only for it, creator makes it and peers pass it on
+    #[readonly]
+    pub routing: Arc<ArcSwap<RoutingTable>>, // published by publish(), read by data path
 }
 
 impl Network {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         encrypted: bool,
-        key: [u8; 32],
+        key: SecretKey,
         net_id: String,
+        registrar_id: String,
         salt: [u8; BLOCK_SIZE as usize],
+        subnet: Subnet,
+        mode: NetworkMode,
         peers: Vec<Peer>,
     ) -> Self {
         Network {
             encrypted,
             key,
+            // registrar tags its notifications by id it knows
+            net_tag: shared::net::net_tag(&registrar_id),
             net_id,
+            registrar_id,
             salt,
+            subnet,
+            mode,
+            cipher: CipherSuite::AES_256_CBC,
+            kdf: KdfParams::default(),
+            compression: Compression::NONE,
+            compression_stats: Arc::new(CompressionStats::default()),
             peers,
+            mac_table: HashMap::new(),
+            pakes: HashMap::new(),
+            identity: None,
+            owner: None,
+            allowlist: None,
+            recent_floods: VecDeque::with_capacity(FLOOD_CACHE_SIZE),
             private_ip: std::net::Ipv4Addr::UNSPECIFIED,
+            private_ip6: Ipv6Addr::UNSPECIFIED,
+            routes: Vec::new(),
+            exit_node: false,
+            use_exit_node: None,
+            exit_routes: Vec::new(),
+            registrar: None,
+            public_sock_addr: None,
+            registration: None,
+            heartbeat: Arc::new(ArcSwap::from_pointee(Box::default())),
+            last_heartbeat_ack: None,
+            last_update_ack: None,
+            update_secret: None,
+            routing: Arc::new(ArcSwap::from_pointee(RoutingTable::default())),
+        }
+    }
+
+    /// swap in routing table matching current peers, has to be called after every change
+    /// of peers, their routes or mtu, mac table or exit node
+    pub fn publish(&self) {
+        let mut table = RoutingTable {
+            encrypted: self.encrypted,
+            key: self.key.clone(),
+            net_tag: self.net_tag,
+            sender: self.private_ip.octets(),
+            subnet: self.subnet,
+            mode: self.mode,
+            cipher: self.cipher,
+            sessions: self.uses_sessions(),
+            compression: self.compression,
+            compression_stats: self.compression_stats.clone(),
+            mac_table: self.mac_table.clone(),
+            ..Default::default()
+        };
+        // peers that didn't tell us their address yet can't be routed to
+        for peer in self.peers.iter().filter(|p| !p.private_ip.is_unspecified()) {
+            let i = table.peers.len();
+            table.peers.push(PeerRoute {
+                sock_addr: peer.sock_addr,
+                private_ip: peer.private_ip,
+                replay: peer.replay.clone(),
+                packet_counter: peer.packet_counter.clone(),
+                session: peer.session.clone(),
+                mtu: self.mtu_for(peer.path_mtu),
+            });
+            table.by_ip.insert(peer.private_ip, i);
+            if let Some(ip6) = peer.private_ip6 {
+                table.by_ip6.insert(ip6, i);
+            }
+            table.by_sock_addr.insert(peer.sock_addr, i);
+            table.routes.extend(
+                peer.routes
+                    .iter()
+                    .filter(|r| !r.overlaps(&self.subnet))
+                    .map(|r| (*r, i)),
+            );
+            if peer.exit_node && self.use_exit_node == Some(peer.private_ip) {
+                table.exit = Some(i);
+            }
+        }
+        table
+            .routes
+            .sort_by_key(|(r, _)| std::cmp::Reverse(r.prefix_len()));
+        self.routing.store(Arc::new(table));
+    }
+
+    /// peers agree on their own keys in hello, network key only proves membership
+    /// aes-256-cbc networks keep using network key for everything
+    pub fn uses_sessions(&self) -> bool {
+        self.encrypted && self.cipher != CipherSuite::AES_256_CBC
+    }
+
+    /// unique local ipv6 address belonging to ipv4 address
+    /// prefix is derived from network id registrar knows and ipv4 address is used as interface id
+    pub fn ip6_for(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = [0u8; IPV6_SIZE];
+        octets[0] = 0xfd; // locally assigned ULA
+        octets[1..6].copy_from_slice(&sha2::Sha256::digest(self.registrar_id.as_bytes())[..5]); // global id
+        octets[IPV6_SIZE - IPV4_SIZE..].copy_from_slice(&ip.octets());
+        Ipv6Addr::from(octets)
+    }
+
+    /// largest packet or frame from tun that fits into one udp datagram on path with given mtu
+    pub fn mtu_for(&self, path_mtu: usize) -> usize {
+        let mut available = path_mtu.max(PATH_MTU_MIN)
+            - IPV4_HEADER_SIZE
+            - UDP_HEADER_SIZE
+            - P2PStandardDataPositions::DATA as usize;
+        if self.encrypted {
+            available = match self.cipher {
+                CipherSuite::AES_256_CBC => available / BLOCK_SIZE * BLOCK_SIZE - 1, // padding is allways at least one byte
+                _ => available - AEAD_TAG_SIZE,
+            };
+        }
+        match self.mode {
+            NetworkMode::TUN => available,
+            NetworkMode::TAP => available - ETHERNET_HEADER_SIZE, // mtu of tap doesn't count ethernet header
+        }
+    }
+
+    pub fn addrs(&self) -> PeerAddrs {
+        PeerAddrs {
+            ip: self.private_ip,
+            ip6: Some(self.private_ip6),
+            routes: self.routes.clone(),
+            exit_node: self.exit_node,
+        }
+    }
+
+    /// sock addrs at registrar are encrypted with network key only on aes-256-cbc networks,
+    /// on others anybody asking registrar could check guessed password against them
+    pub fn encrypts_sock_addrs(&self) -> bool {
+        self.encrypted && !self.uses_sessions()
+    }
+
+    /// keep key agreed on in query until hello of same peer comes
+    pub fn remember_pake(&mut self, sock_addr: SocketAddr, pake: Pake) {
+        self.pakes
+            .retain(|_, p| p.at.elapsed().as_secs() < PAKE_TIMEOUT_S);
+        if self.pakes.len() >= PAKE_PENDING_MAX
+            && !self.pakes.contains_key(&sock_addr)
+            && let Some(oldest) = self.pakes.iter().min_by_key(|(_, p)| p.at).map(|(s, _)| *s)
+        {
+            self.pakes.remove(&oldest);
         }
+        self.pakes.insert(sock_addr, pake);
+    }
+
+    /// psk for hello of peer, it can be used only once
+    pub fn take_pake_psk(&mut self, sock_addr: &SocketAddr) -> Option<SecretKey> {
+        self.pakes
+            .remove(sock_addr)
+            .filter(|p| p.at.elapsed().as_secs() < PAKE_TIMEOUT_S)
+            .map(|p| p.psk)
+    }
+
+    /// remember broadcast received from peer so it isn't flooded again if it comes back from tun
+    pub fn remember_flood(&mut self, key: u64) {
+        let now = std::time::Instant::now();
+        while let Some(&(_, t)) = self.recent_floods.front() {
+            if self.recent_floods.len() < FLOOD_CACHE_SIZE
+                && now.duration_since(t).as_millis() < FLOOD_CACHE_TTL_MS as u128
+            {
+                break;
+            }
+            self.recent_floods.pop_front();
+        }
+        self.recent_floods.push_back((key, now));
+    }
+
+    pub fn is_recent_flood(&self, key: u64) -> bool {
+        self.recent_floods
+            .iter()
+            .any(|&(k, t)| k == key && t.elapsed().as_millis() < FLOOD_CACHE_TTL_MS as u128)
+    }
+
+    /// both sides of hello put it into handshake, peer that got other owner from registrar
+    /// can't finish it
+    pub fn handshake_prologue(&self) -> Vec<u8> {
+        let mut prologue = self.net_tag.to_vec();
+        if let Some(owner) = self.owner {
+            prologue.extend_from_slice(&owner.0);
+        }
+        prologue
+    }
+
+    /// peer can say hello when network has no owner or peer is on allowlist
+    pub fn admits(&self, identity: &IdentityKey) -> bool {
+        match (self.owner, &self.allowlist) {
+            (None, _) => true,
+            (Some(_), Some(list)) => list.allows(identity),
+            (Some(_), None) => false,
+        }
+    }
+
+    pub fn is_owner(&self) -> bool {
+        match (self.owner, &self.identity) {
+            (Some(owner), Some(identity)) => owner == identity.public(),
+            _ => false,
+        }
+    }
+
+    /// switch to key derived from new password and update secret registrar wants next time,
+    /// caller publishes routing table
+    pub fn change_key(&mut self, key: SecretKey, salt: [u8; BLOCK_SIZE], update_secret: SecretKey) {
+        self.key = key;
+        self.salt = salt;
+        self.update_secret = Some(update_secret);
+    }
+
+    /// serialize network params that are stored by registrar
+    pub fn params(&self) -> Box<[u8]> {
+        let mut params: Box<[u8]> = vec![
+            0u8;
+            match self.owner {
+                Some(_) => OWNED_NETWORK_PARAMS_SIZE,
+                None => NetworkParamsPositions::END as usize,
+            }
+        ]
+        .into();
+        params[NetworkParamsPositions::SUBNET as usize
+            ..NetworkParamsPositions::SUBNET as usize + IPV4_SIZE]
+            .copy_from_slice(&self.subnet.addr().octets());
+        params[NetworkParamsPositions::PREFIX_LEN as usize] = self.subnet.prefix_len();
+        params[NetworkParamsPositions::MODE as usize] = self.mode as u8;
+        params[NetworkParamsPositions::COMPRESSION as usize] = self.compression as u8;
+        params[NetworkParamsPositions::CIPHER as usize] = self.cipher as u8;
+        params[NetworkParamsPositions::KDF as usize] = self.kdf.kdf as u8;
+        params[NetworkParamsPositions::KDF_MEMORY as usize
+            ..NetworkParamsPositions::KDF_ITERATIONS as usize]
+            .copy_from_slice(&self.kdf.memory.to_be_bytes());
+        params[NetworkParamsPositions::KDF_ITERATIONS as usize
+            ..NetworkParamsPositions::KDF_PARALLELISM as usize]
+            .copy_from_slice(&self.kdf.iterations.to_be_bytes());
+        params[NetworkParamsPositions::KDF_PARALLELISM as usize] = self.kdf.parallelism;
+        if let Some(owner) = self.owner {
+            params[NetworkParamsPositions::END as usize..].copy_from_slice(&owner.0);
+        }
+        params
     }
 }
 
@@ -64,3 +762,305 @@ impl EncryptablePulicSockAddr {
         EncryptablePulicSockAddr { iv, sock_addr }
     }
 }
+
+/// tun carrying ip packets or tap carrying ethernet frames
+pub enum VirtualIface {
+    Tun(tappers::Tun),
+    #[cfg(not(target_os = "windows"))]
+    Tap(tappers::Tap),
+}
+
+impl VirtualIface {
+    pub fn name(&self) -> std::io::Result<String> {
+        let interface = match self {
+            VirtualIface::Tun(t) => t.name()?,
+            #[cfg(not(target_os = "windows"))]
+            VirtualIface::Tap(t) => t.name()?,
+        };
+        Ok(interface.name().to_string_lossy().into_owned())
+    }
+
+    pub fn send(&self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            VirtualIface::Tun(t) => t.send(buf),
+            #[cfg(not(target_os = "windows"))]
+            VirtualIface::Tap(t) => t.send(buf),
+        }
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            VirtualIface::Tun(t) => t.recv(buf),
+            #[cfg(not(target_os = "windows"))]
+            VirtualIface::Tap(t) => t.recv(buf),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for VirtualIface {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        match self {
+            VirtualIface::Tun(t) => t.as_raw_fd(),
+            VirtualIface::Tap(t) => t.as_raw_fd(),
+        }
+    }
+}
+
+/// network this process is member of together with everything that serves it
+pub struct JoinedNetwork {
+    pub network: Arc<RwLock<Network>>,
+    pub routing: Arc<ArcSwap<RoutingTable>>, // same as network.routing, reachable without lock
+    pub tun_iface: Arc<VirtualIface>,
+    pub socket: Arc<std::net::UdpSocket>,
+    pub if_name: String,
+    pub left: Arc<AtomicBool>, // tells threads serving this network to stop
+}
+
+// all joined networks by their net tag
+pub type Networks = Arc<RwLock<HashMap<[u8; NET_TAG_SIZE], Arc<JoinedNetwork>>>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_window_accepts_in_order() {
+        let mut window = ReplayWindow::default();
+        for counter in 0..3 * REPLAY_WINDOW_SIZE {
+            assert!(window.accept(counter), "counter {}", counter);
+        }
+        assert_eq!(window.replayed, 0);
+    }
+
+    #[test]
+    fn replay_window_drops_duplicates() {
+        let mut window = ReplayWindow::default();
+        assert!(window.accept(5));
+        assert!(!window.accept(5));
+        assert!(window.accept(10));
+        assert!(window.accept(7)); // reordered but not seen yet
+        assert!(!window.accept(7));
+        assert!(!window.accept(10));
+        assert_eq!(window.replayed, 3);
+    }
+
+    #[test]
+    fn replay_window_drops_too_old() {
+        let mut window = ReplayWindow::default();
+        let newest = 5 * REPLAY_WINDOW_SIZE;
+        assert!(window.accept(newest));
+        assert!(!window.accept(newest - REPLAY_WINDOW_SIZE));
+        assert!(!window.accept(0));
+        assert!(window.accept(newest - REPLAY_WINDOW_SIZE + 1));
+        assert_eq!(window.replayed, 2);
+    }
+
+    #[test]
+    fn replay_window_jumps_forward() {
+        let mut window = ReplayWindow::default();
+        for counter in 0..100 {
+            assert!(window.accept(counter));
+        }
+        // reuses slots of already seen counters, they must be cleared
+        let newest = 98 + REPLAY_WINDOW_SIZE;
+        assert!(window.accept(newest));
+        assert!(window.accept(newest - 1));
+        assert!(window.accept(100));
+        assert!(!window.accept(99));
+
+        assert!(window.accept(1 << 40));
+        assert!(!window.accept(newest));
+        assert!(window.accept((1 << 40) - 1));
+        assert!(!window.accept(1 << 40));
+    }
+
+    fn session() -> Session {
+        Session::new(SessionKeys {
+            send: SecretKey::new([1; 32]),
+            recv: SecretKey::new([2; 32]),
+        })
+    }
+
+    #[test]
+    fn session_rekeys_after_time() {
+        let mut session = session();
+        let (key, epoch) = session.send_key(100);
+        assert_eq!((*key, epoch), ([1; 32], 0));
+        session.send_since = std::time::Instant::now()
+            .checked_sub(std::time::Duration::from_secs(REKEY_AFTER_S))
+            .unwrap();
+        let (key, epoch) = session.send_key(100);
+        assert_eq!(epoch, 1);
+        assert_eq!(*key, *shared::crypto::ratchet_key(&[1; 32]));
+        // fresh epoch is kept
+        assert_eq!(session.send_key(100).1, 1);
+    }
+
+    #[test]
+    fn session_rekeys_after_bytes() {
+        let mut session = session();
+        assert_eq!(session.send_key(REKEY_AFTER_BYTES as usize - 1).1, 0);
+        assert_eq!(session.send_key(1).1, 0);
+        assert_eq!(session.send_key(1).1, 1);
+        assert_eq!(session.send_key(REKEY_AFTER_BYTES as usize).1, 1);
+        assert_eq!(session.send_key(1).1, 2);
+        assert_eq!(session.epochs(), (2, 0));
+    }
+
+    #[test]
+    fn session_keeps_previous_epoch_for_grace_period() {
+        let mut session = session();
+        let next = session.recv_key(1).unwrap();
+        assert_eq!(*next, *shared::crypto::ratchet_key(&[2; 32]));
+        assert!(session.recv_key(REKEY_MAX_EPOCH_SKIP + 1).is_none());
+        session.advance_recv(1, next);
+        assert_eq!(session.epochs(), (0, 1));
+        assert_eq!(*session.recv_key(0).unwrap(), [2; 32]);
+
+        let (_, _, until) = session.previous_recv.as_ref().unwrap();
+        let grace = until.duration_since(std::time::Instant::now());
+        assert!(grace <= std::time::Duration::from_secs(REKEY_GRACE_S));
+        assert!(grace > std::time::Duration::from_secs(REKEY_GRACE_S - 1));
+
+        // grace period is over
+        session.previous_recv.as_mut().unwrap().2 = std::time::Instant::now();
+        assert!(session.recv_key(0).is_none());
+        assert!(session.previous_recv.is_none());
+        assert_eq!(
+            *session.recv_key(1).unwrap(),
+            *shared::crypto::ratchet_key(&[2; 32])
+        );
+    }
+
+    fn network(encrypted: bool, cipher: CipherSuite, mode: NetworkMode) -> Network {
+        let mut network = Network::new(
+            encrypted,
+            SecretKey::default(),
+            "net".to_owned(),
+            "net".to_owned(),
+            [0; BLOCK_SIZE],
+            Subnet::default(),
+            mode,
+            Vec::new(),
+        );
+        network.cipher = cipher;
+        network
+    }
+
+    #[test]
+    fn mtu_for_leaves_room_for_headers() {
+        let overhead = IPV4_HEADER_SIZE + UDP_HEADER_SIZE + P2PStandardDataPositions::DATA as usize;
+        let plain = network(false, CipherSuite::AES_256_GCM, NetworkMode::TUN);
+        assert_eq!(plain.mtu_for(1500), 1500 - overhead);
+        let aead = network(true, CipherSuite::CHACHA20_POLY1305, NetworkMode::TUN);
+        assert_eq!(aead.mtu_for(1500), 1500 - overhead - AEAD_TAG_SIZE);
+        let tap = network(true, CipherSuite::AES_256_GCM, NetworkMode::TAP);
+        assert_eq!(
+            tap.mtu_for(1500),
+            1500 - overhead - AEAD_TAG_SIZE - ETHERNET_HEADER_SIZE
+        );
+        // padded packet has to fit too
+        let cbc = network(true, CipherSuite::AES_256_CBC, NetworkMode::TUN).mtu_for(1500);
+        assert_eq!((cbc + 1) % BLOCK_SIZE, 0);
+        assert!(cbc < 1500 - overhead);
+    }
+
+    #[test]
+    fn mtu_for_tiny_path_mtu() {
+        let tap = network(true, CipherSuite::AES_256_CBC, NetworkMode::TAP);
+        let min = tap.mtu_for(PATH_MTU_MIN);
+        assert!(min > 0);
+        for path_mtu in [0, 1, 100, PATH_MTU_MIN - 1] {
+            assert_eq!(tap.mtu_for(path_mtu), min);
+        }
+    }
+
+    fn identity(seed: u8) -> Identity {
+        // identity is only loaded from file, tests run in parallel so each gets its own
+        let path = std::env::temp_dir().join(format!(
+            "pea_2_pea_identity_{}_{:?}_{}",
+            std::process::id(),
+            std::thread::current().id(),
+            seed
+        ));
+        std::fs::write(&path, [seed; 32]).unwrap();
+        let identity = Identity::load_or_create(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        identity
+    }
+
+    #[test]
+    fn allowlist_round_trip() {
+        let owner = identity(1);
+        let member = identity(2).public();
+        let list = Allowlist::signed(&owner, &[7; NET_TAG_SIZE], 3, vec![owner.public(), member]);
+        let parsed =
+            Allowlist::from_bytes(&list.to_bytes(), &owner.public(), &[7; NET_TAG_SIZE]).unwrap();
+        assert_eq!(parsed.version, 3);
+        assert_eq!(parsed.members, vec![owner.public(), member]);
+        assert!(parsed.allows(&member));
+        assert!(!parsed.allows(&identity(3).public()));
+    }
+
+    #[test]
+    fn allowlist_rejects_forged_lists() {
+        let owner = identity(1);
+        let tag = [7; NET_TAG_SIZE];
+        let bytes = Allowlist::signed(&owner, &tag, 3, vec![owner.public()]).to_bytes();
+
+        let mut bad_signature = bytes.clone();
+        *bad_signature.last_mut().unwrap() ^= 1;
+        assert!(Allowlist::from_bytes(&bad_signature, &owner.public(), &tag).is_none());
+        let mut other_member = bytes.clone();
+        other_member[AllowlistPositions::MEMBERS as usize] ^= 1;
+        assert!(Allowlist::from_bytes(&other_member, &owner.public(), &tag).is_none());
+        // list of other network can't be replayed here
+        assert!(Allowlist::from_bytes(&bytes, &owner.public(), &[8; NET_TAG_SIZE]).is_none());
+        // signed by somebody else than owner
+        let stranger = Allowlist::signed(&identity(2), &tag, 4, vec![identity(2).public()]);
+        assert!(Allowlist::from_bytes(&stranger.to_bytes(), &owner.public(), &tag).is_none());
+
+        assert!(Allowlist::from_bytes(&bytes[1..], &owner.public(), &tag).is_none());
+        assert!(Allowlist::from_bytes(&bytes[..SIGNATURE_SIZE], &owner.public(), &tag).is_none());
+    }
+
+    #[test]
+    fn peer_addrs_round_trip() {
+        let addrs = PeerAddrs {
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            ip6: Some(Ipv6Addr::from_str("fd00::2").unwrap()),
+            routes: vec![Subnet::from_str("192.168.1.0/24").unwrap()],
+            exit_node: true,
+        };
+        let parsed = PeerAddrs::from_str(&addrs.to_string()).unwrap();
+        assert_eq!(parsed.ip, addrs.ip);
+        assert_eq!(parsed.ip6, addrs.ip6);
+        assert_eq!(parsed.routes, addrs.routes);
+        assert!(parsed.exit_node);
+
+        // nul padding and unknown fields are skipped
+        let parsed = PeerAddrs::from_str("10.0.0.3 future=1\0\0").unwrap();
+        assert_eq!(parsed.ip, Ipv4Addr::new(10, 0, 0, 3));
+        assert_eq!(
+            (parsed.ip6, parsed.routes.len(), parsed.exit_node),
+            (None, 0, false)
+        );
+    }
+
+    #[test]
+    fn peer_addrs_rejects_malformed() {
+        for s in [
+            "",
+            " ip6=fd00::2",
+            "10.0.0",
+            "300.0.0.1",
+            "10.0.0.2 ip6=fd00::zz",
+            "10.0.0.2 ip6=10.0.0.3",
+            "10.0.0.2 route=192.168.1.0",
+            "10.0.0.2 route=192.168.1.0/33",
+        ] {
+            assert!(PeerAddrs::from_str(s).is_err(), "{:?}", s);
+        }
+    }
+}
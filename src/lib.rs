@@ -2,20 +2,90 @@ use core::fmt;
 
 pub const SERVER_PORT: u16 = 3543;
 pub const UDP_BUFFER_SIZE: usize = 65527;
+pub const UDP_PAYLOAD_MAX: usize = 65507; // biggest datagram that can be sent over ipv4
 pub const IP_BUFFER_SIZE: usize = 65535;
 pub const DEFAULT_TIMEOUT: u64 = 30;
-pub const VERSION: &str = "v1.1.1";
+pub const VERSION: &str = "v1.2.0";
 pub const BLOCK_SIZE: usize = 16;
+pub const AEAD_NONCE_SIZE: usize = 12; // taken from start of IV
+pub const AEAD_TAG_SIZE: usize = 16;
+pub const NOISE_PATTERN: &str = "Noise_IKpsk2_25519_ChaChaPoly_BLAKE2s"; // static keys are identities of peers, ephemeral keys give forward secrecy
+pub const NOISE_HELLO_OVERHEAD: usize = 96; // ephemeral key, encrypted static key and tag added to payload of hello
+pub const NOISE_ANSWER_OVERHEAD: usize = 48; // ephemeral key and tag added to payload of answer
+pub const IDENTITY_KEY_SIZE: usize = 32; // ed25519 public key of client
+pub const SIGNATURE_SIZE: usize = 64;
+pub const IDENTITY_FILE_NAME: &str = "identity.key";
+pub const PAKE_MESSAGE_SIZE: usize = 33; // SPAKE2 element carried by PEER_QUERY and its answer
+pub const PAKE_TIMEOUT_S: u64 = 60; // key agreed on in query is forgotten when hello doesn't follow
+pub const PAKE_PENDING_MAX: usize = 256; // queries waiting for hello, oldest one is forgotten first
 pub const STANDARD_RETRY_MAX: usize = 10;
+pub const UPDATE_SECRET_SIZE: usize = 32; // random secret of registration registrar wants before it takes new salt
 
+pub const PBKDF2_ITERATIONS: u32 = 10000;
+pub const ARGON2_MEMORY_KIB: u32 = 65536;
+pub const ARGON2_ITERATIONS: u32 = 3;
+pub const ARGON2_PARALLELISM: u8 = 4;
+pub const ARGON2_MEMORY_MAX_KIB: u32 = 1048576; // registrar could ask joining clients for more than they have
+pub const ARGON2_ITERATIONS_MAX: u32 = 16; // and for more time than they want to wait
+pub const ARGON2_PARALLELISM_MAX: u8 = 16;
+pub const PBKDF2_ITERATIONS_MAX: u32 = 1000000;
+
+pub const NET_TAG_SIZE: usize = 4;
+
+pub const SRC_IN_IPV4_OFFSET: usize = 12;
 pub const DEST_IN_IPV4_OFFSET: usize = 16;
 pub const IPV4_SIZE: usize = 4;
+pub const TTL_IN_IPV4_OFFSET: usize = 8;
+pub const HOP_LIMIT_IN_IPV6_OFFSET: usize = 7;
+pub const DEST_IN_IPV6_OFFSET: usize = 24;
+pub const IPV6_SIZE: usize = 16;
+pub const ULA_PREFIX_LEN: u8 = 64; // every network gets its own fdxx:xxxx:xxxx::/64
+
+pub const IPV4_HEADER_SIZE: usize = 20; // without options
+pub const IPV6_HEADER_SIZE: usize = 40;
+pub const UDP_HEADER_SIZE: usize = 8;
+pub const TCP_HEADER_SIZE: usize = 20; // without options
+pub const ICMP_HEADER_SIZE: usize = 8;
+pub const PROTOCOL_IN_IPV4_OFFSET: usize = 9;
+pub const NEXT_HEADER_IN_IPV6_OFFSET: usize = 6;
+
+pub const UNDERLAY_MTU: usize = 1500; // assumed until path mtu to peer is probed
+pub const PMTU_PROBE_SIZES: [usize; 8] = [1500, 1492, 1480, 1440, 1400, 1280, 1000, 576]; // ip packet sizes on underlay
+pub const PMTU_PROBE_INTERVAL_S: u64 = 600;
+pub const PATH_MTU_MIN: usize = 576; // every ipv4 host takes packet this big, smallest probe
+pub const PMTU_FIRST_PROBE_DELAY_S: u64 = 10; // new peer is still joining and doesn't listen yet
+pub const PMTU_PROBE_WAIT_MS: u64 = 2000; // acks that come later are ignored
+
+pub const MAC_SIZE: usize = 6;
+pub const SRC_MAC_OFFSET: usize = 6; // destination mac is at start of frame
+pub const ETHERTYPE_OFFSET: usize = 12;
+pub const ETHERNET_HEADER_SIZE: usize = 14;
 
 pub const MAPPING_SHOT_COUNT: u8 = 5;
 
-pub const DEFAULT_NETWORK_PREFIX: [u8; 3] = [172, 22, 44];
+pub const UDP_BATCH_SIZE: usize = 16; // datagrams sent or received by one syscall on linux
+
+pub const FLOOD_CACHE_SIZE: usize = 256; // broadcasts received from peers that won't be flooded again
+pub const FLOOD_CACHE_TTL_MS: u64 = 2000;
+
+pub const REPLAY_WINDOW_SIZE: u64 = 1024; // packets older than newest one from peer minus this are dropped
+
+pub const REKEY_AFTER_S: u64 = 600; // session key for sending to peer is moved to next epoch this often
+pub const REKEY_AFTER_BYTES: u64 = 1 << 30; // or after this many bytes were sealed with it
+pub const REKEY_GRACE_S: u64 = 30; // packets sealed with previous epoch are still opened this long
+pub const REKEY_MAX_EPOCH_SKIP: u32 = 16; // packets from further epochs than this are dropped
+
+pub const LEAVE_POLL_INTERVAL_MS: i32 = 1000; // how often threads serving network check if it was left
+
+pub const DEFAULT_NETWORK_ADDR: [u8; 4] = [172, 22, 44, 0];
+pub const DEFAULT_PREFIX_LEN: u8 = 24;
+pub const MIN_NETWORK_PREFIX_LEN: u8 = 8; // shorter prefixes would make address search run for too long
 
 pub const DEFAULT_INTERFACE_NAME: &str = "pea0";
+pub const DEFAULT_INTERFACE_PREFIX: &str = "pea"; // next free pea{n} is used when joining more networks
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/pea_2_pea.sock";
+
+pub const EXIT_ROUTE_TABLE: u32 = 3543; // routing table and fwmark used when default traffic goes through exit node
 
 #[repr(u8)]
 #[allow(non_camel_case_types)]
@@ -24,6 +94,7 @@ pub enum ServerMethods {
     REGISTER = 1,
     GET = 2,
     HEARTBEAT = 3, // this also registers addtional clients
+    UPDATE = 4,    // new salt and sockaddr of client after password of network changed
 }
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[allow(non_camel_case_types)]
@@ -33,6 +104,8 @@ pub enum ServerResponse {
     ID_EXISTS = 254,
     ID_DOESNT_EXIST = 253, // both error since sometimes it is the problem that the id exist and somethimes problem is that is doesn't
     IO = 252,              // had to place it here to avoid creating anther enum
+    IP_IN_USE = 251,       // peer already holds requested virtual ip
+    NOT_ON_ALLOWLIST = 250, // peer refused hello, our identity is not member of network
 }
 
 #[allow(non_camel_case_types)]
@@ -43,6 +116,8 @@ pub enum ServerErrorResponses {
     ID_EXISTS,
     ID_DOESNT_EXIST,
     IO(std::io::Error), // IO errors wraper
+    IP_IN_USE,
+    NOT_ON_ALLOWLIST,
 }
 
 impl fmt::Display for ServerErrorResponses {
@@ -52,6 +127,10 @@ impl fmt::Display for ServerErrorResponses {
             ServerErrorResponses::ID_EXISTS => write!(f, "ID is already registered"),
             ServerErrorResponses::ID_DOESNT_EXIST => write!(f, "ID isn't yet registered"),
             ServerErrorResponses::IO(err) => write!(f, "IO error: {}", err),
+            ServerErrorResponses::IP_IN_USE => write!(f, "virtual IP is already in use"),
+            ServerErrorResponses::NOT_ON_ALLOWLIST => {
+                write!(f, "identity is not on allowlist of network")
+            }
         }
     }
 }
@@ -76,6 +155,8 @@ impl ServerErrorResponses {
             ServerErrorResponses::ID_EXISTS => ServerResponse::ID_EXISTS,
             ServerErrorResponses::ID_DOESNT_EXIST => ServerResponse::ID_DOESNT_EXIST,
             ServerErrorResponses::IO(_) => ServerResponse::IO,
+            ServerErrorResponses::IP_IN_USE => ServerResponse::IP_IN_USE,
+            ServerErrorResponses::NOT_ON_ALLOWLIST => ServerResponse::NOT_ON_ALLOWLIST,
         }
     }
 }
@@ -86,9 +167,10 @@ pub enum RegisterRequestDataPositions {
     ENCRYPTED = 1, // this feeld should be 0 if not encrypted
     ID_LEN = 2,
     SOCKADDR_LEN = 3,
-    SALT = 4,
+    PARAMS_LEN = 4,
+    SALT = 5,
     IV = (BLOCK_SIZE as usize + RegisterRequestDataPositions::SALT as usize) as usize,
-    DATA = (BLOCK_SIZE as usize + RegisterRequestDataPositions::IV as usize) as usize, // after this there will be id and sockaddr in string or encrypted form after
+    DATA = (BLOCK_SIZE as usize + RegisterRequestDataPositions::IV as usize) as usize, // after this there will be id, sockaddr in string or encrypted form, network params and hash of update secret of encrypted network
 }
 
 #[allow(non_camel_case_types)]
@@ -100,11 +182,192 @@ pub enum GetRequestDataPositions {
 #[allow(non_camel_case_types)]
 #[repr(usize)]
 pub enum GetResponseDataPositions {
-    ENCRYPTED = 1, // this feeld should be 0 if not encrypted
-    NUM_OF_CLIENTS = 2,
-    SALT = 3,
-    CLIENTS = (BLOCK_SIZE as usize + RegisterRequestDataPositions::SALT as usize) - 1 as usize,
-    // after this there will be blocks of this sturcture: one byte size of sockaddr than there will be IV that is SALT_AND_IV_SIZE long and after that there will be sockaddr this repeats until the end of packet
+    ENCRYPTED = 1,      // this feeld should be 0 if not encrypted
+    NUM_OF_CLIENTS = 2, // u16 big endian
+    PARAMS_LEN = 4,
+    SALT = 5,
+    PARAMS = (BLOCK_SIZE as usize + GetResponseDataPositions::SALT as usize) as usize,
+    // after params there will be blocks of this sturcture: one byte size of sockaddr than there will be IV that is SALT_AND_IV_SIZE long and after that there will be sockaddr this repeats until the end of packet
+}
+
+// network params are stored by registrar as is and returned to joining clients
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+pub enum NetworkParamsPositions {
+    SUBNET = 0,
+    PREFIX_LEN = NetworkParamsPositions::SUBNET as usize + IPV4_SIZE,
+    MODE = NetworkParamsPositions::PREFIX_LEN as usize + 1,
+    COMPRESSION = NetworkParamsPositions::MODE as usize + 1,
+    CIPHER = NetworkParamsPositions::COMPRESSION as usize + 1,
+    KDF = NetworkParamsPositions::CIPHER as usize + 1,
+    KDF_MEMORY = NetworkParamsPositions::KDF as usize + 1, // u32 big endian, KiB
+    KDF_ITERATIONS = NetworkParamsPositions::KDF_MEMORY as usize + 4, // u32 big endian
+    KDF_PARALLELISM = NetworkParamsPositions::KDF_ITERATIONS as usize + 4,
+    END = NetworkParamsPositions::KDF_PARALLELISM as usize + 1, // size of params block, identity key of owner follows when network has allowlist
+}
+pub const OWNED_NETWORK_PARAMS_SIZE: usize =
+    NetworkParamsPositions::END as usize + IDENTITY_KEY_SIZE;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+pub enum NetworkMode {
+    #[default]
+    TUN = 0, // ip packets
+    TAP = 1, // ethernet frames, for games using IPX or raw broadcasts
+}
+
+impl TryFrom<u8> for NetworkMode {
+    type Error = ServerErrorResponses;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == NetworkMode::TUN as u8 => Ok(NetworkMode::TUN),
+            x if x == NetworkMode::TAP as u8 => Ok(NetworkMode::TAP),
+            x => Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "unknown network mode: {}",
+                x
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for NetworkMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkMode::TUN => write!(f, "tun"),
+            NetworkMode::TAP => write!(f, "tap"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+pub enum Compression {
+    #[default]
+    NONE = 0,
+    LZ4 = 1, // fast enough to not slow down games
+}
+
+impl TryFrom<u8> for Compression {
+    type Error = ServerErrorResponses;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Compression::NONE as u8 => Ok(Compression::NONE),
+            x if x == Compression::LZ4 as u8 => Ok(Compression::LZ4),
+            x => Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "unknown compression: {}",
+                x
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Compression::NONE => write!(f, "none"),
+            Compression::LZ4 => write!(f, "lz4"),
+        }
+    }
+}
+
+// how key is derived from password, chosen by creator of network
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+pub enum Kdf {
+    PBKDF2_SHA256 = 0, // cheap to guess, new networks don't use it
+    #[default]
+    ARGON2ID = 1, // memory hard so guessing password offline is expensive
+}
+
+impl TryFrom<u8> for Kdf {
+    type Error = ServerErrorResponses;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == Kdf::PBKDF2_SHA256 as u8 => Ok(Kdf::PBKDF2_SHA256),
+            x if x == Kdf::ARGON2ID as u8 => Ok(Kdf::ARGON2ID),
+            x => Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "unknown key derivation function: {}, network was probably created by newer client",
+                x
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Kdf {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Kdf::PBKDF2_SHA256 => write!(f, "pbkdf2-sha256"),
+            Kdf::ARGON2ID => write!(f, "argon2id"),
+        }
+    }
+}
+
+// cipher used for PACKET in encrypted networks, control messages stay on AES_256_CBC
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default, serde::Deserialize)]
+#[serde(try_from = "String")]
+#[allow(non_camel_case_types)]
+#[repr(u8)]
+pub enum CipherSuite {
+    AES_256_CBC = 0,       // unauthenticated, altered packets are not detected
+    CHACHA20_POLY1305 = 1, // faster on cpus without aes instructions
+    #[default]
+    AES_256_GCM = 2,
+}
+
+impl TryFrom<u8> for CipherSuite {
+    type Error = ServerErrorResponses;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            x if x == CipherSuite::AES_256_CBC as u8 => Ok(CipherSuite::AES_256_CBC),
+            x if x == CipherSuite::CHACHA20_POLY1305 as u8 => Ok(CipherSuite::CHACHA20_POLY1305),
+            x if x == CipherSuite::AES_256_GCM as u8 => Ok(CipherSuite::AES_256_GCM),
+            x => Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "unknown cipher suite: {}, network was probably created by newer client",
+                x
+            ))),
+        }
+    }
+}
+
+impl std::str::FromStr for CipherSuite {
+    type Err = ServerErrorResponses;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-256-cbc" => Ok(CipherSuite::AES_256_CBC),
+            "chacha20-poly1305" => Ok(CipherSuite::CHACHA20_POLY1305),
+            "aes-256-gcm" => Ok(CipherSuite::AES_256_GCM),
+            _ => Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "unknown cipher suite: {}, use chacha20-poly1305, aes-256-gcm or aes-256-cbc",
+                s
+            ))),
+        }
+    }
+}
+
+impl TryFrom<String> for CipherSuite {
+    type Error = ServerErrorResponses;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for CipherSuite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CipherSuite::AES_256_CBC => write!(f, "aes-256-cbc"),
+            CipherSuite::CHACHA20_POLY1305 => write!(f, "chacha20-poly1305"),
+            CipherSuite::AES_256_GCM => write!(f, "aes-256-gcm"),
+        }
+    }
 }
 
 #[allow(non_camel_case_types)]
@@ -116,21 +379,86 @@ pub enum HeartBeatRequestDataPositions {
     DATA = (HeartBeatRequestDataPositions::IV as usize + BLOCK_SIZE as usize) as usize, // first ID than sockaddr
 }
 
+// registrar takes update only from address of client it knows, new salt also needs
+// update secret of registration, members that only follow new salt leave secret zeroed
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+pub enum UpdateRequestDataPositions {
+    ID_LEN = 1,
+    SOCKADDR_LEN = 2,
+    SALT = 3,
+    IV = UpdateRequestDataPositions::SALT as usize + BLOCK_SIZE,
+    SECRET = UpdateRequestDataPositions::IV as usize + BLOCK_SIZE,
+    NEXT_SECRET_HASH = UpdateRequestDataPositions::SECRET as usize + UPDATE_SECRET_SIZE, // registrar wants its secret next time
+    DATA = UpdateRequestDataPositions::NEXT_SECRET_HASH as usize + UPDATE_SECRET_SIZE, // first ID than sockaddr
+}
+
 #[allow(non_camel_case_types)]
 #[repr(u8)]
 pub enum P2PMethods {
     PEER_QUERY = 20,   // responds with its private ip
     PEER_HELLO = 21,   // sends private ip encrypted if on
     PEER_GOODBYE = 22, // sends private ip encrypted if on
-    PACKET = 23,       // sends IP packet encrypted if on
+    PACKET = 23,       // sends IP packet or ethernet frame in tap mode encrypted if on
     NEW_CLIENT_NOTIFY = 24,
     DO_NOTHING = 25,
+    PMTU_PROBE = 26,        // padded to probed size, peer answers with PMTU_PROBE_ACK
+    PMTU_PROBE_ACK = 27,    // carries size of probe that got through
+    PACKET_COMPRESSED = 28, // same as PACKET but data was compressed before encryption
+    ALLOWLIST = 29,         // member identity keys signed by owner, sealed like PACKET
+    NETWORK_KEY = 30,       // salt and key derived from new password, sealed like PACKET
 }
+#[allow(non_camel_case_types)]
 #[repr(usize)]
 pub enum P2PStandardDataPositions {
-    // sould apply to all P2P Methods
-    IV = 1,
+    // sould apply to all P2P Methods except one byte acknowledgments and DO_NOTHING
+    NET_TAG = 1, // identifies network packet belongs to when client is in multiple networks
+    IV = P2PStandardDataPositions::NET_TAG as usize + NET_TAG_SIZE,
     DATA = P2PStandardDataPositions::IV as usize + BLOCK_SIZE,
 }
 
+// signature covers net tag and everything before it
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+pub enum AllowlistPositions {
+    VERSION = 0, // u64 big endian, newer list replaces older one
+    MEMBERS = AllowlistPositions::VERSION as usize + 8, // identity keys, signature of owner follows them
+}
+
+// payload of answer to PEER_HELLO, sealed by handshake on aead networks, on others it is
+// encrypted with network key and iv is in front of it
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+pub enum HelloAnswerPositions {
+    UPDATE_SECRET = 0, // zeroed when peer doesn't know it
+    ALLOWLIST = HelloAnswerPositions::UPDATE_SECRET as usize + UPDATE_SECRET_SIZE, // only on aead networks, empty when network has none
+}
+
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+pub enum NetworkKeyPositions {
+    SALT = 0,
+    KEY = NetworkKeyPositions::SALT as usize + BLOCK_SIZE,
+    UPDATE_SECRET = NetworkKeyPositions::KEY as usize + 32,
+    END = NetworkKeyPositions::UPDATE_SECRET as usize + UPDATE_SECRET_SIZE,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+pub enum PmtuProbeDataPositions {
+    NET_TAG = 1,
+    SIZE = PmtuProbeDataPositions::NET_TAG as usize + NET_TAG_SIZE, // u16 big endian size of probe as ip packet on underlay
+    END = PmtuProbeDataPositions::SIZE as usize + 2, // ack ends here, probe is padded after it
+}
+
+// IV of PACKET in networks with AEAD cipher, nonce is sender and its counter so it never repeats
+#[allow(non_camel_case_types)]
+#[repr(usize)]
+pub enum PacketIvPositions {
+    SENDER = 0,                                               // virtual ip of sender
+    COUNTER = PacketIvPositions::SENDER as usize + IPV4_SIZE, // u64 big endian, grows with every packet
+    EPOCH = PacketIvPositions::COUNTER as usize + 8, // u32 big endian, epoch of session key packet is sealed with
+    END = PacketIvPositions::EPOCH as usize + 4,
+}
+
 pub mod shared;
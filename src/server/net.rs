@@ -72,23 +72,40 @@ pub async fn handle_request(
                 },
             }
             .cloned();
+            // most recently seen clients first, the rest doesn't fit into one datagram,
+            // they learn about joining peer from notification when it heartbeats
+            let mut clients: Vec<&types::Client> = registration.clients.iter().collect();
+            clients.sort_by_key(|c| std::cmp::Reverse(c.last_heart_beat));
+            let mut response_len: usize =
+                GetResponseDataPositions::PARAMS as usize + registration.params.len();
+            let count: usize = clients
+                .iter()
+                .take(u16::MAX as usize)
+                .take_while(|c| {
+                    response_len += 1 + BLOCK_SIZE + c.client_sock_addr.len();
+                    response_len <= UDP_PAYLOAD_MAX
+                })
+                .count();
+            clients.truncate(count);
+
             let mut send_vec: Vec<u8> = Vec::with_capacity(
                 1/*initial status byte */ +
-                GetResponseDataPositions::SALT as usize + /*2 times one for SALT and other for first IV*/ 2*BLOCK_SIZE as usize + 20, /*magic number guess for how long is encrypted residencial ipv4 with port long */
+                GetResponseDataPositions::PARAMS as usize + registration.params.len() + /*first IV*/ BLOCK_SIZE + 20, /*magic number guess for how long is encrypted residencial ipv4 with port long */
             ); // use vector to handle many clients
 
             send_vec.push(ServerMethods::GET as u8); // this means success
 
             // lets start serializing
             send_vec.push(registration.encrypted as u8);
-            send_vec.push(registration.clients.len() as u8);
-            // todo!("make sure it allows only 255 client per network max");
+            send_vec.extend_from_slice(&(clients.len() as u16).to_be_bytes());
+            send_vec.push(registration.params.len() as u8);
             send_vec.extend_from_slice(&registration.salt);
+            send_vec.extend_from_slice(&registration.params);
 
             #[cfg(debug_assertions)]
             eprintln!("Found {} clients", registration.clients.len());
 
-            registration.clients.iter().for_each(|client| {
+            clients.iter().for_each(|client| {
                 #[cfg(debug_assertions)]
                 eprintln!(
                     "Client:\nIV: {}\nSockAddr: {}",
@@ -112,21 +129,6 @@ pub async fn handle_request(
                 send_vec.extend_from_slice(&client.client_sock_addr);
             });
 
-            if send_vec.len() > UDP_BUFFER_SIZE {
-                send_general_error_to_client(
-                    src,
-                    std::io::Error::new(
-                        std::io::ErrorKind::FileTooLarge,
-                        format!(
-                            "Max number of clients reached count: {}",
-                            registration.clients.len()
-                        ),
-                    ),
-                    socket,
-                );
-                return;
-            }
-
             send_with_count(socket, &src, &send_vec).await;
         }
         x if x == ServerMethods::REGISTER as u8 => {
@@ -149,6 +151,22 @@ pub async fn handle_request(
                 return;
             };
 
+            let params_len: usize = buf[RegisterRequestDataPositions::PARAMS_LEN as usize] as usize;
+
+            if RegisterRequestDataPositions::DATA as usize
+                + id_len as usize
+                + sock_addr_len as usize
+                + params_len
+                > data_len
+            {
+                send_general_error_to_client(
+                    src,
+                    std::io::Error::new(std::io::ErrorKind::InvalidData, "Register request is too short"),
+                    socket,
+                );
+                return;
+            }
+
             let net_id: String = match std::str::from_utf8(
                 &buf[(RegisterRequestDataPositions::DATA as usize)
                     ..(id_len as usize) + (RegisterRequestDataPositions::DATA as usize)],
@@ -198,6 +216,38 @@ pub async fn handle_request(
                     + id_len as usize
                     + (sock_addr_len as usize)]
                 .to_vec();
+            let params: Vec<u8> = buf[RegisterRequestDataPositions::DATA as usize
+                + id_len as usize
+                + sock_addr_len as usize
+                ..RegisterRequestDataPositions::DATA as usize
+                    + id_len as usize
+                    + sock_addr_len as usize
+                    + params_len]
+                .to_vec();
+            // members reveal secret of this hash when they change salt of encrypted network
+            let secret_hash_start: usize = RegisterRequestDataPositions::DATA as usize
+                + id_len as usize
+                + sock_addr_len as usize
+                + params_len;
+            let update_secret_hash: Option<[u8; UPDATE_SECRET_SIZE]> = match encrypted {
+                true => match buf[..data_len]
+                    .get(secret_hash_start..secret_hash_start + UPDATE_SECRET_SIZE)
+                {
+                    Some(h) => Some(h.try_into().unwrap()),
+                    None => {
+                        send_general_error_to_client(
+                            src,
+                            std::io::Error::new(
+                                std::io::ErrorKind::InvalidData,
+                                "Register request is missing hash of update secret",
+                            ),
+                            socket,
+                        );
+                        return;
+                    }
+                },
+                false => None,
+            };
 
             #[cfg(debug_assertions)]
             eprintln!(
@@ -232,7 +282,9 @@ pub async fn handle_request(
                 chrono::Utc::now().timestamp(),
                 salt,
                 iv,
-                src
+                src,
+                params.clone(),
+                update_secret_hash
             );}),
                 None => {registration_vector.push(types::Registration::new(
                 net_id,
@@ -241,7 +293,9 @@ pub async fn handle_request(
                 chrono::Utc::now().timestamp(),
                 salt,
                 iv,
-                src
+                src,
+                params,
+                update_secret_hash
             ));},
             };
 
@@ -323,6 +377,7 @@ pub async fn handle_request(
                         None => {// add new client if it isn't found
                             r.clients.par_iter().for_each(|c| {let mut send_buf: Box<[u8]> = vec![0; P2PStandardDataPositions::DATA as usize + sock_addr_len as usize].into();
                             send_buf[0] = P2PMethods::NEW_CLIENT_NOTIFY as u8;
+                            send_buf[P2PStandardDataPositions::NET_TAG as usize..P2PStandardDataPositions::NET_TAG as usize + NET_TAG_SIZE].copy_from_slice(&shared::net::net_tag(&r.net_id));
                             send_buf[P2PStandardDataPositions::IV as usize..P2PStandardDataPositions::IV as usize+ BLOCK_SIZE].copy_from_slice(&iv);
                             send_buf[P2PStandardDataPositions::DATA as usize..P2PStandardDataPositions::DATA as usize + sock_addr_len as usize].copy_from_slice(&sock_addr);
                             let sock_clone = socket.clone();
@@ -340,6 +395,102 @@ pub async fn handle_request(
             send_with_count(socket, &src, &[ServerMethods::HEARTBEAT as u8]).await;
             return;
         }
+        x if x == ServerMethods::UPDATE as u8 => {
+            #[cfg(debug_assertions)]
+            println!("UPDATE method");
+
+            let id_len: usize = buf[UpdateRequestDataPositions::ID_LEN as usize] as usize;
+            let sock_addr_len: usize =
+                buf[UpdateRequestDataPositions::SOCKADDR_LEN as usize] as usize;
+            if id_len == 0
+                || sock_addr_len == 0
+                || UpdateRequestDataPositions::DATA as usize + id_len + sock_addr_len > data_len
+            {
+                send_general_error_to_client(
+                    src,
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Update request is too short",
+                    ),
+                    socket,
+                );
+                return;
+            }
+
+            let net_id: String = match std::str::from_utf8(
+                &buf[UpdateRequestDataPositions::DATA as usize
+                    ..UpdateRequestDataPositions::DATA as usize + id_len],
+            ) {
+                Ok(s) => s.to_string(),
+                Err(e) => {
+                    eprint!("id to utf-8 failed: {}", e);
+                    utils::send_general_error_to_client(src, e, socket);
+                    return;
+                }
+            };
+
+            let salt: [u8; BLOCK_SIZE] = buf[UpdateRequestDataPositions::SALT as usize
+                ..UpdateRequestDataPositions::SALT as usize + BLOCK_SIZE]
+                .try_into()
+                .unwrap();
+            let iv: [u8; BLOCK_SIZE] = buf[UpdateRequestDataPositions::IV as usize
+                ..UpdateRequestDataPositions::IV as usize + BLOCK_SIZE]
+                .try_into()
+                .unwrap();
+            let sock_addr: Vec<u8> = buf[UpdateRequestDataPositions::DATA as usize + id_len
+                ..UpdateRequestDataPositions::DATA as usize + id_len + sock_addr_len]
+                .to_vec();
+            let secret: &[u8] = &buf[UpdateRequestDataPositions::SECRET as usize
+                ..UpdateRequestDataPositions::NEXT_SECRET_HASH as usize];
+            let next_secret_hash: [u8; UPDATE_SECRET_SIZE] = buf
+                [UpdateRequestDataPositions::NEXT_SECRET_HASH as usize
+                    ..UpdateRequestDataPositions::DATA as usize]
+                .try_into()
+                .unwrap();
+
+            let registration = match registration_vector
+                .iter()
+                .find(|elem| elem.map(|s| s.net_id == net_id && !s.invalid && s.encrypted))
+            {
+                Some(reg) => reg,
+                None => {
+                    futures::executor::block_on(send_with_count(
+                        socket,
+                        &src,
+                        &[ServerResponse::ID_DOESNT_EXIST as u8],
+                    ));
+                    return;
+                }
+            };
+            // only member that heartbeats from this address can change registration and only
+            // member that knows update secret can change salt, others just follow it
+            let mut refusal: Option<&str> = None;
+            registration.update(|r| {
+                let Some(i) = r.clients.iter().position(|c| c.src == src) else {
+                    refusal = Some("Not member of network");
+                    return;
+                };
+                if let Err(e) = r.change_salt(salt, secret, next_secret_hash) {
+                    refusal = Some(e);
+                    return;
+                }
+                let c = &mut r.clients[i];
+                c.client_sock_addr = sock_addr.clone();
+                c.iv = iv;
+                c.last_heart_beat = chrono::Utc::now().timestamp();
+            });
+            if let Some(refusal) = refusal {
+                send_general_error_to_client(
+                    src,
+                    std::io::Error::new(std::io::ErrorKind::PermissionDenied, refusal),
+                    socket,
+                );
+                return;
+            }
+            #[cfg(debug_assertions)]
+            println!("registration updated");
+            send_with_count(socket, &src, &[ServerMethods::UPDATE as u8]).await;
+        }
         _ => {
             println!(
                 "Warning!: client: {} called Unknown method: 0x{:02x}",
@@ -3,10 +3,8 @@ use pea_2_pea::*;
 #[derive(Clone)]
 #[readonly::make]
 pub struct Client {
-    #[readonly]
-    pub client_sock_addr: Vec<u8>,
+    pub client_sock_addr: Vec<u8>, // replaced with update when password of network changes
     pub last_heart_beat: i64,
-    #[readonly]
     pub iv: [u8; BLOCK_SIZE as usize],
     #[readonly]
     pub src: std::net::SocketAddr,
@@ -38,12 +36,17 @@ pub struct Registration {
 
     #[readonly]
     pub encrypted: bool,
-    #[readonly]
+    // replaced with update when password of network changes
     pub salt: [u8; BLOCK_SIZE as usize],
+    #[readonly]
+    pub params: Vec<u8>, // network params, registrar does not interpret them
+    // salt is changed only by update with secret of this hash, None for unencrypted networks
+    update_secret_hash: Option<[u8; UPDATE_SECRET_SIZE]>,
     pub invalid: bool,
 }
 
 impl Registration {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         net_id: String,
         client_addr: Vec<u8>,
@@ -52,6 +55,8 @@ impl Registration {
         salt: Option<[u8; BLOCK_SIZE as usize]>,
         iv: Option<[u8; BLOCK_SIZE as usize]>,
         src: std::net::SocketAddr,
+        params: Vec<u8>,
+        update_secret_hash: Option<[u8; UPDATE_SECRET_SIZE]>,
     ) -> Self {
         Registration {
             net_id,
@@ -64,7 +69,99 @@ impl Registration {
             encrypted,
             last_heart_beat: heart_beat,
             salt: salt.unwrap_or([0; BLOCK_SIZE as usize]),
+            params,
+            update_secret_hash,
             invalid: false,
         }
     }
+    /// salt is changed by member that reveals update secret of registration, hash of next
+    /// secret replaces old one so revealed secret can't be used again
+    pub fn change_salt(
+        &mut self,
+        salt: [u8; BLOCK_SIZE],
+        secret: &[u8],
+        next_secret_hash: [u8; UPDATE_SECRET_SIZE],
+    ) -> Result<(), &'static str> {
+        if self.salt == salt {
+            return Ok(()); // member follows salt that was changed already
+        }
+        match self.update_secret_hash {
+            Some(h) if h == shared::crypto::update_secret_hash(secret) => {
+                self.update_secret_hash = Some(next_secret_hash);
+                self.salt = salt;
+                Ok(())
+            }
+            _ => Err("Update secret doesn't match registration"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registration(secret: &[u8; UPDATE_SECRET_SIZE]) -> Registration {
+        Registration::new(
+            "net".to_owned(),
+            vec![1, 2, 3],
+            true,
+            0,
+            Some([1; BLOCK_SIZE]),
+            Some([2; BLOCK_SIZE]),
+            "10.0.0.1:3000".parse().unwrap(),
+            Vec::new(),
+            Some(shared::crypto::update_secret_hash(secret)),
+        )
+    }
+
+    #[test]
+    fn salt_changes_with_secret_only_once() {
+        let mut r = registration(&[7; UPDATE_SECRET_SIZE]);
+        let next = shared::crypto::update_secret_hash(&[8; UPDATE_SECRET_SIZE]);
+        assert!(
+            r.change_salt([3; BLOCK_SIZE], &[7; UPDATE_SECRET_SIZE], next)
+                .is_ok()
+        );
+        assert_eq!(r.salt, [3; BLOCK_SIZE]);
+        // seen secret is spent
+        assert!(
+            r.change_salt([4; BLOCK_SIZE], &[7; UPDATE_SECRET_SIZE], next)
+                .is_err()
+        );
+        assert!(
+            r.change_salt([4; BLOCK_SIZE], &[8; UPDATE_SECRET_SIZE], next)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn wrong_secret_is_refused() {
+        let mut r = registration(&[7; UPDATE_SECRET_SIZE]);
+        let next = shared::crypto::update_secret_hash(&[8; UPDATE_SECRET_SIZE]);
+        assert!(
+            r.change_salt([3; BLOCK_SIZE], &[6; UPDATE_SECRET_SIZE], next)
+                .is_err()
+        );
+        assert!(
+            r.change_salt([3; BLOCK_SIZE], &[0; UPDATE_SECRET_SIZE], next)
+                .is_err()
+        );
+        assert_eq!(r.salt, [1; BLOCK_SIZE]);
+        // following salt that is already set needs no secret
+        assert!(
+            r.change_salt([1; BLOCK_SIZE], &[0; UPDATE_SECRET_SIZE], next)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn unencrypted_registration_keeps_salt() {
+        let mut r = registration(&[7; UPDATE_SECRET_SIZE]);
+        r.update_secret_hash = None;
+        let next = shared::crypto::update_secret_hash(&[8; UPDATE_SECRET_SIZE]);
+        assert!(
+            r.change_salt([3; BLOCK_SIZE], &[7; UPDATE_SECRET_SIZE], next)
+                .is_err()
+        );
+    }
 }
@@ -0,0 +1,52 @@
+use crate::IP_BUFFER_SIZE;
+
+/// compress packet with lz4, None if it doesn't get smaller and should be sent as is
+pub fn compress(data: &[u8]) -> Option<Vec<u8>> {
+    let compressed = lz4_flex::block::compress(data);
+    match compressed.len() < data.len() {
+        true => Some(compressed),
+        false => None,
+    }
+}
+
+/// decompress packet, fails if it would not fit into ip buffer
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Ok(lz4_flex::block::decompress(data, IP_BUFFER_SIZE)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let packet: Vec<u8> = b"pea_2_pea ".iter().cycle().take(1400).copied().collect();
+        let compressed = compress(&packet).unwrap();
+        assert!(compressed.len() < packet.len());
+        assert_eq!(decompress(&compressed).unwrap(), packet);
+    }
+
+    #[test]
+    fn incompressible_is_sent_as_is() {
+        // xorshift, no repeats lz4 could find
+        let mut x: u32 = 0x9e3779b9;
+        let packet: Vec<u8> = (0..1400)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        assert!(compress(&packet).is_none());
+        assert!(compress(&[0]).is_none());
+    }
+
+    #[test]
+    fn oversized_packet_is_refused() {
+        let compressed = lz4_flex::block::compress(&vec![0u8; IP_BUFFER_SIZE + 1]);
+        assert!(decompress(&compressed).is_err());
+        let compressed = lz4_flex::block::compress(&vec![0u8; IP_BUFFER_SIZE]);
+        assert_eq!(decompress(&compressed).unwrap().len(), IP_BUFFER_SIZE);
+    }
+}
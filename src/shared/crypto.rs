@@ -1,9 +1,21 @@
 use aes::Aes256;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
 use cbc::cipher::{BlockDecryptMut, BlockEncryptMut, KeyIvInit, block_padding::Pkcs7};
 use cbc::{Decryptor, Encryptor};
-use hmac::Hmac;
+use chacha20poly1305::ChaCha20Poly1305;
+use hmac::{Hmac, Mac};
 use pbkdf2::pbkdf2;
-use sha2::Sha256;
+use sha2::{Digest, Sha256};
+use spake2::{Ed25519Group, Spake2};
+use zeroize::Zeroizing;
+
+use crate::{
+    AEAD_NONCE_SIZE, AEAD_TAG_SIZE, ARGON2_ITERATIONS, ARGON2_ITERATIONS_MAX, ARGON2_MEMORY_KIB,
+    ARGON2_MEMORY_MAX_KIB, ARGON2_PARALLELISM, ARGON2_PARALLELISM_MAX, CipherSuite,
+    IDENTITY_KEY_SIZE, Kdf, NOISE_ANSWER_OVERHEAD, NOISE_HELLO_OVERHEAD, NOISE_PATTERN,
+    PBKDF2_ITERATIONS, PBKDF2_ITERATIONS_MAX, SIGNATURE_SIZE, UPDATE_SECRET_SIZE,
+};
 
 // they are used
 #[allow(dead_code)]
@@ -11,21 +23,120 @@ type Aes256CbcEnc = Encryptor<Aes256>;
 #[allow(dead_code)]
 type Aes256CbcDec = Decryptor<Aes256>;
 
-pub fn derive_key_from_password(password: &[u8], salt: &[u8]) -> [u8; 32] {
-    let mut key = [0u8; 32];
-    let _ = pbkdf2::<Hmac<Sha256>>(password, salt, 10000, &mut key);
+/// key that is wiped from memory when it is dropped
+pub type SecretKey = Zeroizing<[u8; 32]>;
+
+pub fn derive_key_from_password(password: &[u8], salt: &[u8]) -> SecretKey {
+    let mut key = SecretKey::default();
+    let _ = pbkdf2::<Hmac<Sha256>>(password, salt, PBKDF2_ITERATIONS, &mut *key);
     key
 }
 
+/// key derivation function and its cost, stored with network params so everybody derives same key
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct KdfParams {
+    pub kdf: Kdf,
+    pub memory: u32, // KiB, only used by argon2id
+    pub iterations: u32,
+    pub parallelism: u8, // only used by argon2id
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            kdf: Kdf::ARGON2ID,
+            memory: ARGON2_MEMORY_KIB,
+            iterations: ARGON2_ITERATIONS,
+            parallelism: ARGON2_PARALLELISM,
+        }
+    }
+}
+
+impl std::fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.kdf {
+            Kdf::PBKDF2_SHA256 => write!(f, "{}, {} iterations", self.kdf, self.iterations),
+            Kdf::ARGON2ID => write!(
+                f,
+                "{}, {} KiB, {} iterations, {} lanes",
+                self.kdf, self.memory, self.iterations, self.parallelism
+            ),
+        }
+    }
+}
+
+pub fn derive_key(
+    password: &[u8],
+    salt: &[u8],
+    params: &KdfParams,
+) -> Result<SecretKey, Box<dyn std::error::Error>> {
+    // params come from registrar, it could make joining clients spin forever
+    let too_much = |what: &str, wanted: u32, max: u32| {
+        format!(
+            "network wants {} {} for key derivation, more than {} allowed",
+            wanted, what, max
+        )
+    };
+    let mut key = SecretKey::default();
+    match params.kdf {
+        Kdf::PBKDF2_SHA256 => {
+            if params.iterations > PBKDF2_ITERATIONS_MAX {
+                return Err(
+                    too_much("iterations", params.iterations, PBKDF2_ITERATIONS_MAX).into(),
+                );
+            }
+            pbkdf2::<Hmac<Sha256>>(password, salt, params.iterations, &mut *key)
+                .map_err(|e| format!("PBKDF2 error: {}", e))?
+        }
+        Kdf::ARGON2ID => {
+            if params.memory > ARGON2_MEMORY_MAX_KIB {
+                return Err(too_much("KiB", params.memory, ARGON2_MEMORY_MAX_KIB).into());
+            }
+            if params.iterations > ARGON2_ITERATIONS_MAX {
+                return Err(
+                    too_much("iterations", params.iterations, ARGON2_ITERATIONS_MAX).into(),
+                );
+            }
+            if params.parallelism > ARGON2_PARALLELISM_MAX {
+                return Err(too_much(
+                    "lanes",
+                    params.parallelism as u32,
+                    ARGON2_PARALLELISM_MAX as u32,
+                )
+                .into());
+            }
+            let argon2_params = argon2::Params::new(
+                params.memory,
+                params.iterations,
+                params.parallelism as u32,
+                Some(key.len()),
+            )
+            .map_err(|e| format!("Argon2 error: {}", e))?;
+            argon2::Argon2::new(
+                argon2::Algorithm::Argon2id,
+                argon2::Version::V0x13,
+                argon2_params,
+            )
+            .hash_password_into(password, salt, &mut *key)
+            .map_err(|e| format!("Argon2 error: {}", e))?;
+        }
+    }
+    Ok(key)
+}
+
 /// Encrypt using AES-256-CBC
-pub fn encrypt(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+pub fn encrypt(
+    key: &[u8; 32],
+    iv: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     let cipher = Aes256CbcEnc::new_from_slices(key, iv)?;
     Ok(cipher.encrypt_padded_vec_mut::<Pkcs7>(data))
 }
 
 /// Decrypt using AES-256-CBC
 pub fn decrypt(
-    key: &[u8],
+    key: &[u8; 32],
     iv: &[u8],
     ciphertext: &[u8],
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
@@ -36,6 +147,357 @@ pub fn decrypt(
     }
 }
 
+fn seal_with<C: AeadInPlace + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    header: &[u8],
+    data: &mut [u8],
+) -> Result<[u8; AEAD_TAG_SIZE], Box<dyn std::error::Error>> {
+    let cipher = C::new_from_slice(key)?;
+    let tag = cipher
+        .encrypt_in_place_detached(nonce[..AEAD_NONCE_SIZE].into(), header, data)
+        .map_err(|e| format!("Encryption error: {}", e))?;
+    Ok(tag.as_slice().try_into()?)
+}
+
+fn open_with<C: AeadInPlace + KeyInit>(
+    key: &[u8],
+    nonce: &[u8],
+    header: &[u8],
+    data: &mut [u8],
+    tag: &[u8],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cipher = C::new_from_slice(key)?;
+    cipher
+        .decrypt_in_place_detached(nonce[..AEAD_NONCE_SIZE].into(), header, data, tag.into())
+        .map_err(|_| "Decryption error: packet was modified or encrypted with different key".into())
+}
+
+/// Encrypt data in place with AEAD suite, header is authenticated but left readable
+/// returns tag that has to be appended after data
+pub fn seal_in_place(
+    suite: CipherSuite,
+    key: &[u8; 32],
+    nonce: &[u8],
+    header: &[u8],
+    data: &mut [u8],
+) -> Result<[u8; AEAD_TAG_SIZE], Box<dyn std::error::Error>> {
+    match suite {
+        CipherSuite::CHACHA20_POLY1305 => seal_with::<ChaCha20Poly1305>(key, nonce, header, data),
+        CipherSuite::AES_256_GCM => seal_with::<Aes256Gcm>(key, nonce, header, data),
+        CipherSuite::AES_256_CBC => Err("AES-256-CBC is not AEAD suite".into()),
+    }
+}
+
+/// Decrypt data ending with tag in place, returns lenght of plaintext
+pub fn open_in_place(
+    suite: CipherSuite,
+    key: &[u8; 32],
+    nonce: &[u8],
+    header: &[u8],
+    data: &mut [u8],
+) -> Result<usize, Box<dyn std::error::Error>> {
+    let len = data
+        .len()
+        .checked_sub(AEAD_TAG_SIZE)
+        .ok_or("Decryption error: packet is shorter than tag")?;
+    let (data, tag) = data.split_at_mut(len);
+    match suite {
+        CipherSuite::CHACHA20_POLY1305 => {
+            open_with::<ChaCha20Poly1305>(key, nonce, header, data, tag)
+        }
+        CipherSuite::AES_256_GCM => open_with::<Aes256Gcm>(key, nonce, header, data, tag),
+        CipherSuite::AES_256_CBC => Err("AES-256-CBC is not AEAD suite".into()),
+    }?;
+    Ok(len)
+}
+
+/// what registrar knows network by in privacy mode, name can't be read back from it and same name
+/// with other password gets other slot, argon2id makes guessing names and passwords slow,
+/// but registrar can still try them offline against it
+pub fn hidden_network_id(
+    net_id: &str,
+    password: &[u8],
+) -> Result<String, Box<dyn std::error::Error>> {
+    // salt has to be known before registrar is asked, so it comes from name itself
+    let salt = Sha256::digest([&b"pea_2_pea network id "[..], net_id.as_bytes()].concat());
+    let hash = derive_key(password, &salt[..16], &KdfParams::default())?;
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// registrar keeps only hash of update secret, secret itself goes on the wire once when salt
+/// changes and hash of next one replaces it
+pub fn update_secret_hash(secret: &[u8]) -> [u8; UPDATE_SECRET_SIZE] {
+    Sha256::digest(secret).into()
+}
+
+/// keys of one peer pair agreed on in PEER_HELLO, each direction has its own
+#[derive(Clone)]
+pub struct SessionKeys {
+    pub send: SecretKey,
+    pub recv: SecretKey,
+}
+
+/// session key of next epoch, old one can't be derived back from it so packets
+/// recorded before rekeying stay safe when key leaks later
+pub fn ratchet_key(key: &[u8; 32]) -> SecretKey {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("hmac takes key of any size");
+    mac.update(b"pea_2_pea rekey");
+    SecretKey::new(mac.finalize().into_bytes().into())
+}
+
+/// keypair of client kept across restarts, members of network with allowlist are known by it
+pub struct Identity {
+    key: ed25519_dalek::SigningKey,
+}
+
+impl Identity {
+    /// read secret key from file, new one is generated and saved when file doesn't exist
+    pub fn load_or_create(path: &std::path::Path) -> std::io::Result<Self> {
+        let secret: SecretKey = match std::fs::read(path).map(Zeroizing::new) {
+            Ok(bytes) => SecretKey::new(bytes.as_slice().try_into().map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("{} is not identity key file", path.display()),
+                )
+            })?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let mut secret = SecretKey::default();
+                rand::RngCore::fill_bytes(&mut rand::rng(), &mut *secret);
+                if let Some(dir) = path.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                let mut options = std::fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                std::io::Write::write_all(&mut options.open(path)?, &*secret)?;
+                secret
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(Identity {
+            key: ed25519_dalek::SigningKey::from_bytes(&secret),
+        })
+    }
+
+    pub fn public(&self) -> IdentityKey {
+        IdentityKey(self.key.verifying_key().to_bytes())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_SIZE] {
+        ed25519_dalek::Signer::sign(&self.key, message).to_bytes()
+    }
+
+    // same key converted to x25519 so handshake proves identity
+    fn noise_private(&self) -> SecretKey {
+        SecretKey::new(self.key.to_scalar_bytes())
+    }
+}
+
+/// public key of identity, hex encoded in config and on command line
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct IdentityKey(pub [u8; IDENTITY_KEY_SIZE]);
+
+impl IdentityKey {
+    pub fn verify(&self, message: &[u8], signature: &[u8; SIGNATURE_SIZE]) -> bool {
+        match ed25519_dalek::VerifyingKey::from_bytes(&self.0) {
+            Ok(key) => key
+                .verify_strict(message, &ed25519_dalek::Signature::from_bytes(signature))
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    /// short form for people comparing keys, first half of sha256 of key
+    pub fn fingerprint(&self) -> String {
+        Sha256::digest(self.0)[..16]
+            .chunks(2)
+            .map(|c| format!("{:02x}{:02x}", c[0], c[1]))
+            .collect::<Vec<String>>()
+            .join(":")
+    }
+
+    fn noise_public(&self) -> Result<[u8; 32], Box<dyn std::error::Error>> {
+        Ok(ed25519_dalek::VerifyingKey::from_bytes(&self.0)?
+            .to_montgomery()
+            .to_bytes())
+    }
+}
+
+impl std::fmt::Display for IdentityKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+impl std::str::FromStr for IdentityKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid identity key: {}, expected 64 hex digits", s);
+        if s.len() != IDENTITY_KEY_SIZE * 2 || !s.is_ascii() {
+            return Err(invalid());
+        }
+        let mut key = [0u8; IDENTITY_KEY_SIZE];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        match ed25519_dalek::VerifyingKey::from_bytes(&key) {
+            Ok(_) => Ok(IdentityKey(key)),
+            Err(_) => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for IdentityKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+fn noise_builder<'a>(
+    psk: &'a [u8; 32],
+    prologue: &'a [u8],
+    private: &'a [u8; 32],
+) -> Result<snow::Builder<'a>, Box<dyn std::error::Error>> {
+    // prologue binds handshake to network so it can't be moved to other one
+    Ok(snow::Builder::new(NOISE_PATTERN.parse()?)
+        .psk(2, psk)?
+        .prologue(prologue)?
+        .local_private_key(private)?)
+}
+
+/// first handshake message with our identity key and encrypted payload, peer's identity is
+/// known from query, state is needed to read answer of peer
+pub fn handshake_initiate(
+    psk: &[u8; 32],
+    prologue: &[u8],
+    identity: &Identity,
+    peer: &IdentityKey,
+    payload: &[u8],
+) -> Result<(snow::HandshakeState, Vec<u8>), Box<dyn std::error::Error>> {
+    let private = identity.noise_private();
+    let remote = peer.noise_public()?;
+    let mut state = noise_builder(psk, prologue, &private)?
+        .remote_public_key(&remote)?
+        .build_initiator()?;
+    let mut message = vec![0u8; IDENTITY_KEY_SIZE + payload.len() + NOISE_HELLO_OVERHEAD];
+    let len = state.write_message(&[&identity.public().0[..], payload].concat(), &mut message)?;
+    message.truncate(len);
+    Ok((state, message))
+}
+
+/// read first handshake message of peer, returns identity it proved and its payload
+/// with state to answer with
+pub fn handshake_respond(
+    psk: &[u8; 32],
+    prologue: &[u8],
+    identity: &Identity,
+    message: &[u8],
+) -> Result<(snow::HandshakeState, IdentityKey, Vec<u8>), Box<dyn std::error::Error>> {
+    let private = identity.noise_private();
+    let mut state = noise_builder(psk, prologue, &private)?.build_responder()?;
+    let mut payload = vec![0u8; message.len()];
+    let len = state.read_message(message, &mut payload)?;
+    if len < IDENTITY_KEY_SIZE {
+        return Err("hello without identity key".into());
+    }
+    let peer = IdentityKey(payload[..IDENTITY_KEY_SIZE].try_into().unwrap());
+    // static key of handshake is what proves identity, key in payload only names it
+    if state.get_remote_static() != Some(&peer.noise_public()?[..]) {
+        return Err("identity key doesn't match static key of handshake".into());
+    }
+    payload.truncate(len);
+    payload.drain(..IDENTITY_KEY_SIZE);
+    Ok((state, peer, payload))
+}
+
+/// second handshake message for peer, ephemeral keys are dropped with state
+pub fn handshake_answer(
+    mut state: snow::HandshakeState,
+    payload: &[u8],
+) -> Result<(Vec<u8>, SessionKeys), Box<dyn std::error::Error>> {
+    let mut answer = vec![0u8; payload.len() + NOISE_ANSWER_OVERHEAD];
+    let len = state.write_message(payload, &mut answer)?;
+    answer.truncate(len);
+    let (initiator, responder) = state.dangerously_get_raw_split();
+    Ok((
+        answer,
+        SessionKeys {
+            send: SecretKey::new(responder),
+            recv: SecretKey::new(initiator),
+        },
+    ))
+}
+
+/// read answer of peer and its payload, ephemeral keys are dropped with state
+pub fn handshake_finish(
+    mut state: snow::HandshakeState,
+    answer: &[u8],
+) -> Result<(SessionKeys, Vec<u8>), Box<dyn std::error::Error>> {
+    let mut payload = vec![0u8; answer.len()];
+    let len = state.read_message(answer, &mut payload)?;
+    payload.truncate(len);
+    let (initiator, responder) = state.dangerously_get_raw_split();
+    Ok((
+        SessionKeys {
+            send: SecretKey::new(initiator),
+            recv: SecretKey::new(responder),
+        },
+        payload,
+    ))
+}
+
+/// keys from PAKE run in PEER_QUERY, only peer answering query can tell if password was right
+/// so every guess needs one query
+pub struct PakeKeys {
+    pub answer: SecretKey, // encrypts addresses in answer to query
+    pub psk: SecretKey,    // psk of noise handshake in hello that follows
+}
+
+/// start PAKE with network key as password, joining peer is side A
+pub fn pake_start(
+    key: &[u8; 32],
+    net_tag: &[u8],
+    joining: bool,
+) -> (Spake2<Ed25519Group>, Vec<u8>) {
+    let password = spake2::Password::new(key);
+    let id = spake2::Identity::new(net_tag);
+    match joining {
+        true => Spake2::<Ed25519Group>::start_a(&password, &id, &id),
+        false => Spake2::<Ed25519Group>::start_b(&password, &id, &id),
+    }
+}
+
+/// keys are different on both sides when password is, that shows only when answer is decrypted
+pub fn pake_finish(
+    state: Spake2<Ed25519Group>,
+    message: &[u8],
+) -> Result<PakeKeys, Box<dyn std::error::Error>> {
+    let shared = Zeroizing::new(
+        state
+            .finish(message)
+            .map_err(|e| format!("PAKE error: {}", e))?,
+    );
+    let derive = |label: &[u8]| -> SecretKey {
+        SecretKey::new(
+            Sha256::new()
+                .chain_update(label)
+                .chain_update(&*shared)
+                .finalize()
+                .into(),
+        )
+    };
+    Ok(PakeKeys {
+        answer: derive(b"query answer"),
+        psk: derive(b"hello psk"),
+    })
+}
+
 #[cfg(debug_assertions)]
 pub fn test_all_crypto_functions() {
     // Test data
@@ -98,3 +560,94 @@ pub fn test_all_crypto_functions() {
 
     println!("All tests completed!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pake(key_a: &[u8; 32], key_b: &[u8; 32]) -> (PakeKeys, PakeKeys) {
+        let (a, message_a) = pake_start(key_a, b"tag", true);
+        let (b, message_b) = pake_start(key_b, b"tag", false);
+        (
+            pake_finish(a, &message_b).unwrap(),
+            pake_finish(b, &message_a).unwrap(),
+        )
+    }
+
+    #[test]
+    fn pake_agrees_on_keys() {
+        let (a, b) = pake(&[1; 32], &[1; 32]);
+        assert_eq!(*a.answer, *b.answer);
+        assert_eq!(*a.psk, *b.psk);
+        assert_ne!(*a.answer, *a.psk);
+    }
+
+    #[test]
+    fn pake_with_wrong_password_fails() {
+        let (a, b) = pake(&[1; 32], &[2; 32]);
+        assert_ne!(*a.answer, *b.answer);
+        assert_ne!(*a.psk, *b.psk);
+        // answer sealed by one side doesn't open on the other
+        let nonce = [0u8; AEAD_NONCE_SIZE];
+        let mut answer = b"addrs".to_vec();
+        let tag = seal_in_place(
+            CipherSuite::AES_256_GCM,
+            &b.answer,
+            &nonce,
+            &[],
+            &mut answer,
+        );
+        answer.extend_from_slice(&tag.unwrap());
+        assert!(
+            open_in_place(
+                CipherSuite::AES_256_GCM,
+                &a.answer,
+                &nonce,
+                &[],
+                &mut answer
+            )
+            .is_err()
+        );
+    }
+
+    fn identity(seed: u8) -> Identity {
+        Identity {
+            key: ed25519_dalek::SigningKey::from_bytes(&[seed; 32]),
+        }
+    }
+
+    #[test]
+    fn handshake_derives_matching_keys() {
+        let (initiator, responder) = (identity(1), identity(2));
+        let (state, hello) =
+            handshake_initiate(&[3; 32], b"net", &initiator, &responder.public(), b"addrs")
+                .unwrap();
+        let (answering, peer, payload) =
+            handshake_respond(&[3; 32], b"net", &responder, &hello).unwrap();
+        assert_eq!(peer, initiator.public());
+        assert_eq!(payload, b"addrs");
+
+        let (answer, responder_keys) = handshake_answer(answering, b"list").unwrap();
+        let (initiator_keys, payload) = handshake_finish(state, &answer).unwrap();
+        assert_eq!(payload, b"list");
+        assert_eq!(*initiator_keys.send, *responder_keys.recv);
+        assert_eq!(*initiator_keys.recv, *responder_keys.send);
+        assert_ne!(*initiator_keys.send, *initiator_keys.recv);
+    }
+
+    #[test]
+    fn handshake_fails_with_other_psk_or_network() {
+        let (initiator, responder) = (identity(1), identity(2));
+        let (state, hello) =
+            handshake_initiate(&[3; 32], b"net", &initiator, &responder.public(), b"addrs")
+                .unwrap();
+        // psk is mixed in with answer, so initiator is who notices
+        let (answering, _, _) = handshake_respond(&[4; 32], b"net", &responder, &hello).unwrap();
+        let (answer, _) = handshake_answer(answering, b"").unwrap();
+        assert!(handshake_finish(state, &answer).is_err());
+
+        assert!(handshake_respond(&[3; 32], b"other net", &responder, &hello).is_err());
+        // hello for somebody else
+        assert!(handshake_respond(&[3; 32], b"net", &identity(5), &hello).is_err());
+    }
+}
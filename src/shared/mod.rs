@@ -1,2 +1,4 @@
+pub mod compression;
 pub mod crypto;
 pub mod net;
+pub mod subnet;
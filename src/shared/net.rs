@@ -1,8 +1,16 @@
 use std::io::ErrorKind;
 use std::net::{SocketAddr, UdpSocket};
 
+use sha2::Digest;
+
 use crate::*;
 
+/// short identifier of network carried in P2P packets
+pub fn net_tag(net_id: &str) -> [u8; NET_TAG_SIZE] {
+    let hash = sha2::Sha256::digest(net_id.as_bytes());
+    hash[..NET_TAG_SIZE].try_into().unwrap()
+}
+
 #[cfg(target_os = "windows")]
 use std::os::windows::io::AsRawSocket;
 #[cfg(target_os = "windows")]
@@ -178,6 +186,12 @@ pub fn send_and_recv_with_retry(
                     x if x == ServerResponse::ID_EXISTS as u8 => {
                         return Err(ServerErrorResponses::ID_EXISTS);
                     }
+                    x if x == ServerResponse::IP_IN_USE as u8 => {
+                        return Err(ServerErrorResponses::IP_IN_USE);
+                    }
+                    x if x == ServerResponse::NOT_ON_ALLOWLIST as u8 => {
+                        return Err(ServerErrorResponses::NOT_ON_ALLOWLIST);
+                    }
                     x if x == P2PMethods::DO_NOTHING as u8 => {
                         resend = false;
                         continue;
@@ -0,0 +1,162 @@
+use core::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use crate::*;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub struct Subnet {
+    addr: Ipv4Addr, // allways stored as network address
+    prefix_len: u8,
+}
+
+impl Subnet {
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Result<Self, ServerErrorResponses> {
+        if prefix_len > 32 {
+            return Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                "invalid prefix length: {}",
+                prefix_len
+            )));
+        }
+        Ok(Subnet {
+            addr: Ipv4Addr::from(u32::from(addr) & Self::mask_bits(prefix_len)),
+            prefix_len,
+        })
+    }
+
+    fn mask_bits(prefix_len: u8) -> u32 {
+        match prefix_len {
+            0 => 0,
+            n => u32::MAX << (32 - n as u32),
+        }
+    }
+
+    pub fn addr(&self) -> Ipv4Addr {
+        self.addr
+    }
+
+    pub fn prefix_len(&self) -> u8 {
+        self.prefix_len
+    }
+
+    pub fn netmask(&self) -> Ipv4Addr {
+        Ipv4Addr::from(Self::mask_bits(self.prefix_len))
+    }
+
+    pub fn broadcast(&self) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.addr) | !Self::mask_bits(self.prefix_len))
+    }
+
+    pub fn contains(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & Self::mask_bits(self.prefix_len) == u32::from(self.addr)
+    }
+
+    /// number of usable host addresses, network and broadcast address excluded
+    pub fn host_count(&self) -> u32 {
+        match self.prefix_len {
+            31 | 32 => 0, // not usable for virtual network
+            // computed in u64 so /0 does not overflow, 2^32 - 2 still fits
+            n => ((1u64 << (32 - n as u64)) - 2) as u32,
+        }
+    }
+
+    pub fn overlaps(&self, other: &Subnet) -> bool {
+        self.contains(other.addr) || other.contains(self.addr)
+    }
+
+    /// true if ip can be assigned to a peer
+    pub fn is_host(&self, ip: Ipv4Addr) -> bool {
+        self.contains(ip) && ip != self.addr && ip != self.broadcast()
+    }
+
+    /// n-th host address of subnet counting from 1
+    pub fn host(&self, n: u32) -> Option<Ipv4Addr> {
+        if n == 0 || n > self.host_count() {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from(self.addr) + n))
+    }
+
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> + '_ {
+        (1..=self.host_count()).filter_map(|n| self.host(n))
+    }
+}
+
+impl Default for Subnet {
+    fn default() -> Self {
+        Subnet::new(Ipv4Addr::from(DEFAULT_NETWORK_ADDR), DEFAULT_PREFIX_LEN)
+            .expect("default subnet is valid")
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = ServerErrorResponses;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some(parts) => parts,
+            None => {
+                return Err(ServerErrorResponses::GENERAL_ERROR(format!(
+                    "{} is missing prefix length",
+                    s
+                )));
+            }
+        };
+        let addr = Ipv4Addr::from_str(addr)
+            .map_err(|e| ServerErrorResponses::GENERAL_ERROR(format!("{}: {}", s, e)))?;
+        let prefix_len = u8::from_str(prefix_len)
+            .map_err(|e| ServerErrorResponses::GENERAL_ERROR(format!("{}: {}", s, e)))?;
+        Subnet::new(addr, prefix_len)
+    }
+}
+
+impl TryFrom<String> for Subnet {
+    type Error = ServerErrorResponses;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        Subnet::from_str(&s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subnet(prefix_len: u8) -> Subnet {
+        Subnet::new(Ipv4Addr::new(10, 0, 0, 0), prefix_len).unwrap()
+    }
+
+    #[test]
+    fn host_count_edges() {
+        assert_eq!(subnet(0).host_count(), u32::MAX - 1);
+        assert_eq!(subnet(1).host_count(), (1 << 31) - 2);
+        assert_eq!(subnet(30).host_count(), 2);
+        assert_eq!(subnet(31).host_count(), 0);
+        assert_eq!(subnet(32).host_count(), 0);
+    }
+
+    #[test]
+    fn hosts_of_small_subnets() {
+        assert_eq!(
+            subnet(30).hosts().collect::<Vec<_>>(),
+            vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)]
+        );
+        assert_eq!(subnet(31).hosts().count(), 0);
+        assert_eq!(subnet(32).hosts().count(), 0);
+        assert_eq!(subnet(0).host(1), Some(Ipv4Addr::new(0, 0, 0, 1)));
+        assert_eq!(subnet(0).host(u32::MAX), None);
+    }
+
+    #[test]
+    fn rejects_invalid_prefix() {
+        assert!(Subnet::new(Ipv4Addr::new(10, 0, 0, 0), 33).is_err());
+        assert!("10.0.0.0/33".parse::<Subnet>().is_err());
+    }
+}